@@ -0,0 +1,103 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::output::{CHANNELS, SAMPLE_RATE};
+
+/// Samples in each chunk sent to the listeners, 50ms of audio
+const CHUNK_SIZE: usize = SAMPLE_RATE as usize * CHANNELS as usize / 20;
+/// Chunks a listener can fall behind before it misses audio
+const LISTENER_BACKLOG: usize = 40;
+
+/// Interleaved samples in the format of `CHANNELS` and `SAMPLE_RATE`
+pub type Chunk = Arc<Vec<f32>>;
+
+static LISTENERS: Mutex<Vec<Sender<Chunk>>> = Mutex::new(Vec::new());
+
+/// Returns a receiver of everything that is played from now on
+pub fn subscribe() -> Receiver<Chunk> {
+    let (sender, receiver) = mpsc::channel(LISTENER_BACKLOG);
+    LISTENERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Passes the samples of the output on to the listeners of the stream
+pub struct Tap<S>
+where
+    S: Source<Item = f32>,
+{
+    inner: S,
+    buffer: Vec<f32>,
+}
+
+impl<S> Tap<S>
+where
+    S: Source<Item = f32>,
+{
+    /// The source has to be in the format of `CHANNELS` and `SAMPLE_RATE`
+    pub fn new(inner: S) -> Self {
+        Tap {
+            inner,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send(&mut self) {
+        // Never block the audio thread, the chunk is dropped if the list is in use
+        let mut listeners = match LISTENERS.try_lock() {
+            Ok(l) if !l.is_empty() => l,
+            _ => {
+                self.buffer.clear();
+                return;
+            }
+        };
+        let chunk: Chunk = Arc::new(mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+        // Slow listeners miss the chunk, listeners that are gone are removed
+        listeners.retain(|l| !matches!(l.try_send(chunk.clone()), Err(TrySendError::Closed(_))));
+    }
+}
+
+impl<S> Iterator for Tap<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next()?;
+        self.buffer.push(sample);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send();
+        }
+        Some(sample)
+    }
+}
+
+impl<S> Source for Tap<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
@@ -1,6 +1,10 @@
-use std::ops::Div;
+use std::ops::{Div, RangeInclusive};
 
-use crate::song::Song;
+use crate::{
+    effects::{EqBand, EqPreset},
+    loudness::ReplayGainMode,
+    song::Song,
+};
 
 #[derive(Debug)]
 pub enum PlayerMessage {
@@ -11,14 +15,37 @@ pub enum PlayerMessage {
     Volume(f32),
     Add(Song),
     Clear,
+    /// Changes the tempo without changing the pitch
     Speed(f32),
+    /// Shifts the pitch by an amount of semitones without changing the tempo
+    Pitch(f32),
     ReOrder(usize, usize),
     /// Seeks n seconds into the song,
     Seek(u64),
+    /// Changes which ReplayGain values are applied
+    ReplayGain(ReplayGainMode),
+    /// Replaces the bands of the equalizer
+    Equalizer(Vec<EqBand>),
+    EqualizerPreset(EqPreset),
+    /// Sets the gain of the bass boost in dB, 0 turns it off
+    BassBoost(f32),
+    Mono(bool),
+    Limiter(bool),
+    /// Switches to the output device with the name, or to the default device
+    OutputDevice(Option<String>),
+    /// Sets how many seconds the end of a song overlaps with the start of the next one
+    Crossfade(f32),
 }
 
 impl PlayerMessage {
     const VOLUME_MAX :f32= 3.0;
+    /// The slowest and fastest speeds the player accepts
+    pub const SPEED_RANGE: RangeInclusive<f32> = 0.25..=4.0;
+    /// How many semitones the pitch can be shifted either way
+    pub const PITCH_RANGE: RangeInclusive<f32> = -12.0..=12.0;
+    /// How many seconds songs can overlap
+    pub const CROSSFADE_RANGE: RangeInclusive<f32> = 0.0..=12.0;
+
     pub fn skip_first() -> Self {
         Self::Skip(Box::new([0]))
     }
@@ -33,6 +60,30 @@ impl PlayerMessage {
     pub fn reverse_exp_volume(n: f32) -> f64 {
         n.div(Self::VOLUME_MAX).max(0.0).powf(0.25).into()
     }
+
+    /// Brings the speed into `SPEED_RANGE`, something that isn't a number becomes the normal speed
+    pub fn clamp_speed(s: f32) -> f32 {
+        match s.is_nan() {
+            true => 1.0,
+            false => s.clamp(*Self::SPEED_RANGE.start(), *Self::SPEED_RANGE.end()),
+        }
+    }
+
+    /// Brings the pitch shift into `PITCH_RANGE`, something that isn't a number becomes no shift
+    pub fn clamp_pitch(p: f32) -> f32 {
+        match p.is_nan() {
+            true => 0.0,
+            false => p.clamp(*Self::PITCH_RANGE.start(), *Self::PITCH_RANGE.end()),
+        }
+    }
+
+    /// Brings the crossfade into `CROSSFADE_RANGE`, something that isn't a number turns it off
+    pub fn clamp_crossfade(c: f32) -> f32 {
+        match c.is_nan() {
+            true => 0.0,
+            false => c.clamp(*Self::CROSSFADE_RANGE.start(), *Self::CROSSFADE_RANGE.end()),
+        }
+    }
 }
 
 #[test]
@@ -45,3 +96,14 @@ fn test_exp_vol() {
     };
     assert!(diff <= 10e9)
 }
+
+#[test]
+fn test_clamp_speed() {
+    assert_eq!(PlayerMessage::clamp_speed(0.0), 0.25);
+    assert_eq!(PlayerMessage::clamp_speed(f32::INFINITY), 4.0);
+    assert_eq!(PlayerMessage::clamp_speed(f32::NAN), 1.0);
+    assert_eq!(PlayerMessage::clamp_pitch(-100.0), -12.0);
+    assert_eq!(PlayerMessage::clamp_pitch(f32::NAN), 0.0);
+    assert_eq!(PlayerMessage::clamp_crossfade(-1.0), 0.0);
+    assert_eq!(PlayerMessage::clamp_crossfade(f32::NAN), 0.0);
+}
@@ -1,12 +1,28 @@
 use serde::{Deserialize, Serialize};
 
-use crate::remote::auth::Key;
-use std::fs::read_to_string;
+use crate::downloads;
+use crate::effects::Effects;
+use crate::files::duplicates;
+use crate::loudness::ReplayGainMode;
+use crate::output::Backend;
+use crate::remote::auth::{self, Key};
+use crate::remote::cors::CorsPolicy;
+use crate::remote::limits::RateLimits;
+use crate::remote::lookup;
+use crate::remote::tls::ListenAddress;
+use std::fs::{read_to_string, rename, write};
+use std::io;
 use std::path::PathBuf;
 use std::result::Result::*;
 use std::str::FromStr;
+use std::sync::Mutex;
 
 static CONF_PATH: &str = "conf.json";
+/// Written first and then renamed over the conf file, so the file is never half written
+static TEMP_PATH: &str = "conf.json.tmp";
+/// Held while the conf file is read, changed and written, so changes made at the same time from
+/// different threads don't write over each other
+static UPDATE: Mutex<()> = Mutex::new(());
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Configuration {
@@ -15,7 +31,61 @@ pub struct Configuration {
     pub owned_path: PathBuf,
     pub outer_paths: Vec<PathBuf>,
     pub ytdlp_path: String,
-    pub ip: Vec<String>,
+    /// How many downloads run at once, the rest wait in the download queue
+    #[serde(default = "downloads::default_concurrency")]
+    pub max_downloads: usize,
+    /// The addresses the remote listens on, over HTTPS for those that ask for it
+    pub ip: Vec<ListenAddress>,
+    /// Starts the remote on every address in `ip` when the app starts. Otherwise it is started
+    /// from the console with `remote start default`.
+    #[serde(default)]
+    pub remote_autostart: bool,
+    /// Hides the redundant copies of duplicate songs from the library
+    #[serde(default)]
+    pub hide_duplicates: bool,
+    /// Songs that have been hidden from the library
+    #[serde(default)]
+    pub hidden_paths: Vec<PathBuf>,
+    /// Songs with the same title and artist are only considered duplicates if their durations
+    /// differ by at most this many seconds. When unset, or when a duration can't be read,
+    /// durations aren't compared.
+    #[serde(default = "duplicates::default_tolerance")]
+    pub duplicate_duration_tolerance: Option<f32>,
+    /// Analyzes the loudness of the songs in `owned_path` that have no ReplayGain values when the
+    /// app starts, and writes the values into their tags. It can also be started from the console
+    /// with `scan`.
+    #[serde(default)]
+    pub scan_loudness: bool,
+    /// Also analyzes, and writes the tags of, the songs in `outer_paths`
+    #[serde(default)]
+    pub scan_outer_paths: bool,
+    /// Songs whose loudness couldn't be analyzed, they aren't tried again
+    #[serde(default)]
+    pub unanalyzable_paths: Vec<PathBuf>,
+    /// Which ReplayGain values are applied to the volume
+    #[serde(default)]
+    pub replay_gain: ReplayGainMode,
+    /// How many seconds the end of a song overlaps with the start of the next one, 0 turns it off
+    #[serde(default)]
+    pub crossfade: f32,
+    /// The effects applied to every song
+    #[serde(default)]
+    pub effects: Effects,
+    /// The name of the output device, the default device is used if it is missing
+    #[serde(default)]
+    pub output_device: Option<String>,
+    /// Where the audio is sent, a device unless it is set to a null or WAV backend
+    #[serde(default)]
+    pub backend: Backend,
+    /// How often keys may add songs, download and look up pages through the remote
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// The hosts the remote may look up pages on, their subdomains are allowed as well
+    #[serde(default = "lookup::default_hosts")]
+    pub lookup_hosts: Vec<String>,
+    /// Which web pages may use the remote from a browser
+    #[serde(default)]
+    pub cors: CorsPolicy,
 }
 
 impl Default for Configuration {
@@ -25,40 +95,83 @@ impl Default for Configuration {
             default_volume: 1.0,
             owned_path: PathBuf::from_str("songs/").unwrap(),
             outer_paths: Vec::new(),
-            ip: vec!["0.0.0.0:8000".to_string(), "127.0.0.1:8000".to_string()],
+            ip: vec![
+                ListenAddress::Plain("0.0.0.0:8000".to_string()),
+                ListenAddress::Plain("127.0.0.1:8000".to_string()),
+            ],
+            remote_autostart: false,
             ytdlp_path: "".to_string(),
+            max_downloads: downloads::default_concurrency(),
+            hide_duplicates: false,
+            hidden_paths: Vec::new(),
+            duplicate_duration_tolerance: duplicates::default_tolerance(),
+            scan_loudness: false,
+            scan_outer_paths: false,
+            unanalyzable_paths: Vec::new(),
+            replay_gain: ReplayGainMode::default(),
+            crossfade: 0.0,
+            effects: Effects::default(),
+            output_device: None,
+            backend: Backend::default(),
+            rate_limits: RateLimits::default(),
+            lookup_hosts: lookup::default_hosts(),
+            cors: CorsPolicy::default(),
         }
     }
 }
 
 impl Configuration {
-    #[allow(clippy::result_large_err)]
-    fn new() -> Result<Configuration, Configuration> {
+    /// Reads the conf file. A missing file gives the defaults, but a file that can't be parsed is
+    /// an error, so it is never replaced with them.
+    fn new() -> io::Result<Configuration> {
         match read_to_string(CONF_PATH) {
-            Ok(string) => {
-                let conf: Result<Configuration, serde_json::Error> = serde_json::from_str(&string);
-                match conf {
-                    Err(_) => Err(Configuration::default()),
-                    Ok(mut c) => {
-                        for key in &mut c.keys {
-                            key.convert_all();
-                        }
-                        Ok(c)
-                    }
-                }
-            }
-            Err(_) => Err(Configuration::default()),
+            Ok(string) => serde_json::from_str(&string).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not valid: {}", CONF_PATH, e),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Configuration::default()),
+            Err(e) => Err(e),
         }
     }
+
+    /// Checks the conf file once when the program starts, hashing keys from before they were
+    /// hashed. Nothing else writes the file unless something in it changes.
+    pub fn init() -> io::Result<()> {
+        let _update = UPDATE.lock().unwrap_or_else(|e| e.into_inner());
+        let mut conf = Configuration::new()?;
+        if auth::hash_plain_keys(&mut conf.keys) {
+            conf.save()?;
+        }
+        Ok(())
+    }
+
+    /// The configuration to read settings from. If the file can't be read the defaults are
+    /// used, but they are never written over it, as changes go through `update`.
     pub fn get_conf() -> Configuration {
         match Configuration::new() {
             Ok(c) => c,
-            Err(c) => {
-                println!("Could not find conf file, resorting to default");
-                c
+            Err(e) => {
+                println!("Could not read the conf file, resorting to default: {}", e);
+                Configuration::default()
             }
         }
     }
+
+    /// Changes the configuration in the conf file. Nothing is written if the file can't be read.
+    pub fn update(change: impl FnOnce(&mut Configuration)) -> io::Result<()> {
+        let _update = UPDATE.lock().unwrap_or_else(|e| e.into_inner());
+        let mut conf = Configuration::new()?;
+        change(&mut conf);
+        conf.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        write(TEMP_PATH, json)?;
+        rename(TEMP_PATH, CONF_PATH)
+    }
 }
 
 #[cfg(test)]
@@ -1,12 +1,37 @@
-/*use std::sync::{Arc, Mutex};
+use std::io;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{self, Sender};
 
 use crate::conf::Configuration;
 use crate::downloader;
-use crate::remote::RemoteHandler;
+use crate::loudness;
+use crate::remote::{auth::Permission, RemoteHandler};
 use crate::song::Song;
-use crate::{commands::PlayerMessage, exit_program, list_songs, player_state::PlayerState};
+use crate::{commands::PlayerMessage, list_songs, player_state::PlayerState};
+
+/// Reads commands from the terminal the app was started from, one a line, until it is closed
+pub(crate) async fn run(
+    ps: Sender<PlayerMessage>,
+    state: Arc<Mutex<PlayerState>>,
+    mut remote_handler: RemoteHandler,
+) {
+    // Reading the terminal blocks, so it gets a thread of its own
+    let (lines_sender, mut lines) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if lines_sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    while let Some(line) = lines.recv().await {
+        handle_command(line.trim(), ps.clone(), state.clone(), &mut remote_handler).await;
+    }
+}
 
 pub(crate) async fn handle_command(
     command: &str,
@@ -36,11 +61,15 @@ pub(crate) async fn handle_command(
         "stop" => ps.send(PlayerMessage::Stop).await.unwrap(),
         "clear" => ps.send(PlayerMessage::Clear).await.unwrap(),
         "pause" => ps.send(PlayerMessage::Pause).await.unwrap(),
-        "exit" => exit_program(),
+        "exit" => process::exit(0),
         "speed" => ps
             .send(PlayerMessage::Speed((value.parse::<f32>()).unwrap_or(1.0)))
             .await
             .unwrap(),
+        "crossfade" => match value.parse::<f32>() {
+            Ok(c) => ps.send(PlayerMessage::Crossfade(c)).await.unwrap(),
+            Err(e) => println!("Input a valid number of seconds {:?}", e),
+        },
         "remote" => {
             let (c, v1) = value.split_once(' ').unwrap_or((value, ""));
             match c {
@@ -49,13 +78,14 @@ pub(crate) async fn handle_command(
                         match addr {
                             "default" => {
                                 for addr in &conf.ip {
-                                    match remote_handler.new_listener(addr.to_owned()).await {
+                                    let name = addr.address();
+                                    match remote_handler.new_listener(addr.clone()).await {
                                         Ok(_) => {
-                                            println!("Successfully started remote on {}", addr)
+                                            println!("Successfully started remote on {}", name)
                                         }
                                         Err(e) => println!(
                                             "Failed to start remote on {} because {}",
-                                            addr, e
+                                            name, e
                                         ),
                                     }
                                 }
@@ -74,6 +104,7 @@ pub(crate) async fn handle_command(
                         match addr {
                             "default" => {
                                 for addr in &conf.ip {
+                                    let addr = addr.address();
                                     match remote_handler.stop_listener(addr.to_owned()) {
                                         Ok(_) => {
                                             println!("Successfully stopped remote on {}", addr)
@@ -114,6 +145,40 @@ pub(crate) async fn handle_command(
                 }
             }
         }
+        "key" => {
+            let (c, v1) = value.split_once(' ').unwrap_or((value, ""));
+            match c {
+                // key guest <label> [minutes] [permissions...]
+                "guest" => {
+                    let mut args = v1.split(' ').filter(|a| !a.is_empty());
+                    let label = args.next().unwrap_or("guest");
+                    let minutes = args.next().and_then(|m| m.parse::<u64>().ok()).unwrap_or(240);
+                    let mut permissions: Vec<Permission> = args
+                        .filter_map(|p| serde_json::from_value(p.into()).ok())
+                        .collect();
+                    if permissions.is_empty() {
+                        permissions = vec![Permission::Add, Permission::Info];
+                    }
+                    let expires = Duration::from_secs(minutes.saturating_mul(60));
+                    match remote_handler.keys().add(label, permissions, Some(expires), None) {
+                        Ok(key) => println!("Key for {} valid for {} minutes: {}", label, minutes, key),
+                        Err(e) => println!("Failed to create the key: {}", e),
+                    }
+                }
+                "remove" => match remote_handler.keys().remove(v1.trim()) {
+                    Ok(_) => println!("Removed the key {}", v1.trim()),
+                    Err(e) => println!("Failed to remove the key: {}", e),
+                },
+                "list" => {
+                    for key in remote_handler.keys().list() {
+                        println!("{} {:?} expires: {:?}", key.label, key.permissions, key.expires)
+                    }
+                }
+                _ => {
+                    println!("Unknown subcommand of key")
+                }
+            }
+        }
         "now" | "nowplaying" | "current" | "np" => {
             println!("{:?}", state.lock().unwrap().now_playing)
         }
@@ -148,6 +213,12 @@ pub(crate) async fn handle_command(
             }
             ps.send(PlayerMessage::Skip(list.into())).await.unwrap();
         }
+        "scan" => {
+            thread::spawn(|| {
+                loudness::scan_library();
+                println!("Finished analyzing the loudness of the library");
+            });
+        }
         "seek" => match value.parse::<u64>() {
             Ok(t) => ps.send(PlayerMessage::Seek(t)).await.unwrap(),
             Err(e) => println!("Input a valid integer {:?}", e),
@@ -173,4 +244,4 @@ pub(crate) async fn handle_command(
         }
         _ => println!("Unknown command"),
     }
-}*/
+}
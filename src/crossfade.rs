@@ -0,0 +1,187 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rodio::{source::UniformSourceIterator, Source};
+
+use crate::output::{CHANNELS, SAMPLE_RATE};
+
+/// A song as it is played, after the effects
+pub type SongSource = Box<dyn Source<Item = f32> + Send>;
+
+type Uniform = UniformSourceIterator<SongSource, f32>;
+
+/// The song the player wants to fade over to, waiting to be picked up by the source
+struct Next {
+    source: SongSource,
+    factor: f32,
+    frames: usize,
+}
+
+/// A fade from the current song to the next one that is playing
+struct Fade {
+    next: Uniform,
+    /// What the current song is multiplied with
+    factor: f32,
+    /// How many frames the fade lasts
+    frames: usize,
+    /// How many samples have been played since the fade started
+    played: usize,
+}
+
+/// Lets the player hand the next song to the source that is playing
+#[derive(Clone, Default)]
+pub struct FadeHandle {
+    next: Arc<Mutex<Option<Next>>>,
+    fading: Arc<AtomicBool>,
+}
+
+impl FadeHandle {
+    /// Fades from the current song to `source` over `length` of the source. The current song is
+    /// multiplied with `factor` while it fades out, so it keeps its loudness when the volume of
+    /// the sink is changed for the next song.
+    pub fn fade_to(&self, source: SongSource, factor: f32, length: Duration) {
+        let frames = (length.as_secs_f32() * SAMPLE_RATE as f32) as usize;
+        self.fading.store(true, SeqCst);
+        *self.next.lock().unwrap() = Some(Next {
+            source,
+            factor,
+            frames: frames.max(1),
+        });
+    }
+
+    /// Whether a fade has been asked for and hasn't finished yet
+    pub fn is_fading(&self) -> bool {
+        self.fading.load(SeqCst)
+    }
+}
+
+/// Plays a song and every song it is faded over to. During a fade the end of the current song
+/// is mixed with the start of the next one, which then becomes the current song, so the sink
+/// only ends once a song ends without being faded over to another.
+pub struct Crossfade {
+    current: Uniform,
+    fade: Option<Fade>,
+    handle: FadeHandle,
+    /// The channel of the next sample, fades only start on whole frames
+    channel: usize,
+}
+
+impl Crossfade {
+    pub fn new(source: SongSource) -> (Self, FadeHandle) {
+        let handle = FadeHandle::default();
+        let crossfade = Crossfade {
+            current: UniformSourceIterator::new(source, CHANNELS, SAMPLE_RATE),
+            fade: None,
+            handle: handle.clone(),
+            channel: 0,
+        };
+        (crossfade, handle)
+    }
+
+    /// Makes the next song the current one
+    fn finish_fade(&mut self) {
+        if let Some(fade) = self.fade.take() {
+            self.current = fade.next;
+            self.handle.fading.store(false, SeqCst);
+        }
+    }
+}
+
+impl Iterator for Crossfade {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 && self.fade.is_none() {
+            if let Some(next) = self.handle.next.lock().unwrap().take() {
+                self.fade = Some(Fade {
+                    next: UniformSourceIterator::new(next.source, CHANNELS, SAMPLE_RATE),
+                    factor: next.factor,
+                    frames: next.frames,
+                    played: 0,
+                });
+            }
+        }
+        self.channel = (self.channel + 1) % CHANNELS as usize;
+        let Some(fade) = &mut self.fade else {
+            return self.current.next();
+        };
+        let progress = (fade.played / CHANNELS as usize) as f32 / fade.frames as f32;
+        fade.played += 1;
+        let incoming = fade.next.next();
+        // The fade ends on a whole frame, or early if the next song is shorter than it
+        if progress >= 1.0 || incoming.is_none() {
+            self.finish_fade();
+            return incoming;
+        }
+        let outgoing = self.current.next().unwrap_or(0.0) * fade.factor;
+        incoming.map(|s| outgoing * (1.0 - progress) + s * progress)
+    }
+}
+
+impl Source for Crossfade {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn constant(value: f32, frames: usize) -> SongSource {
+        Box::new(SamplesBuffer::new(
+            CHANNELS,
+            SAMPLE_RATE,
+            vec![value; frames * CHANNELS as usize],
+        ))
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let (mut crossfade, handle) = Crossfade::new(constant(1.0, 100));
+        let start: Vec<f32> = crossfade.by_ref().take(50 * CHANNELS as usize).collect();
+        assert!(start.iter().all(|s| *s == 1.0));
+        assert!(!handle.is_fading());
+
+        // The rest of the first song is faded into the second one at half its loudness
+        let frames = 50.0 / SAMPLE_RATE as f32;
+        handle.fade_to(constant(0.0, 100), 0.5, Duration::from_secs_f32(frames));
+        assert!(handle.is_fading());
+        let rest: Vec<f32> = crossfade.by_ref().collect();
+        assert_eq!(rest.len(), 100 * CHANNELS as usize);
+        assert_eq!(rest[0], 0.5);
+        assert!(rest[..50 * CHANNELS as usize]
+            .windows(2)
+            .all(|w| w[0] >= w[1]));
+        assert!(rest[50 * CHANNELS as usize..].iter().all(|s| *s == 0.0));
+        assert!(!handle.is_fading());
+    }
+
+    #[test]
+    fn test_short_next_song() {
+        let (crossfade, handle) = Crossfade::new(constant(1.0, 100));
+        handle.fade_to(constant(1.0, 10), 1.0, Duration::from_secs(1));
+        // The fade ends with the next song, which cuts the first one short
+        assert_eq!(crossfade.count(), 10 * CHANNELS as usize);
+        assert!(!handle.is_fading());
+    }
+}
@@ -1,46 +1,171 @@
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashSet},
+    ffi::OsStr,
     fs,
     hash::{Hash, Hasher},
     io::Cursor,
-    path::PathBuf,
-    process::Command,
+    path::{Path, PathBuf},
+    process::Stdio,
     str::FromStr,
 };
 
-use id3::{frame::Picture, Frame, Tag, TagLike};
-use image::{DynamicImage, EncodableLayout, ImageOutputFormat};
-use youtube_dl::YoutubeDl;
+use id3::{
+    frame::{Chapter, Comment, ExtendedText, Picture, PictureType, TableOfContents},
+    Frame, Tag, TagLike, Timestamp,
+};
+use image::{DynamicImage, EncodableLayout};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use youtube_dl::SingleVideo;
 
-use crate::{conf::Configuration, format::Format, song::Song};
+use crate::{
+    conf::Configuration,
+    files::duplicates::{find_by_url, normalize_url},
+    format::Format,
+    loudness,
+    song::Song,
+    tags::encode_jpeg,
+};
+
+/// Ends the temporary file ffmpeg converts a download into
+const CONVERTED_EXTENSION: &str = ".mp3";
+/// Starts the progress lines yt-dlp is asked to print, so they can be told from other output
+const PROGRESS_PREFIX: &str = "ssmp-progress";
+
+/// How far a download has come, read from the progress lines of yt-dlp
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    /// From 0 to 100
+    pub percent: Option<f32>,
+    /// Bytes per second
+    pub speed: Option<f64>,
+    /// Seconds until the download is done
+    pub eta: Option<u64>,
+}
+
+/// The fields of the yt-dlp info json that are written into the tags of a downloaded song
+#[derive(Debug, Default)]
+pub(crate) struct Metadata {
+    album: Option<String>,
+    date: Option<Timestamp>,
+    description: Option<String>,
+    chapters: Vec<ChapterInfo>,
+    /// Stored as TXXX frames, as pairs of description and value
+    extra: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+struct ChapterInfo {
+    title: String,
+    /// Start of the chapter in milliseconds
+    start: u32,
+    /// End of the chapter in milliseconds
+    end: u32,
+}
+
+impl From<&SingleVideo> for Metadata {
+    fn from(d: &SingleVideo) -> Self {
+        let chapters = d
+            .chapters
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, c)| ChapterInfo {
+                title: c.title.clone().unwrap_or(format!("Chapter {}", i + 1)),
+                start: (c.start_time.unwrap_or(0.0) * 1000.0) as u32,
+                end: (c.end_time.unwrap_or(0.0) * 1000.0) as u32,
+            })
+            .collect();
+        let extra = [
+            ("YTDLP_ID", Some(d.id.clone())),
+            ("CHANNEL", d.channel.clone()),
+            ("CHANNEL_ID", d.channel_id.clone()),
+            ("UPLOADER", d.uploader.clone()),
+            ("UPLOAD_DATE", d.upload_date.clone()),
+            ("WEBPAGE_URL", d.webpage_url.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| Some((k.to_string(), v?)))
+        .collect();
+        Metadata {
+            album: d.album.clone(),
+            date: d.upload_date.as_deref().and_then(parse_upload_date),
+            description: d.description.clone().filter(|d| !d.is_empty()),
+            chapters,
+            extra,
+        }
+    }
+}
 
-// PERF: Change to id:s instead of url:s
 /// Tries to download the video with given url
 pub(crate) async fn download_dlp(url: String) -> Result<Song, String> {
-    let conf = Configuration::get_conf();
+    download_with_progress(url, |_| {}).await
+}
+
+// PERF: Change to id:s instead of url:s
+/// Downloads the video with the url, passing the progress of yt-dlp to `progress` as it comes.
+/// yt-dlp is killed if the returned future is dropped.
+pub(crate) async fn download_with_progress<F>(url: String, mut progress: F) -> Result<Song, String>
+where
+    F: FnMut(Progress) + Send,
+{
+    // Looking for the url reads every song in the library
+    let lookup = url.clone();
+    let existing = tokio::task::spawn_blocking(move || find_by_url(&lookup))
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(song) = existing {
+        return Err(format!(
+            "{} has already been downloaded as {}",
+            url, song.name
+        ));
+    }
+    if is_playlist_url(&url) {
+        return Err(format!("{} is a playlist", url));
+    }
     let mut fldr = Configuration::get_conf().owned_path;
     let mut hash = DefaultHasher::new();
     url.clone().hash(&mut hash);
-    let tfn = format!("{}.temp", hash.finish());
-    let data = YoutubeDl::new(&url)
-        .socket_timeout("15")
-        .youtube_dl_path(conf.ytdlp_path)
-        .download(true)
-        .format("ba")
-        .output_template(&tfn)
-        .output_directory(fldr.to_str().unwrap())
-        .run_async()
-        .await
-        .map_err(|e| format!("failed for {}", e))?;
-    let d = data.into_single_video().ok_or("Tried a playlist")?;
+    // Every attempt gets files of its own, so downloads of the same url never share them
+    let tfn = format!("{}-{:016x}.temp", hash.finish(), rand::random::<u64>());
+    let _temp_files = TempFiles(fldr.join(&tfn));
+    let template = format!(
+        "download:{} %(progress.downloaded_bytes)s %(progress.total_bytes)s \
+         %(progress.total_bytes_estimate)s %(progress.speed)s %(progress.eta)s",
+        PROGRESS_PREFIX
+    );
+    let mut args: Vec<&OsStr> = [
+        "--no-simulate",
+        "--no-playlist",
+        "--newline",
+        "--progress",
+        "--progress-template",
+        &template,
+        "-f",
+        "ba",
+        "-o",
+        &tfn,
+        "-P",
+    ]
+    .into_iter()
+    .map(OsStr::new)
+    .collect();
+    args.push(fldr.as_os_str());
+    let data = run_ytdlp(&args, &url, &mut progress).await?;
+    if data["_type"] == "playlist" {
+        return Err(format!("{} is a playlist", url));
+    }
+    let d: SingleVideo = serde_json::from_value(data).map_err(|e| e.to_string())?;
+    let meta = Metadata::from(&d);
     let file_name = gen_filename(&d.title);
     let artist = match d.artist {
         Some(a) => Some(a),
         None => d.uploader,
     };
     fldr.push(PathBuf::from_str(&tfn).unwrap());
-    let p = change_format_and_name_better(&file_name, fldr).unwrap();
+    let p = change_format_and_name_better(&file_name, fldr).await?;
     let s = Song {
+        id: Song::gen_id(&p),
         name: d.title,
         artist,
         url: Some(url),
@@ -56,12 +181,98 @@ pub(crate) async fn download_dlp(url: String) -> Result<Song, String> {
             }
         }
     }
-    if let Err(e) = set_metadata(s.clone(), img) {
+    if let Err(e) = set_metadata(&s, &meta, img) {
         println!("Error when writing metadata: {:?}", e)
     }
+    let song = s.clone();
+    match tokio::task::spawn_blocking(move || loudness::analyze_track(&song)).await {
+        Ok(Err(e)) => println!("Error when analyzing loudness: {}", e),
+        Err(e) => println!("Error when analyzing loudness: {}", e),
+        Ok(Ok(_)) => {}
+    }
     Ok(s)
 }
 
+/// The videos of a playlist or a channel, in the order yt-dlp lists them
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PlaylistInfo {
+    pub title: Option<String>,
+    pub urls: Vec<String>,
+}
+
+/// Whether the url points to a YouTube playlist or channel rather than a single video. A video
+/// watched as a part of a playlist counts as a video, as only it is downloaded.
+pub(crate) fn is_playlist_url(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url.trim()) else {
+        return false;
+    };
+    let youtube = url
+        .host_str()
+        .is_some_and(|h| h == "youtube.com" || h.ends_with(".youtube.com"));
+    let path = url.path();
+    youtube
+        && (path == "/playlist"
+            || path.starts_with("/@")
+            || ["/channel/", "/c/", "/user/"]
+                .iter()
+                .any(|p| path.starts_with(p)))
+}
+
+/// Lists the videos of a playlist without downloading anything. The tabs of a channel are
+/// listed in turn, and a single video is a playlist of its own.
+pub(crate) async fn list_playlist(url: &str) -> Result<PlaylistInfo, String> {
+    let args = [OsStr::new("--flat-playlist")];
+    let info = run_ytdlp(&args, url, &mut |_| {}).await?;
+    let (mut playlist, tabs) = parse_playlist(&info);
+    for tab in tabs {
+        let info = run_ytdlp(&args, &tab, &mut |_| {}).await?;
+        playlist.urls.extend(parse_playlist(&info).0.urls);
+    }
+    // The same video can be in many tabs
+    let mut seen = HashSet::new();
+    playlist.urls.retain(|u| seen.insert(normalize_url(u)));
+    Ok(playlist)
+}
+
+/// Reads the info json yt-dlp prints with `--flat-playlist`. Returns the playlist and the urls
+/// of the entries that are playlists themselves and have to be listed separately.
+fn parse_playlist(info: &serde_json::Value) -> (PlaylistInfo, Vec<String>) {
+    let mut playlist = PlaylistInfo {
+        title: info["title"].as_str().map(str::to_string),
+        urls: vec![],
+    };
+    let mut nested = vec![];
+    let Some(entries) = info["entries"].as_array() else {
+        playlist
+            .urls
+            .extend(info["webpage_url"].as_str().map(str::to_string));
+        return (playlist, nested);
+    };
+    for entry in entries {
+        if entry["entries"].is_array() {
+            let (inner, more) = parse_playlist(entry);
+            playlist.urls.extend(inner.urls);
+            nested.extend(more);
+        } else if let Some(url) = entry_url(entry) {
+            match entry["_type"] == "playlist" || entry["ie_key"] == "YoutubeTab" {
+                true => nested.push(url),
+                false => playlist.urls.push(url),
+            }
+        }
+    }
+    (playlist, nested)
+}
+
+fn entry_url(entry: &serde_json::Value) -> Option<String> {
+    let url = entry["url"].as_str().or(entry["webpage_url"].as_str())?;
+    match url.starts_with("http") {
+        true => Some(url.to_string()),
+        // Some versions of yt-dlp list YouTube videos by their ids
+        false => (entry["ie_key"] == "Youtube")
+            .then(|| format!("https://www.youtube.com/watch?v={}", url)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -80,22 +291,218 @@ mod tests {
         let new_name = gen_filename("Heilutaan / Eurobeat Remix");
         assert_eq!(new_name, "heilutaan - eurobeat remix")
     }
+
+    #[test]
+    fn test_free_path() {
+        let folder = std::env::temp_dir().join(format!("ssmp-free-{}", rand::random::<u32>()));
+        fs::create_dir_all(&folder).unwrap();
+        assert_eq!(free_path(&folder, "intro"), folder.join("intro.mp3"));
+        fs::write(folder.join("intro.mp3"), "").unwrap();
+        // A song with the same name is never written over
+        assert_eq!(free_path(&folder, "intro"), folder.join("intro 2.mp3"));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_progress() {
+        let progress = parse_progress("ssmp-progress 2500 10000 NA 1024.5 7").unwrap();
+        assert_eq!(progress.percent, Some(25.0));
+        assert_eq!(progress.speed, Some(1024.5));
+        assert_eq!(progress.eta, Some(7));
+        // The estimate is used when the size isn't known
+        let progress = parse_progress("ssmp-progress 500 NA 1000 NA NA").unwrap();
+        assert_eq!((progress.percent, progress.eta), (Some(50.0), None));
+        assert!(parse_progress("[download] Destination: a.temp").is_none());
+    }
+
+    #[test]
+    fn test_playlist_url() {
+        assert!(is_playlist_url(
+            "https://www.youtube.com/playlist?list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG"
+        ));
+        assert!(is_playlist_url("https://youtube.com/@channel/videos"));
+        assert!(!is_playlist_url(
+            "https://www.youtube.com/watch?v=Uk8sAsB25vk&list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG"
+        ));
+        assert!(!is_playlist_url("https://youtu.be/Uk8sAsB25vk"));
+    }
+
+    #[test]
+    fn test_parse_playlist() {
+        let info = serde_json::json!({
+            "_type": "playlist",
+            "title": "Mix",
+            "entries": [
+                {"_type": "url", "ie_key": "Youtube", "url": "https://www.youtube.com/watch?v=a"},
+                {"_type": "url", "ie_key": "Youtube", "url": "b"},
+                {"_type": "url", "ie_key": "YoutubeTab", "url": "https://www.youtube.com/@c/videos"},
+                {"_type": "playlist", "entries": [{"url": "https://www.youtube.com/watch?v=d"}]},
+            ]
+        });
+        let (playlist, nested) = parse_playlist(&info);
+        assert_eq!(playlist.title.as_deref(), Some("Mix"));
+        assert_eq!(
+            playlist.urls,
+            [
+                "https://www.youtube.com/watch?v=a",
+                "https://www.youtube.com/watch?v=b",
+                "https://www.youtube.com/watch?v=d"
+            ]
+        );
+        assert_eq!(nested, ["https://www.youtube.com/@c/videos"]);
+        // A single video
+        let info = serde_json::json!({"title": "a", "webpage_url": "https://youtu.be/a"});
+        assert_eq!(parse_playlist(&info).0.urls, ["https://youtu.be/a"]);
+    }
+
+    #[test]
+    fn test_upload_date() {
+        let date = parse_upload_date("20230415").unwrap();
+        assert_eq!((date.year, date.month, date.day), (2023, Some(4), Some(15)));
+        assert!(parse_upload_date("").is_none());
+    }
 }
 
-// TODO: Use Opus instead of mp3
-pub(crate) fn change_format_and_name_better(name: &str, path: PathBuf) -> Result<PathBuf, String> {
-    let new_file_name = gen_filename(name) + &Format::MP3.filetype_to_extension().unwrap();
-    let mut new_loc = Configuration::get_conf().owned_path;
-    new_loc.push(new_file_name);
+/// Runs yt-dlp with the arguments on the url and returns the info json it prints
+async fn run_ytdlp(
+    args: &[&OsStr],
+    url: &str,
+    progress: &mut (dyn FnMut(Progress) + Send),
+) -> Result<serde_json::Value, String> {
+    let program = Some(Configuration::get_conf().ytdlp_path)
+        .filter(|p| !p.is_empty())
+        .unwrap_or("yt-dlp".to_string());
+    let mut child = tokio::process::Command::new(program)
+        .args(["--no-warnings", "-J", "--socket-timeout", "15"])
+        .args(args)
+        .args(["--", url])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to start yt-dlp: {}", e))?;
+    let mut stdout = child.stdout.take().ok_or("yt-dlp has no output")?;
+    let stderr = child.stderr.take().ok_or("yt-dlp has no output")?;
+    // The json is printed once everything is done, the progress and errors as they happen
+    let mut output = String::new();
+    let mut errors = vec![];
+    let read_stderr = async {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match parse_progress(&line) {
+                Some(p) => progress(p),
+                None => errors.push(line),
+            }
+        }
+    };
+    let (read, _) = futures::join!(stdout.read_to_string(&mut output), read_stderr);
+    let status = child.wait().await.map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(errors
+            .iter()
+            .rev()
+            .find(|l| l.starts_with("ERROR"))
+            .or(errors.last())
+            .cloned()
+            .unwrap_or(format!("yt-dlp failed with {}", status)));
+    }
+    read.map_err(|e| e.to_string())?;
+    let json = output
+        .lines()
+        .rev()
+        .find(|l| l.starts_with('{'))
+        .ok_or("yt-dlp printed no info")?;
+    serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+/// Reads a line printed with the progress template of `run_ytdlp`, fields yt-dlp doesn't know
+/// are NA
+fn parse_progress(line: &str) -> Option<Progress> {
+    let mut fields = line
+        .trim()
+        .strip_prefix(PROGRESS_PREFIX)?
+        .split_whitespace()
+        .map(|f| f.parse::<f64>().ok());
+    let mut next = || fields.next().flatten();
+    let (downloaded, total, estimate, speed, eta) = (next(), next(), next(), next(), next());
+    let percent = downloaded
+        .zip(total.or(estimate))
+        .filter(|(_, total)| *total > 0.0)
+        .map(|(downloaded, total)| (downloaded / total * 100.0).min(100.0) as f32);
+    Some(Progress {
+        percent,
+        speed,
+        eta: eta.map(|e| e as u64),
+    })
+}
 
-    Command::new("ffmpeg")
-        .args(["-i", path.to_str().unwrap(), new_loc.to_str().unwrap()])
+/// Removes what yt-dlp left behind when a download fails or is cancelled
+struct TempFiles(PathBuf);
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for extension in ["", ".part", ".ytdl", CONVERTED_EXTENSION] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(extension);
+            // Most of them don't exist, which is fine
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// TODO: Use Opus instead of mp3
+/// Converts the downloaded file to an mp3 named after the song. ffmpeg writes next to the
+/// download and the result is only moved into place once it is whole, under a name no other
+/// song has. ffmpeg is killed if the returned future is dropped.
+pub(crate) async fn change_format_and_name_better(
+    name: &str,
+    path: PathBuf,
+) -> Result<PathBuf, String> {
+    let converted = converted_path(&path);
+    let output = tokio::process::Command::new("ffmpeg")
+        .arg("-n")
+        .arg("-i")
+        .arg(&path)
+        .arg(&converted)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
         .output()
-        .expect("Failed the command");
-    fs::remove_file(path).unwrap();
+        .await
+        .map_err(|e| format!("Failed to start ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "ffmpeg failed to convert {}: {}",
+            name,
+            stderr.lines().last().unwrap_or_default()
+        ));
+    }
+    let new_loc = free_path(&Configuration::get_conf().owned_path, &gen_filename(name));
+    fs::rename(&converted, &new_loc)
+        .map_err(|e| format!("Failed to move {}: {}", new_loc.display(), e))?;
     Ok(new_loc)
 }
 
+/// Where ffmpeg writes the conversion of the download at `path`, `TempFiles` removes it
+fn converted_path(path: &Path) -> PathBuf {
+    let mut converted = path.as_os_str().to_owned();
+    converted.push(CONVERTED_EXTENSION);
+    converted.into()
+}
+
+/// A path in `folder` for a song called `name` that no file has yet. Songs with the same name
+/// get a number after it.
+fn free_path(folder: &Path, name: &str) -> PathBuf {
+    let extension = Format::MP3.filetype_to_extension().unwrap();
+    (1..)
+        .map(|i| match i {
+            1 => folder.join(format!("{}{}", name, extension)),
+            i => folder.join(format!("{} {}{}", name, i, extension)),
+        })
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
 fn gen_filename(name: &str) -> String {
     name.replace(['/', '\\'], "-")
         .replace([':', '.', '!', '?', '\"', '\''], "")
@@ -104,7 +511,7 @@ fn gen_filename(name: &str) -> String {
 
 async fn get_image(url: String) -> Option<DynamicImage> {
     let resp = reqwest::get(url).await.ok()?;
-    let bytes = resp.bytes().await.unwrap();
+    let bytes = resp.bytes().await.ok()?;
     image::io::Reader::new(Cursor::new(bytes.as_bytes()))
         .with_guessed_format()
         .ok()?
@@ -112,35 +519,89 @@ async fn get_image(url: String) -> Option<DynamicImage> {
         .ok()
 }
 
-fn set_metadata(song: Song, img: Option<DynamicImage>) -> Result<(), id3::Error> {
-    let mut tag = Tag::read_from_path(song.path.clone()).unwrap_or(Tag::new());
+/// Parses the `YYYYMMDD` dates yt-dlp uses
+fn parse_upload_date(date: &str) -> Option<Timestamp> {
+    Some(Timestamp {
+        year: date.get(0..4)?.parse().ok()?,
+        month: date.get(4..6).and_then(|m| m.parse().ok()),
+        day: date.get(6..8).and_then(|d| d.parse().ok()),
+        hour: None,
+        minute: None,
+        second: None,
+    })
+}
+
+/// Writes the tags of a downloaded song. Only fields we know are set and existing frames are
+/// replaced, so running this again on the same file does not duplicate anything.
+fn set_metadata(song: &Song, meta: &Metadata, img: Option<DynamicImage>) -> Result<(), id3::Error> {
+    let mut tag = Tag::read_from_path(&song.path).unwrap_or(Tag::new());
     tag.set_title(song.name.replace(['/', '\\'], "-"));
-    if let Some(artist) = song.artist {
-        tag.set_artist(artist)
-    } else {
-        tag.set_artist("")
+    if let Some(artist) = &song.artist {
+        tag.set_artist(artist.as_str());
+    }
+    if let Some(album) = &meta.album {
+        tag.set_album(album.as_str());
+    }
+    if let Some(date) = meta.date {
+        tag.remove_year();
+        tag.set_date_recorded(date);
     }
-    tag.set_album("");
-    if let Some(url) = song.url {
-        let frame = Frame::link("WOAF", url);
-        tag.add_frame(frame);
+    if let Some(description) = &meta.description {
+        tag.remove_comment(Some(""), None);
+        tag.add_frame(Comment {
+            lang: "eng".to_string(),
+            description: "".to_string(),
+            text: description.clone(),
+        });
+    }
+    if let Some(url) = &song.url {
+        tag.remove("WOAF");
+        tag.add_frame(Frame::link("WOAF", url.as_str()));
+    }
+    if !meta.chapters.is_empty() {
+        tag.remove("CHAP");
+        tag.remove("CTOC");
+        let mut elements = vec![];
+        for (i, c) in meta.chapters.iter().enumerate() {
+            let element_id = format!("chp{}", i);
+            tag.add_frame(Chapter {
+                element_id: element_id.clone(),
+                start_time: c.start,
+                end_time: c.end,
+                start_offset: u32::MAX,
+                end_offset: u32::MAX,
+                frames: vec![Frame::text("TIT2", c.title.as_str())],
+            });
+            elements.push(element_id);
+        }
+        tag.add_frame(TableOfContents {
+            element_id: "toc".to_string(),
+            top_level: true,
+            ordered: true,
+            elements,
+            frames: vec![],
+        });
+    }
+    for (description, value) in &meta.extra {
+        tag.remove_extended_text(Some(description.as_str()), None);
+        tag.add_frame(ExtendedText {
+            description: description.clone(),
+            value: value.clone(),
+        });
     }
-    let mut picture_data: Vec<u8> = Vec::new();
     if let Some(img) = img {
-        if let Err(e) = img.write_to(
-            &mut Cursor::new(&mut picture_data),
-            ImageOutputFormat::Jpeg(90),
-        ) {
-            println!("Error occured while writing image to buf: {:?}", e)
-        } else {
-            let picture = Picture {
-                mime_type: "image/jpeg".to_string(),
-                picture_type: id3::frame::PictureType::CoverFront,
-                description: "A picture".to_string(),
-                data: picture_data,
-            };
-            tag.add_frame(picture);
+        match encode_jpeg(&img) {
+            Err(e) => println!("Error occured while writing image to buf: {:?}", e),
+            Ok(data) => {
+                tag.remove_picture_by_type(PictureType::CoverFront);
+                tag.add_frame(Picture {
+                    mime_type: "image/jpeg".to_string(),
+                    picture_type: PictureType::CoverFront,
+                    description: "A picture".to_string(),
+                    data,
+                });
+            }
         }
     }
-    tag.write_to_path(song.path, id3::Version::Id3v22)
+    tag.write_to_path(&song.path, id3::Version::Id3v24)
 }
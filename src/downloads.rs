@@ -0,0 +1,437 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    conf::Configuration,
+    downloader::{self, Progress},
+    events::{self, Event},
+    files::{duplicates::normalize_url, list_all_songs},
+    song::Song,
+};
+
+/// How many finished jobs are kept around to be listed
+const KEPT_JOBS: usize = 100;
+/// How often the listeners of the events are told about the progress of a job
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type JobId = u64;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for a running job to finish
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_done(self) -> bool {
+        matches!(
+            self,
+            JobState::Finished | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// A download of a single url
+#[derive(Serialize, Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub url: String,
+    pub state: JobState,
+    pub progress: Progress,
+    /// The label of the key that added the job, `None` for jobs added by the player itself
+    pub owner: Option<String>,
+    /// How many times the job has been started
+    pub attempts: u32,
+    /// The downloaded song once the job has finished
+    pub song: Option<Song>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JobError {
+    NotFound,
+    /// The job can't be cancelled or retried in the state it is in
+    WrongState(JobState),
+}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::NotFound => write!(f, "No such download"),
+            JobError::WrongState(s) => write!(f, "The download is {:?}", s),
+        }
+    }
+}
+
+/// An entry of a playlist, with the song if it is in the library and the job downloading it if
+/// it isn't
+#[derive(Serialize, Debug, Clone)]
+pub struct PlaylistEntry {
+    pub url: String,
+    pub song: Option<Song>,
+    pub job: Option<JobId>,
+}
+
+/// A playlist or a channel being downloaded
+#[derive(Serialize, Debug, Clone)]
+pub struct Playlist {
+    pub title: Option<String>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+impl Playlist {
+    /// Lists the videos of the playlist and finds the ones that are already in the library
+    pub async fn read(url: &str) -> Result<Playlist, String> {
+        let info = downloader::list_playlist(url).await?;
+        let library: HashMap<String, Song> = list_all_songs()
+            .into_iter()
+            .filter_map(|s| Some((normalize_url(s.url.as_deref()?), s)))
+            .collect();
+        let entries = info
+            .urls
+            .into_iter()
+            .map(|url| PlaylistEntry {
+                song: library.get(&normalize_url(&url)).cloned(),
+                url,
+                job: None,
+            })
+            .collect();
+        Ok(Playlist {
+            title: info.title,
+            entries,
+        })
+    }
+
+    /// How many of the entries aren't in the library
+    pub fn missing(&self) -> usize {
+        self.entries.iter().filter(|e| e.song.is_none()).count()
+    }
+
+    /// Adds a download of every entry that isn't in the library, inside a tokio runtime like
+    /// `enqueue`
+    pub fn download(&mut self, owner: Option<String>) {
+        for entry in &mut self.entries {
+            if entry.song.is_none() && entry.job.is_none() {
+                entry.job = Some(enqueue(entry.url.clone(), owner.clone()));
+            }
+        }
+    }
+
+    /// Waits until the downloads are done and returns the songs in the order of the playlist,
+    /// leaving out the ones that couldn't be downloaded
+    pub async fn songs(&self) -> Vec<Song> {
+        let mut songs = vec![];
+        for entry in &self.entries {
+            let song = match entry.job {
+                Some(id) => wait(id).await.and_then(|j| j.song),
+                None => entry.song.clone(),
+            };
+            songs.extend(song);
+        }
+        songs
+    }
+}
+
+struct Entry {
+    job: Job,
+    task: Option<JoinHandle<()>>,
+    /// Lets `wait` find out when the job is done
+    state: watch::Sender<JobState>,
+    /// When the listeners of the events were last told about the job
+    published: Instant,
+}
+
+impl Entry {
+    fn set_state(&mut self, state: JobState) {
+        self.job.state = state;
+        self.state.send_replace(state);
+        self.publish();
+    }
+
+    fn publish(&mut self) {
+        self.published = Instant::now();
+        events::publish(Event::Download(self.job.clone()));
+    }
+}
+
+static JOBS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How many downloads run at once unless the configuration says otherwise
+pub fn default_concurrency() -> usize {
+    2
+}
+
+/// Adds a download of the url and returns its id. The download starts once there is room for
+/// it, so this has to be called inside a tokio runtime. If the url is already being downloaded,
+/// the id of that job is returned instead of downloading it twice.
+pub fn enqueue(url: String, owner: Option<String>) -> JobId {
+    let limit = max_downloads();
+    let mut jobs = JOBS.lock().unwrap();
+    let normalized = normalize_url(&url);
+    if let Some(active) = jobs
+        .iter()
+        .find(|e| !e.job.state.is_done() && normalize_url(&e.job.url) == normalized)
+    {
+        return active.job.id;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut entry = Entry {
+        job: Job {
+            id,
+            url,
+            state: JobState::Queued,
+            progress: Progress::default(),
+            owner,
+            attempts: 0,
+            song: None,
+            error: None,
+        },
+        task: None,
+        state: watch::channel(JobState::Queued).0,
+        published: Instant::now(),
+    };
+    entry.publish();
+    jobs.push(entry);
+    forget_old(&mut jobs);
+    start_next(&mut jobs, limit);
+    id
+}
+
+/// Every job, the oldest first
+pub fn list() -> Vec<Job> {
+    JOBS.lock().unwrap().iter().map(|e| e.job.clone()).collect()
+}
+
+pub fn get(id: JobId) -> Option<Job> {
+    JOBS.lock()
+        .unwrap()
+        .iter()
+        .find(|e| e.job.id == id)
+        .map(|e| e.job.clone())
+}
+
+/// Stops a job that is waiting or running, yt-dlp is killed and its files removed. Like
+/// `enqueue` this has to be called inside a tokio runtime, as the next job is started.
+pub fn cancel(id: JobId) -> Result<Job, JobError> {
+    let limit = max_downloads();
+    let mut jobs = JOBS.lock().unwrap();
+    let entry = find(&mut jobs, id)?;
+    if entry.job.state.is_done() {
+        return Err(JobError::WrongState(entry.job.state));
+    }
+    if let Some(task) = entry.task.take() {
+        task.abort();
+    }
+    entry.set_state(JobState::Cancelled);
+    let job = entry.job.clone();
+    start_next(&mut jobs, limit);
+    Ok(job)
+}
+
+/// Queues a job that failed or was cancelled again, inside a tokio runtime like `enqueue`
+pub fn retry(id: JobId) -> Result<Job, JobError> {
+    let limit = max_downloads();
+    let mut jobs = JOBS.lock().unwrap();
+    let entry = find(&mut jobs, id)?;
+    if !matches!(entry.job.state, JobState::Failed | JobState::Cancelled) {
+        return Err(JobError::WrongState(entry.job.state));
+    }
+    entry.job.progress = Progress::default();
+    entry.job.error = None;
+    entry.set_state(JobState::Queued);
+    start_next(&mut jobs, limit);
+    Ok(get_job(&jobs, id))
+}
+
+/// Waits until the job is done and returns it, `None` if there is no such job
+pub async fn wait(id: JobId) -> Option<Job> {
+    let mut state = find(&mut JOBS.lock().unwrap(), id).ok()?.state.subscribe();
+    while !state.borrow().is_done() {
+        state.changed().await.ok()?;
+    }
+    get(id)
+}
+
+fn find(jobs: &mut [Entry], id: JobId) -> Result<&mut Entry, JobError> {
+    jobs.iter_mut()
+        .find(|e| e.job.id == id)
+        .ok_or(JobError::NotFound)
+}
+
+fn get_job(jobs: &[Entry], id: JobId) -> Job {
+    jobs.iter().find(|e| e.job.id == id).unwrap().job.clone()
+}
+
+/// How many jobs may run at once. The configuration is read before `JOBS` is locked, so the
+/// progress of the running jobs doesn't wait for the file.
+fn max_downloads() -> usize {
+    Configuration::get_conf().max_downloads.max(1)
+}
+
+/// Starts queued jobs, the oldest first, until `limit` of them run
+fn start_next(jobs: &mut [Entry], limit: usize) {
+    let running = jobs
+        .iter()
+        .filter(|e| e.job.state == JobState::Running)
+        .count();
+    let queued = jobs.iter_mut().filter(|e| e.job.state == JobState::Queued);
+    for entry in queued.take(limit.saturating_sub(running)) {
+        entry.job.attempts += 1;
+        entry.set_state(JobState::Running);
+        let (id, attempt) = (entry.job.id, entry.job.attempts);
+        entry.task = Some(tokio::spawn(run(id, attempt, entry.job.url.clone())));
+    }
+}
+
+async fn run(id: JobId, attempt: u32, url: String) {
+    // The download runs in a task of its own, so a panic in it fails the job instead of leaving
+    // it running for good
+    let mut download = AbortOnDrop(tokio::spawn(downloader::download_with_progress(
+        url,
+        move |progress| {
+            let mut jobs = JOBS.lock().unwrap();
+            match find(&mut jobs, id) {
+                Ok(entry) if entry.job.attempts == attempt => {
+                    entry.job.progress = progress;
+                    if entry.published.elapsed() >= PROGRESS_INTERVAL {
+                        entry.publish();
+                    }
+                }
+                _ => {}
+            }
+        },
+    )));
+    let result = match (&mut download.0).await {
+        Ok(result) => result,
+        Err(e) => Err(format!("The download stopped unexpectedly: {}", e)),
+    };
+    let limit = max_downloads();
+    let mut jobs = JOBS.lock().unwrap();
+    match find(&mut jobs, id) {
+        // A job that was cancelled or started again is no longer this run's to finish
+        Ok(entry) if entry.job.attempts == attempt && entry.job.state == JobState::Running => {
+            entry.task = None;
+            match result {
+                Ok(song) => {
+                    entry.job.progress.percent = Some(100.0);
+                    entry.job.song = Some(song);
+                    entry.set_state(JobState::Finished);
+                }
+                Err(e) => {
+                    println!("Failed to download {}: {}", entry.job.url, e);
+                    entry.job.error = Some(e);
+                    entry.set_state(JobState::Failed);
+                }
+            }
+        }
+        _ => {}
+    }
+    start_next(&mut jobs, limit);
+}
+
+/// Aborts the task when dropped, so cancelling a job also stops the download it supervises
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Removes the oldest jobs that are done once there are more than `KEPT_JOBS` of them
+fn forget_old(jobs: &mut Vec<Entry>) {
+    let done = jobs.iter().filter(|e| e.job.state.is_done()).count();
+    let mut extra = done.saturating_sub(KEPT_JOBS);
+    jobs.retain(|e| {
+        let forget = extra > 0 && e.job.state.is_done();
+        if forget {
+            extra -= 1;
+        }
+        !forget
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs() {
+        // The download fails right away since nothing is listening on the port
+        let id = enqueue(
+            "http://127.0.0.1:9/song".to_string(),
+            Some("guest".to_string()),
+        );
+        let job = wait(id).await.unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!((job.attempts, job.owner.as_deref()), (1, Some("guest")));
+        assert!(job.error.is_some());
+        assert_eq!(
+            cancel(id).unwrap_err(),
+            JobError::WrongState(JobState::Failed)
+        );
+
+        let job = retry(id).unwrap();
+        assert!(matches!(job.state, JobState::Queued | JobState::Running));
+        assert_eq!(wait(id).await.unwrap().attempts, 2);
+        assert_eq!(cancel(JobId::MAX).unwrap_err(), JobError::NotFound);
+        assert!(list().iter().any(|j| j.id == id));
+    }
+
+    #[tokio::test]
+    async fn test_same_url() {
+        // The port is closed, so the downloads fail without reaching anything
+        let first = enqueue("http://127.0.0.1:9/same".to_string(), None);
+        let second = enqueue("http://127.0.0.1:9/same".to_string(), None);
+        assert_eq!(first, second);
+        wait(first).await.unwrap();
+        // Once the first is done the url can be downloaded again
+        assert_ne!(enqueue("http://127.0.0.1:9/same".to_string(), None), first);
+    }
+
+    #[tokio::test]
+    async fn test_playlist() {
+        let song = Song {
+            id: "a".to_string(),
+            name: "a".to_string(),
+            artist: None,
+            url: Some("https://youtu.be/a".to_string()),
+            path: "a.mp3".into(),
+            format: crate::format::Format::MP3,
+        };
+        let entry = |url: &str, song: Option<Song>| PlaylistEntry {
+            url: url.to_string(),
+            song,
+            job: None,
+        };
+        let mut playlist = Playlist {
+            title: None,
+            entries: vec![
+                entry("http://127.0.0.1:9/b", None),
+                entry("https://youtu.be/a", Some(song)),
+            ],
+        };
+        assert_eq!(playlist.missing(), 1);
+        playlist.download(None);
+        assert!(playlist.entries[0].job.is_some());
+        assert!(playlist.entries[1].job.is_none());
+        // The download fails, so only the song in the library is left
+        let songs = playlist.songs().await;
+        assert_eq!(songs.iter().map(|s| &s.id).collect::<Vec<_>>(), ["a"]);
+    }
+}
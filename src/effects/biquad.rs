@@ -0,0 +1,90 @@
+use std::f64::consts::PI;
+
+/// A second order IIR filter, with coefficients normalized so that a0 is 1
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    // The following filters are from the Audio EQ Cookbook by Robert Bristow-Johnson
+
+    pub fn peaking(rate: u32, frequency: f64, gain: f64, q: f64) -> Self {
+        let (a, cos, alpha) = Self::params(rate, frequency, gain, q);
+        Self::normalized(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(rate: u32, frequency: f64, gain: f64, q: f64) -> Self {
+        let (a, cos, alpha) = Self::params(rate, frequency, gain, q);
+        let s = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + s),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - s),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + s,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - s,
+            ],
+        )
+    }
+
+    pub fn high_shelf(rate: u32, frequency: f64, gain: f64, q: f64) -> Self {
+        let (a, cos, alpha) = Self::params(rate, frequency, gain, q);
+        let s = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + s),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - s),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + s,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - s,
+            ],
+        )
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    /// Returns the linear amplitude, cos(w0) and alpha of the cookbook formulas. The frequency
+    /// is kept below the Nyquist frequency.
+    fn params(rate: u32, frequency: f64, gain: f64, q: f64) -> (f64, f64, f64) {
+        let nyquist = rate as f64 / 2.0;
+        let w0 = 2.0 * PI * frequency.clamp(1.0, nyquist * 0.99) / rate as f64;
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        (10f64.powf(gain / 40.0), w0.cos(), alpha)
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self::new(
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [a[1] / a[0], a[2] / a[0]],
+        )
+    }
+}
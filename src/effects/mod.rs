@@ -0,0 +1,325 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+use self::biquad::Biquad;
+
+pub mod biquad;
+pub mod stretch;
+
+/// How many frames are played before checking if the settings have changed
+const CHECK_INTERVAL: usize = 1024;
+/// The level the limiter keeps the samples under
+const LIMITER_THRESHOLD: f32 = 0.95;
+/// How much of the remaining gain reduction the limiter releases every frame
+const LIMITER_RELEASE: f32 = 0.0005;
+/// The corner frequency of the bass boost
+const BASS_BOOST_FREQUENCY: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum BandKind {
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+/// A band of the parametric equalizer, the gain is in dB
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct EqBand {
+    pub kind: BandKind,
+    pub frequency: f32,
+    pub gain: f32,
+    pub q: f32,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum EqPreset {
+    Flat,
+    Rock,
+    Pop,
+    Jazz,
+    Classical,
+    Vocal,
+}
+
+/// The settings of the effects applied to every song
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Effects {
+    pub equalizer: Vec<EqBand>,
+    /// Gain of the bass boost in dB, 0 turns it off
+    pub bass_boost: f32,
+    /// Mixes all channels into one
+    pub mono: bool,
+    /// Keeps the samples from clipping
+    pub limiter: bool,
+}
+
+/// A partial change of the effects, as sent over the remote API
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EffectsUpdate {
+    pub equalizer: Option<Vec<EqBand>>,
+    pub preset: Option<EqPreset>,
+    pub bass_boost: Option<f32>,
+    pub mono: Option<bool>,
+    pub limiter: Option<bool>,
+}
+
+impl EqPreset {
+    const FREQUENCIES: [f32; 5] = [60.0, 250.0, 1000.0, 4000.0, 12000.0];
+
+    pub fn bands(&self) -> Vec<EqBand> {
+        let gains: [f32; 5] = match self {
+            EqPreset::Flat => return vec![],
+            EqPreset::Rock => [4.0, 2.0, -1.0, 2.0, 4.0],
+            EqPreset::Pop => [-1.0, 2.0, 4.0, 2.0, -1.0],
+            EqPreset::Jazz => [3.0, 1.0, -1.0, 1.0, 3.0],
+            EqPreset::Classical => [3.0, 0.0, 0.0, 1.0, 3.0],
+            EqPreset::Vocal => [-2.0, -1.0, 3.0, 3.0, 0.0],
+        };
+        Self::FREQUENCIES
+            .iter()
+            .zip(gains)
+            .enumerate()
+            .map(|(i, (frequency, gain))| EqBand {
+                kind: match i {
+                    0 => BandKind::LowShelf,
+                    4 => BandKind::HighShelf,
+                    _ => BandKind::Peak,
+                },
+                frequency: *frequency,
+                gain,
+                q: 1.0,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for EqPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "flat" => Ok(EqPreset::Flat),
+            "rock" => Ok(EqPreset::Rock),
+            "pop" => Ok(EqPreset::Pop),
+            "jazz" => Ok(EqPreset::Jazz),
+            "classical" => Ok(EqPreset::Classical),
+            "vocal" => Ok(EqPreset::Vocal),
+            _ => Err(format!("Unknown preset {}", s)),
+        }
+    }
+}
+
+impl Effects {
+    fn filters(&self, rate: u32) -> Vec<Biquad> {
+        let mut filters: Vec<Biquad> = self
+            .equalizer
+            .iter()
+            .map(|b| {
+                let (f, g, q) = (b.frequency as f64, b.gain as f64, b.q as f64);
+                match b.kind {
+                    BandKind::Peak => Biquad::peaking(rate, f, g, q),
+                    BandKind::LowShelf => Biquad::low_shelf(rate, f, g, q),
+                    BandKind::HighShelf => Biquad::high_shelf(rate, f, g, q),
+                }
+            })
+            .collect();
+        if self.bass_boost != 0.0 {
+            filters.push(Biquad::low_shelf(
+                rate,
+                BASS_BOOST_FREQUENCY as f64,
+                self.bass_boost as f64,
+                0.7,
+            ));
+        }
+        filters
+    }
+}
+
+/// Applies the effects to a source. The settings are shared with the player so they can be
+/// changed while the song is playing.
+pub struct EffectsSource<S>
+where
+    S: Source<Item = f32>,
+{
+    inner: S,
+    shared: Arc<Mutex<Effects>>,
+    effects: Effects,
+    /// The filters of every channel
+    filters: Vec<Vec<Biquad>>,
+    frame: Vec<f32>,
+    pos: usize,
+    frames_since_check: usize,
+    limiter_gain: f32,
+}
+
+impl<S> EffectsSource<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, shared: Arc<Mutex<Effects>>) -> Self {
+        let effects = shared.lock().unwrap().clone();
+        EffectsSource {
+            inner,
+            shared,
+            effects,
+            filters: vec![],
+            frame: vec![],
+            pos: 0,
+            frames_since_check: 0,
+            limiter_gain: 1.0,
+        }
+    }
+
+    fn rebuild(&mut self, channels: usize) {
+        let rate = self.inner.sample_rate();
+        self.filters = (0..channels).map(|_| self.effects.filters(rate)).collect();
+    }
+
+    /// Reads and processes the next frame, returns `None` when the inner source has ended
+    fn next_frame(&mut self) -> Option<()> {
+        self.frames_since_check += 1;
+        if self.frames_since_check >= CHECK_INTERVAL {
+            self.frames_since_check = 0;
+            // Never block the audio thread, the change will be picked up on the next check
+            if let Ok(shared) = self.shared.try_lock() {
+                if *shared != self.effects {
+                    self.effects = shared.clone();
+                    self.filters.clear();
+                }
+            }
+        }
+
+        let channels = self.inner.channels().max(1) as usize;
+        self.frame.clear();
+        for _ in 0..channels {
+            match self.inner.next() {
+                Some(s) => self.frame.push(s),
+                None => break,
+            }
+        }
+        if self.frame.is_empty() {
+            return None;
+        }
+        if self.filters.len() != self.frame.len() {
+            self.rebuild(self.frame.len());
+        }
+
+        for (sample, filters) in self.frame.iter_mut().zip(self.filters.iter_mut()) {
+            let mut s = *sample as f64;
+            for filter in filters.iter_mut() {
+                s = filter.process(s);
+            }
+            *sample = s as f32;
+        }
+        if self.effects.mono {
+            let mean = self.frame.iter().sum::<f32>() / self.frame.len() as f32;
+            self.frame.iter_mut().for_each(|s| *s = mean);
+        }
+        if self.effects.limiter {
+            let peak = self.frame.iter().fold(0.0f32, |p, s| p.max(s.abs()));
+            if peak * self.limiter_gain > LIMITER_THRESHOLD {
+                self.limiter_gain = LIMITER_THRESHOLD / peak;
+            } else {
+                self.limiter_gain += (1.0 - self.limiter_gain) * LIMITER_RELEASE;
+            }
+            let gain = self.limiter_gain;
+            self.frame.iter_mut().for_each(|s| *s *= gain);
+        }
+        self.pos = 0;
+        Some(())
+    }
+}
+
+impl<S> Iterator for EffectsSource<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pos >= self.frame.len() {
+            self.next_frame()?;
+        }
+        let sample = self.frame[self.pos];
+        self.pos += 1;
+        Some(sample)
+    }
+}
+
+impl<S> Source for EffectsSource<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner
+            .current_frame_len()
+            .map(|l| l + self.frame.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn process(effects: Effects, channels: u16, samples: Vec<f32>) -> Vec<f32> {
+        let source = SamplesBuffer::new(channels, 44100, samples);
+        EffectsSource::new(source, Arc::new(Mutex::new(effects))).collect()
+    }
+
+    #[test]
+    fn test_flat_is_unchanged() {
+        let samples = vec![0.1, -0.2, 0.3, -0.4];
+        assert_eq!(process(Effects::default(), 2, samples.clone()), samples);
+    }
+
+    #[test]
+    fn test_mono() {
+        let effects = Effects {
+            mono: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            process(effects, 2, vec![0.2, 0.4, -0.2, 0.0]),
+            vec![0.3, 0.3, -0.1, -0.1]
+        );
+    }
+
+    #[test]
+    fn test_limiter() {
+        let effects = Effects {
+            limiter: true,
+            ..Default::default()
+        };
+        let out = process(effects, 1, vec![2.0, -3.0, 0.5]);
+        assert!(out
+            .iter()
+            .all(|s| s.abs() <= LIMITER_THRESHOLD + f32::EPSILON));
+    }
+
+    #[test]
+    fn test_presets() {
+        assert!(EqPreset::Flat.bands().is_empty());
+        assert_eq!(EqPreset::Rock.bands().len(), 5);
+        assert_eq!("rock".parse::<EqPreset>(), Ok(EqPreset::Rock));
+    }
+}
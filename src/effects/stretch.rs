@@ -0,0 +1,261 @@
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+
+/// Length of the segments that are overlapped, in seconds
+const SEGMENT_LENGTH: f32 = 0.04;
+/// How far from the ideal position a segment may be taken to line up with the previous one,
+/// in seconds
+const SEARCH_LENGTH: f32 = 0.005;
+const MIN_TEMPO: f32 = 0.25;
+const MAX_TEMPO: f32 = 4.0;
+
+/// Changes the tempo of a source without changing its pitch using WSOLA (waveform similarity
+/// overlap-add). The tempo is shared with the player so it can be changed while playing.
+pub struct TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    inner: S,
+    tempo: Arc<Mutex<f32>>,
+    current_tempo: f32,
+    channels: usize,
+    /// Frames per hop, a segment is two hops long
+    hop: usize,
+    search: usize,
+    window: Vec<f32>,
+    /// The input frames that might still be used, starting at the frame `offset`
+    input: Vec<f32>,
+    offset: usize,
+    ended: bool,
+    /// The ideal position of the next segment in the input
+    position: f64,
+    /// Where the previous segment would have continued
+    natural: Option<usize>,
+    /// The second half of the previous segment, which the next one is added onto
+    overlap: Vec<f32>,
+    output: Vec<f32>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<S> TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, tempo: Arc<Mutex<f32>>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let rate = inner.sample_rate() as f32;
+        let hop = ((rate * SEGMENT_LENGTH) as usize / 2).max(1);
+        let window = (0..hop * 2)
+            .map(|i| 0.5 - 0.5 * (PI * i as f32 / hop as f32).cos())
+            .collect();
+        TimeStretch {
+            inner,
+            tempo,
+            current_tempo: 1.0,
+            channels,
+            hop,
+            search: (rate * SEARCH_LENGTH) as usize,
+            window,
+            input: vec![],
+            offset: 0,
+            ended: false,
+            position: 0.0,
+            natural: None,
+            overlap: vec![0.0; hop * channels],
+            output: vec![],
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    /// The sample of a channel at an absolute frame, silence past the end of the input
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        let i = (frame - self.offset) * self.channels + channel;
+        self.input.get(i).copied().unwrap_or(0.0)
+    }
+
+    /// Reads the inner source until the frame `end` is buffered or the source ends
+    fn fill(&mut self, end: usize) {
+        while !self.ended && self.offset + self.input.len() / self.channels < end {
+            for _ in 0..self.channels {
+                match self.inner.next() {
+                    Some(s) => self.input.push(s),
+                    None => {
+                        self.ended = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The start of the candidate segment that lines up best with where the previous segment
+    /// would have continued
+    fn best_start(&self, natural: usize, from: usize, to: usize) -> usize {
+        (from..=to)
+            .map(|start| {
+                let similarity: f32 = (0..self.hop)
+                    .flat_map(|i| (0..self.channels).map(move |c| (i, c)))
+                    .map(|(i, c)| self.sample(start + i, c) * self.sample(natural + i, c))
+                    .sum();
+                (start, similarity)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(start, _)| start)
+            .unwrap_or(natural)
+    }
+
+    /// Adds the next segment and puts a hop of finished frames in the output, returns `None`
+    /// when all of the input has been used
+    fn next_hop(&mut self) -> Option<()> {
+        if self.finished {
+            return None;
+        }
+        // Never block the audio thread, the change will be picked up on the next hop
+        if let Ok(t) = self.tempo.try_lock() {
+            self.current_tempo = t.clamp(MIN_TEMPO, MAX_TEMPO);
+        }
+        let target = self.position.round() as usize;
+        let from = target.saturating_sub(self.search).max(self.offset);
+        let to = target + self.search;
+        self.fill(to + self.hop * 2);
+
+        let available = self.offset + self.input.len() / self.channels;
+        if self.ended && from >= available {
+            // Play out what is left of the last segment
+            self.finished = true;
+            self.output = std::mem::take(&mut self.overlap);
+            self.pos = 0;
+            return if self.output.is_empty() {
+                None
+            } else {
+                Some(())
+            };
+        }
+
+        let start = match self.natural {
+            // Taking the natural continuation reproduces the input exactly
+            Some(natural) if self.current_tempo == 1.0 => natural,
+            Some(natural) => self.best_start(natural, from, to.min(available)),
+            None => target,
+        };
+
+        self.output.clear();
+        for i in 0..self.hop {
+            // The first segment has nothing to fade in from
+            let w = if self.natural.is_none() {
+                1.0
+            } else {
+                self.window[i]
+            };
+            for c in 0..self.channels {
+                let s = self.overlap[i * self.channels + c] + w * self.sample(start + i, c);
+                self.output.push(s);
+            }
+        }
+        for i in 0..self.hop {
+            let w = self.window[self.hop + i];
+            for c in 0..self.channels {
+                self.overlap[i * self.channels + c] = w * self.sample(start + self.hop + i, c);
+            }
+        }
+        self.pos = 0;
+
+        self.natural = Some(start + self.hop);
+        self.position = if self.current_tempo == 1.0 {
+            (start + self.hop) as f64
+        } else {
+            self.position + self.hop as f64 * self.current_tempo as f64
+        };
+
+        // Drop the frames that can no longer be part of a segment
+        let keep = (start + self.hop).min((self.position as usize).saturating_sub(self.search));
+        if keep > self.offset {
+            let drop = ((keep - self.offset) * self.channels).min(self.input.len());
+            self.input.drain(..drop);
+            self.offset += drop / self.channels;
+        }
+        Some(())
+    }
+}
+
+impl<S> Iterator for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pos >= self.output.len() {
+            self.next_hop()?;
+        }
+        let sample = self.output.get(self.pos).copied();
+        self.pos += 1;
+        sample
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn sine(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * PI * 440.0 * i as f32 / 44100.0).sin() * 0.5)
+            .collect()
+    }
+
+    fn stretch(samples: Vec<f32>, tempo: f32) -> Vec<f32> {
+        let source = SamplesBuffer::new(1, 44100, samples);
+        TimeStretch::new(source, Arc::new(Mutex::new(tempo))).collect()
+    }
+
+    #[test]
+    fn test_normal_tempo_is_unchanged() {
+        let samples = sine(10000);
+        let out = stretch(samples.clone(), 1.0);
+        assert!(out.len() >= samples.len());
+        assert!(samples
+            .iter()
+            .zip(out.iter())
+            .all(|(a, b)| (a - b).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_tempo_changes_length() {
+        let samples = sine(44100);
+        let fast = stretch(samples.clone(), 2.0).len() as f32;
+        let slow = stretch(samples, 0.5).len() as f32;
+        assert!((fast - 22050.0).abs() < 2000.0, "{}", fast);
+        assert!((slow - 88200.0).abs() < 2000.0, "{}", slow);
+    }
+}
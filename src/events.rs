@@ -0,0 +1,198 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::{downloads::Job, player_state::PlayerState, song::Song};
+
+/// Events a listener can fall behind before it misses some
+const LISTENER_BACKLOG: usize = 64;
+
+static LISTENERS: Mutex<Vec<Sender<Event>>> = Mutex::new(Vec::new());
+
+/// Something that happened in the player
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A song started playing, or nothing is playing anymore
+    SongChanged {
+        song: Option<Song>,
+        /// The length of the song in seconds
+        duration: Option<f64>,
+    },
+    QueueChanged {
+        queue: Vec<Song>,
+    },
+    Paused,
+    Resumed,
+    VolumeChanged {
+        volume: f32,
+    },
+    /// Sent every second while a song plays and when it is seeked
+    Position {
+        elapsed: f64,
+        duration: Option<f64>,
+    },
+    /// A download job changed its state or made progress
+    Download(Job),
+}
+
+impl Event {
+    /// The name of the event, the same as its `type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::SongChanged { .. } => "song_changed",
+            Event::QueueChanged { .. } => "queue_changed",
+            Event::Paused => "paused",
+            Event::Resumed => "resumed",
+            Event::VolumeChanged { .. } => "volume_changed",
+            Event::Position { .. } => "position",
+            Event::Download(_) => "download",
+        }
+    }
+}
+
+/// Returns a receiver of every event from now on
+pub fn subscribe() -> Receiver<Event> {
+    let (sender, receiver) = mpsc::channel(LISTENER_BACKLOG);
+    LISTENERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Sends the event to every listener
+pub fn publish(event: Event) {
+    let mut listeners = LISTENERS.lock().unwrap();
+    // Slow listeners miss the event, listeners that are gone are removed
+    listeners.retain(|l| !matches!(l.try_send(event.clone()), Err(TrySendError::Closed(_))));
+}
+
+/// Remembers what the listeners were last told about the state, to find out what has changed.
+/// Nothing is known at first, so the first update describes the whole state.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    now_playing: Option<Option<String>>,
+    queue: Option<Vec<String>>,
+    paused: Option<bool>,
+    volume: Option<f32>,
+    /// The whole seconds of the song that had been played
+    elapsed: Option<u64>,
+}
+
+impl Tracker {
+    /// The events that describe how the state changed since the last update
+    pub fn update(&mut self, state: &PlayerState) -> Vec<Event> {
+        let mut events = vec![];
+        let duration = state.total_duration.map(|d| d.as_secs_f64());
+        let now_playing = state.now_playing.as_ref().map(|s| s.id.clone());
+        let song_changed = self.now_playing.as_ref() != Some(&now_playing);
+        if song_changed {
+            events.push(Event::SongChanged {
+                song: state.now_playing.clone(),
+                duration,
+            });
+            self.now_playing = Some(now_playing);
+        }
+        let queue_changed = match &self.queue {
+            Some(queue) => queue.iter().ne(state.queue.iter().map(|s| &s.id)),
+            None => true,
+        };
+        if queue_changed {
+            events.push(Event::QueueChanged {
+                queue: state.queue.iter().cloned().collect(),
+            });
+            self.queue = Some(state.queue.iter().map(|s| s.id.clone()).collect());
+        }
+        if self.paused != Some(state.paused) {
+            events.push(match state.paused {
+                true => Event::Paused,
+                false => Event::Resumed,
+            });
+            self.paused = Some(state.paused);
+        }
+        if self.volume != Some(state.volume) {
+            events.push(Event::VolumeChanged {
+                volume: state.volume,
+            });
+            self.volume = Some(state.volume);
+        }
+        let elapsed = state.elapsed_duration.map(|d| d.as_secs());
+        if elapsed != self.elapsed || song_changed {
+            if let Some(e) = state.elapsed_duration {
+                events.push(Event::Position {
+                    elapsed: e.as_secs_f64(),
+                    duration,
+                });
+            }
+            self.elapsed = elapsed;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::*;
+    use crate::{downloader::Progress, downloads::JobState, format::Format};
+
+    fn song(id: &str) -> Song {
+        Song {
+            id: id.to_string(),
+            name: id.to_string(),
+            artist: None,
+            url: None,
+            path: PathBuf::from(format!("{}.mp3", id)),
+            format: Format::MP3,
+        }
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut state = PlayerState::new();
+        let mut tracker = Tracker::default();
+        // The first update describes everything
+        let names: Vec<&str> = tracker.update(&state).iter().map(Event::name).collect();
+        assert_eq!(
+            names,
+            ["song_changed", "queue_changed", "resumed", "volume_changed"]
+        );
+        assert!(tracker.update(&state).is_empty());
+
+        state.queue.push_back(song("a"));
+        state.paused = true;
+        let names: Vec<&str> = tracker.update(&state).iter().map(Event::name).collect();
+        assert_eq!(names, ["queue_changed", "paused"]);
+
+        state.now_playing = state.queue.pop_front();
+        state.elapsed_duration = Some(Duration::from_millis(10));
+        let names: Vec<&str> = tracker.update(&state).iter().map(Event::name).collect();
+        assert_eq!(names, ["song_changed", "queue_changed", "position"]);
+        // Positions are only sent once a second
+        state.elapsed_duration = Some(Duration::from_millis(900));
+        assert!(tracker.update(&state).is_empty());
+        state.elapsed_duration = Some(Duration::from_millis(1100));
+        assert!(matches!(
+            tracker.update(&state)[..],
+            [Event::Position { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_serialize() {
+        let event = Event::Download(Job {
+            id: 1,
+            url: "https://youtu.be/1".to_string(),
+            state: JobState::Failed,
+            progress: Progress::default(),
+            owner: None,
+            attempts: 1,
+            song: None,
+            error: Some("gone".to_string()),
+        });
+        let json = serde_json::to_value(event).unwrap();
+        assert_eq!(json["type"], "download");
+        assert_eq!(json["state"], "failed");
+        assert_eq!(json["error"], "gone");
+    }
+}
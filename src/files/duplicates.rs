@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::Configuration,
+    files::{list_all_songs, list_songs},
+    format::Format,
+    song::Song,
+    tags::{edit_tags, TagEdit},
+};
+
+/// Words that mark a bracketed part of a title as noise, like "(Official Music Video)"
+const TITLE_NOISE: &[&str] = &[
+    "official",
+    "video",
+    "audio",
+    "lyric",
+    "visualizer",
+    "remaster",
+    "hd",
+    "hq",
+    "4k",
+];
+
+/// The durations read so far and when their files were modified at the time
+static DURATIONS: Mutex<BTreeMap<PathBuf, (SystemTime, Option<Duration>)>> =
+    Mutex::new(BTreeMap::new());
+
+/// The reason why songs were grouped together
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum DuplicateReason {
+    /// The songs have been downloaded from the same url
+    Url,
+    /// The songs have the same title and artist
+    TitleArtist,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub reason: DuplicateReason,
+    pub songs: Vec<Song>,
+}
+
+impl DuplicateGroup {
+    /// Returns the copy that should be kept. Songs from `outer_paths` are preferred over the ones
+    /// we have downloaded ourselves.
+    pub fn preferred(&self, conf: &Configuration) -> &Song {
+        self.songs
+            .iter()
+            .find(|s| !s.path.starts_with(&conf.owned_path))
+            .unwrap_or(&self.songs[0])
+    }
+}
+
+/// How many seconds the durations of songs with the same title may differ unless the
+/// configuration says otherwise
+pub fn default_tolerance() -> Option<f32> {
+    Some(3.0)
+}
+
+/// Finds the duplicates among the songs currently shown in the library
+pub fn list_duplicates() -> Vec<DuplicateGroup> {
+    let conf = Configuration::get_conf();
+    find_duplicates(&list_songs(), tolerance(&conf))
+}
+
+/// Groups together songs that share the same url or the same normalized title and artist. Songs
+/// without an artist or a title are only grouped by url. If a tolerance is given, songs with
+/// matching titles also need to have durations within it, songs whose duration can't be read
+/// are matched by their title alone.
+pub fn find_duplicates(songs: &[Song], tolerance: Option<Duration>) -> Vec<DuplicateGroup> {
+    let mut parents: Vec<usize> = (0..songs.len()).collect();
+    let mut by_url: HashMap<String, usize> = HashMap::new();
+    let mut by_title: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    let mut durations: HashMap<usize, Option<Duration>> = HashMap::new();
+
+    for (i, song) in songs.iter().enumerate() {
+        if let Some(url) = &song.url {
+            match by_url.get(&normalize_url(url)) {
+                Some(j) => union(&mut parents, *j, i),
+                None => {
+                    by_url.insert(normalize_url(url), i);
+                }
+            }
+        }
+        let Some(key) = title_key(song) else {
+            continue;
+        };
+        let members = by_title.entry(key).or_default();
+        for j in members.iter() {
+            let matches = match tolerance {
+                None => true,
+                Some(t) => {
+                    let a = *durations.entry(i).or_insert_with(|| duration(song));
+                    let b = *durations.entry(*j).or_insert_with(|| duration(&songs[*j]));
+                    match (a, b) {
+                        (Some(a), Some(b)) => a.max(b) - a.min(b) <= t,
+                        _ => true,
+                    }
+                }
+            };
+            if matches {
+                union(&mut parents, *j, i);
+                break;
+            }
+        }
+        members.push(i);
+    }
+
+    let roots: Vec<usize> = (0..songs.len()).map(|i| find(&mut parents, i)).collect();
+    (0..songs.len())
+        .into_group_map_by(|i| roots[*i])
+        .into_values()
+        .filter(|g| g.len() > 1)
+        .sorted()
+        .map(|g| {
+            let urls: Vec<Option<String>> = g
+                .iter()
+                .map(|i| songs[*i].url.as_deref().map(normalize_url))
+                .collect();
+            let reason = match urls[0].is_some() && urls.iter().all_equal() {
+                true => DuplicateReason::Url,
+                false => DuplicateReason::TitleArtist,
+            };
+            DuplicateGroup {
+                reason,
+                songs: g.into_iter().map(|i| songs[i].clone()).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Leaves only the preferred copy of every group of duplicates in the list
+pub fn hide_duplicates(songs: Vec<Song>, conf: &Configuration) -> Vec<Song> {
+    let hidden: Vec<String> = find_duplicates(&songs, tolerance(conf))
+        .iter()
+        .flat_map(|g| {
+            let keep = g.preferred(conf).id.clone();
+            g.songs
+                .iter()
+                .filter(move |s| s.id != keep)
+                .map(|s| s.id.clone())
+        })
+        .collect();
+    songs
+        .into_iter()
+        .filter(|s| !hidden.contains(&s.id))
+        .collect()
+}
+
+/// Hides the songs with the given ids from the library
+pub fn hide(ids: &[String]) -> Result<(), String> {
+    let songs: Vec<Song> = list_all_songs()
+        .into_iter()
+        .filter(|s| ids.contains(&s.id))
+        .collect();
+    Configuration::update(|conf| {
+        for song in songs {
+            if !conf.hidden_paths.contains(&song.path) {
+                conf.hidden_paths.push(song.path)
+            }
+        }
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// Merges the duplicates of the song with the given id into it. Downloaded copies from the same
+/// url are removed and copies in `outer_paths` are hidden, as we do not want to delete files we
+/// do not own. Copies that only have the same title are hidden too, unless `delete_title_matches`
+/// confirms they may be removed. If the kept song has no url, the url of a duplicate is written
+/// into it so it will not be downloaded again. Returns the songs that were merged away.
+pub fn merge_into(id: &str, delete_title_matches: bool) -> Result<Vec<Song>, String> {
+    let conf = Configuration::get_conf();
+    let group = find_duplicates(&list_all_songs(), tolerance(&conf))
+        .into_iter()
+        .find(|g| g.songs.iter().any(|s| s.id == id))
+        .ok_or(format!("Song {} has no duplicates", id))?;
+    let (keep, rest): (Vec<Song>, Vec<Song>) = group.songs.into_iter().partition(|s| s.id == id);
+    if keep[0].url.is_none() {
+        if let Some(url) = rest.iter().find_map(|s| s.url.clone()) {
+            let edit = TagEdit {
+                url: Some(url),
+                ..TagEdit::default()
+            };
+            edit_tags(&keep[0], &edit)?;
+        }
+    }
+    let url = |s: &Song| s.url.as_deref().map(normalize_url);
+    let mut hidden = vec![];
+    for song in &rest {
+        let same_url = url(song).is_some() && url(song) == url(&keep[0]);
+        if song.path.starts_with(&conf.owned_path) && (same_url || delete_title_matches) {
+            fs::remove_file(&song.path).map_err(|e| e.to_string())?;
+        } else {
+            hidden.push(song.path.clone());
+        }
+    }
+    Configuration::update(|conf| {
+        for path in hidden {
+            if !conf.hidden_paths.contains(&path) {
+                conf.hidden_paths.push(path);
+            }
+        }
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(rest)
+}
+
+/// Finds a song in the library that has been downloaded from the given url
+pub fn find_by_url(url: &str) -> Option<Song> {
+    let url = normalize_url(url);
+    list_all_songs()
+        .into_iter()
+        .find(|s| s.url.as_deref().map(normalize_url) == Some(url.clone()))
+}
+
+/// Reduces the different forms of YouTube urls to the id of the video, other urls are only
+/// trimmed
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    let id = if let Some((_, rest)) = url.split_once("youtu.be/") {
+        rest.split(['?', '&', '#']).next()
+    } else if url.contains("youtube.com/") {
+        url.split(['?', '&', '#'])
+            .find_map(|p| p.strip_prefix("v="))
+    } else {
+        None
+    };
+    match id {
+        Some(id) if !id.is_empty() => format!("youtube:{}", id),
+        _ => url.trim_end_matches('/').to_string(),
+    }
+}
+
+/// Lowercases the title and removes punctuation and noise like "(Official Video)"
+pub fn normalize_title(title: &str) -> String {
+    let lower = title.to_lowercase();
+    let mut kept = String::new();
+    let mut rest = lower.as_str();
+    while let Some(start) = rest.find(['(', '[']) {
+        kept.push_str(&rest[..start]);
+        let close = if rest[start..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        match rest[start..].find(close) {
+            Some(end) => {
+                let inner = &rest[start + 1..start + end];
+                let noise = inner
+                    .split(|c: char| !c.is_alphanumeric())
+                    .any(|w| TITLE_NOISE.iter().any(|n| w.starts_with(n)));
+                if !noise {
+                    kept.push(' ');
+                    kept.push_str(inner);
+                }
+                rest = &rest[start + end + 1..];
+            }
+            None => rest = &rest[start + 1..],
+        }
+    }
+    kept.push_str(rest);
+    collapse(&kept)
+}
+
+/// Lowercases the artist and removes the suffixes YouTube adds to channel names
+pub fn normalize_artist(artist: &str) -> String {
+    let lower = artist.to_lowercase();
+    let trimmed = lower.trim();
+    let trimmed = trimmed.strip_suffix("- topic").unwrap_or(trimmed);
+    let trimmed = trimmed.strip_suffix("vevo").unwrap_or(trimmed);
+    collapse(trimmed)
+}
+
+/// Titles of videos often start with the artist, as in "Artist - Title", which is removed here.
+/// Songs missing either have no key, as too many unrelated songs would share it.
+fn title_key(song: &Song) -> Option<(String, String)> {
+    let artist = normalize_artist(song.artist.as_deref().unwrap_or(""));
+    let title = normalize_title(&song.name);
+    let title = match title.strip_prefix(&artist) {
+        Some(t) if !artist.is_empty() && t.starts_with(' ') => t.trim().to_string(),
+        _ => title,
+    };
+    match artist.is_empty() || title.is_empty() {
+        true => None,
+        false => Some((artist, title)),
+    }
+}
+
+fn collapse(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .join(" ")
+}
+
+/// Reading the duration of an MP3 file goes through all of its frames, so the durations are
+/// remembered until the file changes
+fn duration(song: &Song) -> Option<Duration> {
+    let modified = fs::metadata(&song.path).and_then(|m| m.modified()).ok()?;
+    if let Some((at, duration)) = DURATIONS.lock().unwrap().get(&song.path) {
+        if *at == modified {
+            return *duration;
+        }
+    }
+    let duration = match song.format {
+        Format::MP3 => mp3_duration::from_path(&song.path).ok(),
+        Format::MP4 => mp4ameta::Tag::read_from_path(&song.path).ok()?.duration(),
+        Format::UNSUPPORTED => None,
+    };
+    DURATIONS
+        .lock()
+        .unwrap()
+        .insert(song.path.clone(), (modified, duration));
+    duration
+}
+
+fn tolerance(conf: &Configuration) -> Option<Duration> {
+    conf.duplicate_duration_tolerance
+        .map(|t| Duration::from_secs_f32(t.max(0.0)))
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    parents[b] = a;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn song(name: &str, artist: Option<&str>, url: Option<&str>, path: &str) -> Song {
+        let path = PathBuf::from(path);
+        Song {
+            id: Song::gen_id(&path),
+            name: name.to_string(),
+            artist: artist.map(|a| a.to_string()),
+            url: url.map(|u| u.to_string()),
+            path,
+            format: Format::UNSUPPORTED,
+        }
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize_url("https://www.youtube.com/watch?v=Uk8sAsB25vk&t=10"),
+            "youtube:Uk8sAsB25vk"
+        );
+        assert_eq!(
+            normalize_url("https://youtu.be/Uk8sAsB25vk?si=abc"),
+            "youtube:Uk8sAsB25vk"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/song/"),
+            "https://example.com/song"
+        );
+    }
+
+    #[test]
+    fn test_normalize_title() {
+        assert_eq!(
+            normalize_title("Metallica: Lux Æterna (Official Music Video)"),
+            "metallica lux æterna"
+        );
+        assert_eq!(
+            normalize_title("Heilutaan (Eurobeat Remix)"),
+            "heilutaan eurobeat remix"
+        );
+        assert_eq!(normalize_artist("MetallicaVEVO"), "metallica");
+        assert_eq!(normalize_artist("Metallica - Topic"), "metallica");
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let songs = vec![
+            song(
+                "Lux Æterna",
+                Some("Metallica"),
+                None,
+                "/music/lux aeterna.mp3",
+            ),
+            song(
+                "Metallica: Lux Æterna (Official Music Video)",
+                Some("Metallica"),
+                Some("https://www.youtube.com/watch?v=Uk8sAsB25vk"),
+                "songs/a.mp3",
+            ),
+            song(
+                "Something else",
+                Some("Someone"),
+                Some("https://youtu.be/Uk8sAsB25vk"),
+                "songs/b.mp3",
+            ),
+            song("Heilutaan", None, None, "songs/c.mp3"),
+            song("Heilutaan", None, None, "songs/d.mp3"),
+            song("(Official Video)", Some("Someone"), None, "songs/e.mp3"),
+            song("[HD]", Some("Someone"), None, "songs/f.mp3"),
+        ];
+        let groups = find_duplicates(&songs, None);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].songs.len(), 3);
+        assert_eq!(groups[0].reason, DuplicateReason::TitleArtist);
+        // The durations of the files can't be read, so the titles decide
+        let groups = find_duplicates(&songs, Some(Duration::from_secs(3)));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].songs.len(), 3);
+    }
+
+    #[test]
+    fn test_other_formats() {
+        // The copies can't be read, like an M4A file whose duration isn't known
+        let songs = vec![
+            Song {
+                format: Format::MP4,
+                ..song("Heilutaan", Some("Gasellit"), None, "/music/heilutaan.m4a")
+            },
+            Song {
+                format: Format::MP3,
+                ..song("Heilutaan", Some("Gasellit"), None, "songs/heilutaan.mp3")
+            },
+        ];
+        let groups = find_duplicates(&songs, default_tolerance().map(Duration::from_secs_f32));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].reason, DuplicateReason::TitleArtist);
+    }
+}
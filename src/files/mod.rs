@@ -5,8 +5,26 @@ use crate::conf::Configuration;
 use crate::format::{Format, Formattable};
 use crate::song::Song;
 
-// PERF: make this async
+pub mod duplicates;
+pub mod playlists;
+
+/// Lists the songs in the library, leaving out hidden songs and, when `hide_duplicates` is set,
+/// the redundant copies of duplicates.
 pub fn list_songs() -> Vec<Song> {
+    let conf = Configuration::get_conf();
+    let songs: Vec<Song> = list_all_songs()
+        .into_iter()
+        .filter(|s| !conf.hidden_paths.contains(&s.path))
+        .collect();
+    if conf.hide_duplicates {
+        return duplicates::hide_duplicates(songs, &conf);
+    }
+    songs
+}
+
+// PERF: make this async
+/// Lists every song found from the configured folders
+pub fn list_all_songs() -> Vec<Song> {
     let mut song_list: Vec<Song> = Vec::new();
     let conf = Configuration::get_conf();
     let owned_path = conf.owned_path;
@@ -0,0 +1,66 @@
+use std::{fs, path::PathBuf};
+
+use crate::{conf::Configuration, song::Song};
+
+/// The folder inside the owned path where playlists are saved
+const PLAYLIST_FOLDER: &str = "playlists";
+
+/// Saves the songs as an M3U playlist with the name and returns where it was saved. A playlist
+/// with the same name is replaced.
+pub fn save(name: &str, songs: &[Song]) -> Result<PathBuf, String> {
+    check_name(name)?;
+    let folder = Configuration::get_conf().owned_path.join(PLAYLIST_FOLDER);
+    fs::create_dir_all(&folder).map_err(|e| e.to_string())?;
+    let path = folder.join(format!("{}.m3u", name.trim()));
+    fs::write(&path, to_m3u(songs)).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+/// Names can't be empty or point outside the playlist folder
+pub fn check_name(name: &str) -> Result<(), String> {
+    let name = name.trim();
+    match name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        true => Err(format!("{} isn't a valid name for a playlist", name)),
+        false => Ok(()),
+    }
+}
+
+/// Lists the songs with absolute paths, so the playlist can be moved around
+fn to_m3u(songs: &[Song]) -> String {
+    let mut m3u = String::from("#EXTM3U\n");
+    for song in songs {
+        let title = match &song.artist {
+            Some(artist) => format!("{} - {}", artist, song.name),
+            None => song.name.clone(),
+        };
+        let path = fs::canonicalize(&song.path).unwrap_or(song.path.clone());
+        m3u += &format!("#EXTINF:-1,{}\n{}\n", title, path.display());
+    }
+    m3u
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+
+    #[test]
+    fn test_to_m3u() {
+        let song = |name: &str, artist: Option<&str>| Song {
+            id: name.to_string(),
+            name: name.to_string(),
+            artist: artist.map(str::to_string),
+            url: None,
+            path: PathBuf::from(format!("/music/{}.mp3", name)),
+            format: Format::MP3,
+        };
+        let m3u = to_m3u(&[song("a", Some("b")), song("c", None)]);
+        assert_eq!(
+            m3u,
+            "#EXTM3U\n#EXTINF:-1,b - a\n/music/a.mp3\n#EXTINF:-1,c\n/music/c.mp3\n"
+        );
+        assert!(check_name("../a").is_err());
+        assert!(check_name(" ").is_err());
+        assert!(check_name("Road trip").is_ok());
+    }
+}
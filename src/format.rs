@@ -26,6 +26,13 @@ impl Format {
             Format::UNSUPPORTED => None,
         }
     }
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Format::MP3 => "audio/mpeg",
+            Format::MP4 => "audio/mp4",
+            Format::UNSUPPORTED => "application/octet-stream",
+        }
+    }
 }
 
 pub(crate) trait Formattable {
@@ -0,0 +1,514 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    f64::consts::PI,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use id3::{frame::ExtendedText, Tag, TagLike};
+use mp4ameta::{Data, FreeformIdent};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::Configuration, effects::biquad::Biquad, files::list_all_songs, format::Format,
+    song::Song, tags::read_tags,
+};
+
+/// The loudness ReplayGain 2.0 normalizes to, in LUFS
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+const TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+const TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+const ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+const ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+
+/// Which of the stored gains the player applies
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum ReplayGainMode {
+    #[default]
+    Track,
+    Album,
+    Off,
+}
+
+impl FromStr for ReplayGainMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "track" => Ok(ReplayGainMode::Track),
+            "album" => Ok(ReplayGainMode::Album),
+            "off" => Ok(ReplayGainMode::Off),
+            _ => Err(format!("Unknown replay gain mode {}", s)),
+        }
+    }
+}
+
+/// The ReplayGain values of a song, gains are in dB and peaks relative to full scale
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Reads the values from the tags of the song
+    pub fn read(song: &Song) -> ReplayGain {
+        match song.format {
+            Format::MP3 => match Tag::read_from_path(&song.path) {
+                Ok(tag) => Self::from_fields(|field| {
+                    tag.extended_texts()
+                        .find(|t| t.description.eq_ignore_ascii_case(field))
+                        .map(|t| t.value.clone())
+                }),
+                Err(_) => ReplayGain::default(),
+            },
+            Format::MP4 => match mp4ameta::Tag::read_from_path(&song.path) {
+                Ok(tag) => Self::from_fields(|field| {
+                    tag.strings_of(&mp4_ident(field))
+                        .next()
+                        .map(|s| s.to_string())
+                }),
+                Err(_) => ReplayGain::default(),
+            },
+            Format::UNSUPPORTED => ReplayGain::default(),
+        }
+    }
+
+    /// Writes the values that are set into the tags of the song
+    pub fn write(&self, song: &Song) -> Result<(), String> {
+        let fields = self.to_fields();
+        match song.format {
+            Format::MP3 => {
+                let mut tag = Tag::read_from_path(&song.path).unwrap_or(Tag::new());
+                // Players that only read older tags keep reading the file
+                let version = tag.version();
+                for (field, value) in fields {
+                    tag.remove_extended_text(Some(field), None);
+                    tag.add_frame(ExtendedText {
+                        description: field.to_string(),
+                        value,
+                    });
+                }
+                tag.write_to_path(&song.path, version)
+                    .map_err(|e| e.to_string())
+            }
+            Format::MP4 => {
+                let mut tag =
+                    mp4ameta::Tag::read_from_path(&song.path).map_err(|e| e.to_string())?;
+                for (field, value) in fields {
+                    tag.set_data(mp4_ident(field), Data::Utf8(value));
+                }
+                tag.write_to_path(&song.path).map_err(|e| e.to_string())
+            }
+            Format::UNSUPPORTED => Err("Unsupported format".to_string()),
+        }
+    }
+
+    /// Returns the factor the volume should be multiplied with. The gain is limited so that the
+    /// peak does not go over full scale.
+    pub fn factor(&self, mode: ReplayGainMode) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Track => (self.track_gain, self.track_peak),
+            ReplayGainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+            ReplayGainMode::Off => return 1.0,
+        };
+        let factor = 10f32.powf(gain.unwrap_or(0.0) / 20.0);
+        match peak {
+            Some(p) if p > 0.0 => factor.min(1.0 / p),
+            _ => factor,
+        }
+    }
+
+    fn from_fields(get: impl Fn(&str) -> Option<String>) -> ReplayGain {
+        let parse = |field| {
+            get(field)?
+                .trim()
+                .trim_end_matches("dB")
+                .trim()
+                .parse::<f32>()
+                .ok()
+        };
+        ReplayGain {
+            track_gain: parse(TRACK_GAIN),
+            track_peak: parse(TRACK_PEAK),
+            album_gain: parse(ALBUM_GAIN),
+            album_peak: parse(ALBUM_PEAK),
+        }
+    }
+
+    fn to_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![];
+        if let Some(g) = self.track_gain {
+            fields.push((TRACK_GAIN, format!("{:.2} dB", g)));
+        }
+        if let Some(p) = self.track_peak {
+            fields.push((TRACK_PEAK, format!("{:.6}", p)));
+        }
+        if let Some(g) = self.album_gain {
+            fields.push((ALBUM_GAIN, format!("{:.2} dB", g)));
+        }
+        if let Some(p) = self.album_peak {
+            fields.push((ALBUM_PEAK, format!("{:.6}", p)));
+        }
+        fields
+    }
+}
+
+fn mp4_ident(field: &str) -> FreeformIdent<'_> {
+    FreeformIdent::new("com.apple.iTunes", field)
+}
+
+/// Measures the integrated loudness of audio as specified in EBU R128 / ITU-R BS.1770
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<KWeighting>,
+    /// Samples per channel in a 100 ms sub-block
+    sub_len: usize,
+    sub_pos: usize,
+    sub_energy: f64,
+    /// The mean square energies of the latest sub-blocks, 4 of them make up a 400 ms block
+    subs: VecDeque<f64>,
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: u16, rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        LoudnessMeter {
+            channels,
+            filters: (0..channels).map(|_| KWeighting::new(rate)).collect(),
+            sub_len: (rate as usize / 10).max(1),
+            sub_pos: 0,
+            sub_energy: 0.0,
+            subs: VecDeque::with_capacity(4),
+            blocks: vec![],
+            peak: 0.0,
+        }
+    }
+
+    /// Adds interleaved samples to the measurement
+    pub fn add_frames(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (ch, sample) in frame.iter().enumerate() {
+                self.peak = self.peak.max(sample.abs());
+                let y = self.filters[ch].process(*sample as f64);
+                self.sub_energy += channel_weight(ch, self.channels) * y * y;
+            }
+            self.sub_pos += 1;
+            if self.sub_pos == self.sub_len {
+                self.subs.push_back(self.sub_energy / self.sub_len as f64);
+                self.sub_pos = 0;
+                self.sub_energy = 0.0;
+                if self.subs.len() == 4 {
+                    self.blocks.push(self.subs.iter().sum::<f64>() / 4.0);
+                    self.subs.pop_front();
+                }
+            }
+        }
+    }
+
+    /// The gated integrated loudness in LUFS, `None` if there was nothing loud enough
+    pub fn loudness(&self) -> Option<f64> {
+        gated_loudness(&self.blocks)
+    }
+
+    /// The largest absolute sample value
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    /// The integrated loudness of multiple measurements as if they were one
+    pub fn loudness_multiple(meters: &[LoudnessMeter]) -> Option<f64> {
+        let blocks: Vec<f64> = meters.iter().flat_map(|m| m.blocks.clone()).collect();
+        gated_loudness(&blocks)
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let above_absolute: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|e| energy_to_loudness(*e) > -70.0)
+        .collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+    let mean = above_absolute.iter().sum::<f64>() / above_absolute.len() as f64;
+    let relative = energy_to_loudness(mean) - 10.0;
+    let gated: Vec<f64> = above_absolute
+        .into_iter()
+        .filter(|e| energy_to_loudness(*e) > relative)
+        .collect();
+    Some(energy_to_loudness(
+        gated.iter().sum::<f64>() / gated.len() as f64,
+    ))
+}
+
+/// Surround channels are weighted more and the LFE channel is left out, following 5.1 order
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// The K-weighting pre-filter, a high shelf followed by a high pass, with the coefficients
+/// calculated for the sample rate
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(rate: u32) -> Self {
+        let rate = rate as f64;
+
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// Decodes the whole song and measures it
+pub fn measure(song: &Song) -> Result<LoudnessMeter, String> {
+    let source = song.create_source().map_err(|e| e.to_string())?;
+    let mut meter = LoudnessMeter::new(source.channels(), source.sample_rate());
+    let buffer_len = 4096 * source.channels().max(1) as usize;
+    let mut buffer = Vec::with_capacity(buffer_len);
+    for sample in source.convert_samples::<f32>() {
+        buffer.push(sample);
+        if buffer.len() == buffer_len {
+            meter.add_frames(&buffer);
+            buffer.clear();
+        }
+    }
+    meter.add_frames(&buffer);
+    Ok(meter)
+}
+
+/// Measures the song and writes its track gain, keeping the album gain it may already have
+pub fn analyze_track(song: &Song) -> Result<ReplayGain, String> {
+    let meter = measure(song)?;
+    let loudness = meter.loudness().ok_or("The song is silent")?;
+    let gain = ReplayGain {
+        track_gain: Some((REFERENCE_LOUDNESS - loudness) as f32),
+        track_peak: Some(meter.peak()),
+        ..ReplayGain::read(song)
+    };
+    gain.write(song)?;
+    Ok(gain)
+}
+
+/// Measures the songs of an album and writes both the track and album gains into them. A song
+/// that can't be decoded or written doesn't stop the rest, the album gain is measured from the
+/// songs that could be decoded. Returns the paths of the songs that failed.
+pub fn analyze_album(songs: &[Song]) -> Result<Vec<PathBuf>, String> {
+    let mut failed = vec![];
+    let (mut measured, mut meters) = (vec![], vec![]);
+    for song in songs {
+        match measure(song) {
+            Ok(meter) => {
+                measured.push(song);
+                meters.push(meter);
+            }
+            Err(e) => {
+                println!("Failed to analyze {}: {}", song.name, e);
+                failed.push(song.path.clone());
+            }
+        }
+    }
+    let album_loudness = LoudnessMeter::loudness_multiple(&meters).ok_or("The album is silent")?;
+    let album_peak = meters.iter().map(|m| m.peak()).fold(0.0, f32::max);
+    for (song, meter) in measured.into_iter().zip(meters) {
+        let gain = ReplayGain {
+            track_gain: meter.loudness().map(|l| (REFERENCE_LOUDNESS - l) as f32),
+            track_peak: Some(meter.peak()),
+            album_gain: Some((REFERENCE_LOUDNESS - album_loudness) as f32),
+            album_peak: Some(album_peak),
+        };
+        if let Err(e) = gain.write(song) {
+            println!("Failed to write the gains of {}: {}", song.name, e);
+            failed.push(song.path.clone());
+        }
+    }
+    Ok(failed)
+}
+
+/// Analyzes the songs in the library that are missing their gains. Only the songs in the owned
+/// folder are analyzed, unless the configuration allows writing to the outer folders as well.
+/// Songs with an album tag are analyzed together with the rest of the album in the same folder.
+/// Songs that fail are remembered in the configuration and not tried again, so an album with a
+/// broken file isn't decoded on every scan.
+pub fn scan_library() {
+    let conf = Configuration::get_conf();
+    let mut failed = vec![];
+    let mut albums: HashMap<(PathBuf, String), Vec<Song>> = HashMap::new();
+    for song in list_all_songs() {
+        if song.format == Format::UNSUPPORTED
+            || conf.unanalyzable_paths.contains(&song.path)
+            || !(conf.scan_outer_paths || song.path.starts_with(&conf.owned_path))
+        {
+            continue;
+        }
+        match read_tags(&song).album.filter(|a| !a.is_empty()) {
+            Some(album) => {
+                let folder = song
+                    .path
+                    .parent()
+                    .map(|p| p.to_path_buf())
+                    .unwrap_or_default();
+                albums.entry((folder, album)).or_default().push(song);
+            }
+            None => {
+                if ReplayGain::read(&song).track_gain.is_none() {
+                    if let Err(e) = analyze_track(&song) {
+                        println!("Failed to analyze {}: {}", song.name, e);
+                        failed.push(song.path.clone());
+                    }
+                }
+            }
+        }
+    }
+    for ((_, album), songs) in albums {
+        if songs
+            .iter()
+            .any(|s| ReplayGain::read(s).album_gain.is_none())
+        {
+            match analyze_album(&songs) {
+                Ok(f) => failed.extend(f),
+                Err(e) => {
+                    println!("Failed to analyze album {}: {}", album, e);
+                    failed.extend(songs.into_iter().map(|s| s.path));
+                }
+            }
+        }
+    }
+    if !failed.is_empty() {
+        if let Err(e) = Configuration::update(|c| c.unanalyzable_paths.extend(failed)) {
+            println!("Failed to save the songs that couldn't be analyzed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32, rate: u32, seconds: u32) -> Vec<f32> {
+        (0..rate * seconds)
+            .map(|i| {
+                amplitude * (2.0 * std::f32::consts::PI * 997.0 * i as f32 / rate as f32).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sine_loudness() {
+        // A 997 Hz sine at -20 dBFS in one channel should measure at -23 LUFS
+        let mut meter = LoudnessMeter::new(1, 48000);
+        meter.add_frames(&sine(0.1, 48000, 5));
+        assert!((meter.loudness().unwrap() + 23.01).abs() < 0.1);
+        let mut meter = LoudnessMeter::new(1, 44100);
+        meter.add_frames(&sine(0.1, 44100, 5));
+        assert!((meter.loudness().unwrap() + 23.01).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.add_frames(&vec![0.0; 48000 * 2]);
+        assert_eq!(meter.loudness(), None);
+    }
+
+    #[test]
+    fn test_gain_factor() {
+        let gain = ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.5),
+            album_gain: Some(6.0),
+            album_peak: Some(0.9),
+        };
+        assert!((gain.factor(ReplayGainMode::Track) - 0.501).abs() < 0.01);
+        assert!((gain.factor(ReplayGainMode::Album) - 1.0 / 0.9).abs() < 0.01);
+        assert_eq!(gain.factor(ReplayGainMode::Off), 1.0);
+    }
+
+    #[test]
+    fn test_write_keeps_version() {
+        let path = std::env::temp_dir().join(format!("ssmp-gain-{}.mp3", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+        Tag::new()
+            .write_to_path(&path, id3::Version::Id3v23)
+            .unwrap();
+        let song = Song {
+            id: String::new(),
+            name: "gain".to_string(),
+            artist: None,
+            url: None,
+            path: path.clone(),
+            format: Format::MP3,
+        };
+        let gain = ReplayGain {
+            track_gain: Some(-3.5),
+            ..ReplayGain::default()
+        };
+        gain.write(&song).unwrap();
+        let tag = Tag::read_from_path(&path).unwrap();
+        let read = ReplayGain::read(&song);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tag.version(), id3::Version::Id3v23);
+        assert_eq!(read, gain);
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let gain = ReplayGain::from_fields(|f| match f {
+            TRACK_GAIN => Some("-3.21 dB".to_string()),
+            TRACK_PEAK => Some("0.988".to_string()),
+            _ => None,
+        });
+        assert_eq!(gain.track_gain, Some(-3.21));
+        assert_eq!(gain.track_peak, Some(0.988));
+        assert_eq!(gain.album_gain, None);
+    }
+}
@@ -1,13 +1,21 @@
+pub mod broadcast;
 pub mod commands;
 pub mod conf;
 pub mod console;
+pub mod crossfade;
 pub mod downloader;
+pub mod downloads;
+pub mod effects;
+pub mod events;
 pub mod files;
 pub mod format;
+pub mod loudness;
+pub mod output;
 mod player;
 pub mod player_state;
 pub mod remote;
 pub mod song;
+pub mod tags;
 pub mod ui;
 pub mod youtube;
 
@@ -18,16 +26,24 @@ use relm4::component::{
 };
 use relm4::factory::{FactoryVecDeque, FactoryVecDequeGuard};
 use relm4::gtk::EntryIconPosition;
-use relm4::{prelude::*, AsyncComponentSender};
+use relm4::{prelude::*, AsyncComponentSender, Controller, WorkerController};
 use song::Song;
+use ui::invite::InviteView;
+use ui::tag_editor::{TagEditor, TagEditorMessage};
 use ui::youtube_browser::{YoutubeBrowser, YtMessage};
 
 use std::convert::identity;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::*;
 
+use crate::conf::Configuration;
+use crate::files::duplicates::list_duplicates;
 use crate::files::list_songs;
+use crate::loudness::ReplayGainMode;
 use crate::player_state::PlayerState;
+use crate::remote::RemoteHandler;
+use crate::ui::duplicates::DuplicateGroupView;
 use crate::ui::song_selecter::SongFile;
 
 use self::commands::PlayerMessage;
@@ -35,6 +51,10 @@ use self::commands::PlayerMessage;
 const APP_ID: &str = "jere.ssmp";
 
 fn main() {
+    if let Err(e) = Configuration::init() {
+        println!("Could not read the conf file: {}", e);
+        process::exit(1);
+    }
     relm4::RELM_THREADS.set(4).unwrap();
     let app = RelmApp::new(APP_ID);
     app.run_async::<AppModel>(PlayerState::new());
@@ -42,10 +62,16 @@ fn main() {
 
 struct AppModel {
     status: PlayerState,
+    player_handler: Rc<WorkerController<Player>>,
     song_files_factory: FactoryVecDeque<SongFile>,
     song_list: Vec<Song>,
+    duplicates_factory: FactoryVecDeque<DuplicateGroupView>,
     youtube_searcher: AsyncController<YoutubeBrowser>,
+    tag_editor: Controller<TagEditor>,
+    invite: Controller<InviteView>,
     current_search: String,
+    /// What the remote answers with, kept up to date with the player
+    remote_state: Arc<Mutex<PlayerState>>,
 }
 
 #[derive(Debug)]
@@ -53,6 +79,8 @@ pub enum MainMessage {
     StateUpdated(PlayerState),
     SearchChanged(String),
     FilesChanged,
+    Player(PlayerMessage),
+    EditTags(Song),
 }
 
 #[relm4::component(async)]
@@ -170,6 +198,19 @@ impl AsyncComponent for AppModel {
                         sender.input(MainMessage::SearchChanged(buffer.text().into()))
                     }
                 },
+                model.tag_editor.widget(),
+                gtk::Expander{
+                    set_label: Some("Invite a guest"),
+                    model.invite.widget(),
+                },
+                gtk::Expander{
+                    set_label: Some("Duplicates"),
+                    #[local_ref]
+                    duplicate_box -> gtk::Box{
+                        set_orientation: gtk::Orientation::Vertical,
+                        set_spacing: 5,
+                    },
+                },
                 gtk::Box{
                     set_orientation: gtk::Orientation::Horizontal,
                     set_spacing: 5,
@@ -207,25 +248,76 @@ impl AsyncComponent for AppModel {
                 .forward(sender.input_sender(), identity),
         );
 
+        // The remote sends its messages to the player through the app
+        let (remote_sender, mut remote_receiver) = tokio::sync::mpsc::channel(100);
+        let input = sender.input_sender().clone();
+        relm4::spawn(async move {
+            while let Some(message) = remote_receiver.recv().await {
+                input.emit(MainMessage::Player(message));
+            }
+        });
+        let remote_state = Arc::new(Mutex::new(status.clone()));
+        let mut remote = RemoteHandler::new(remote_sender.clone(), remote_state.clone());
+        // The listeners are started on the runtime of relm4, as they need tokio. The remote lets
+        // other devices control the player, so it only starts by itself if it's asked to.
+        let remote = relm4::spawn(async move {
+            let conf = Configuration::get_conf();
+            if !conf.remote_autostart {
+                return remote;
+            }
+            for address in conf.ip {
+                let name = address.address().to_string();
+                match remote.new_listener(address).await {
+                    Ok(_) => println!("Successfully started remote on {}", name),
+                    Err(e) => println!("Failed to start remote on {} because {}", name, e),
+                }
+            }
+            remote
+        })
+        .await
+        .expect("Starting the remote not to panic");
+
+        let conf = Configuration::get_conf();
+        if conf.scan_loudness && conf.replay_gain != ReplayGainMode::Off {
+            thread::spawn(loudness::scan_library);
+        }
+
         let mut song_files_factory =
-            FactoryVecDeque::<SongFile>::new(gtk::Box::default(), player_handler.sender());
+            FactoryVecDeque::<SongFile>::new(gtk::Box::default(), sender.input_sender());
         let mut g = song_files_factory.guard();
         let song_list = list_songs();
         song_list.iter().for_each(|x| {
             g.push_back(x.clone());
         });
         g.drop();
+        let mut duplicates_factory =
+            FactoryVecDeque::<DuplicateGroupView>::new(gtk::Box::default(), sender.input_sender());
+        insert_into_factory(list_duplicates().into_iter(), &mut duplicates_factory.guard());
         let youtube_searcher = YoutubeBrowser::builder()
             .launch(())
             .forward(sender.input_sender(), identity);
+        let tag_editor = TagEditor::builder()
+            .launch(())
+            .forward(sender.input_sender(), identity);
+        let invite = InviteView::builder()
+            .launch(remote.keys().clone())
+            .detach();
+        // The remote is handed to the console, which can start and stop it and manage the keys
+        relm4::spawn(console::run(remote_sender, remote_state.clone(), remote));
         let model = AppModel {
             status,
+            player_handler: player_handler.clone(),
             song_files_factory,
             song_list,
+            duplicates_factory,
             youtube_searcher,
+            tag_editor,
+            invite,
             current_search: "".to_string(),
+            remote_state,
         };
         let song_box = model.song_files_factory.widget();
+        let duplicate_box = model.duplicates_factory.widget();
         let widgets = view_output!();
         AsyncComponentParts { model, widgets }
     }
@@ -250,8 +342,16 @@ impl AsyncComponent for AppModel {
                         .filter(|x| x.matches_name(&self.current_search)),
                     &mut g,
                 );
+                let mut g = self.duplicates_factory.guard();
+                g.clear();
+                insert_into_factory(list_duplicates().into_iter(), &mut g);
+            }
+            MainMessage::StateUpdated(s) => {
+                *self.remote_state.lock().unwrap() = s.clone();
+                self.status = s
             }
-            MainMessage::StateUpdated(s) => self.status = s,
+            MainMessage::Player(m) => self.player_handler.emit(m),
+            MainMessage::EditTags(s) => self.tag_editor.emit(TagEditorMessage::Open(s)),
             MainMessage::SearchChanged(s) => {
                 self.current_search = s.clone();
                 let mut g = self.song_files_factory.guard();
@@ -0,0 +1,301 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rodio::{
+    cpal::{self, traits::HostTrait},
+    queue::SourcesQueueOutput,
+    source::UniformSourceIterator,
+    DeviceTrait, OutputStream, Sink, Source,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::broadcast::Tap;
+
+/// How long a playing sink may go without asking for samples before the device is treated as lost
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// The format everything is converted to before it is played, recorded or streamed
+pub(crate) const CHANNELS: u16 = 2;
+pub(crate) const SAMPLE_RATE: u32 = 44100;
+/// How much audio the null and WAV backends consume at once
+const CHUNK_LENGTH: Duration = Duration::from_millis(10);
+
+/// Where the player sends its audio
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub enum Backend {
+    /// The configured output device
+    #[default]
+    Device,
+    /// Throws the samples away, consuming them `speed` times faster than real time
+    Null { speed: f32 },
+    /// Records everything that is played to a WAV file, consuming the samples `speed` times
+    /// faster than real time
+    Wav { path: PathBuf, speed: f32 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OutputDevice {
+    pub name: String,
+    pub default: bool,
+}
+
+/// Lists the audio output devices of the default host
+pub fn list_devices() -> Vec<OutputDevice> {
+    let host = cpal::default_host();
+    let default = host.default_output_device().and_then(|d| d.name().ok());
+    match host.output_devices() {
+        Ok(devices) => devices
+            .filter_map(|d| d.name().ok())
+            .map(|name| OutputDevice {
+                default: Some(&name) == default.as_ref(),
+                name,
+            })
+            .collect(),
+        Err(e) => {
+            println!("Failed to list output devices: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// What keeps the sink playing
+enum Backing {
+    Device {
+        _stream: OutputStream,
+    },
+    /// No device could be opened, the queue is kept so the sink isn't closed
+    Idle {
+        _queue: SourcesQueueOutput<f32>,
+    },
+    /// A thread consumes the samples until the flag is set
+    Thread {
+        stop: Arc<AtomicBool>,
+        handle: Option<JoinHandle<()>>,
+    },
+}
+
+/// The sink songs are played through, together with what keeps it playing
+pub struct Output {
+    pub sink: Sink,
+    backing: Backing,
+    /// Counts how often the device has asked for samples of the tracked sources
+    progress: Arc<AtomicUsize>,
+    last_progress: (usize, Instant),
+}
+
+impl Output {
+    /// Opens the device with the given name, or the default device if there is no name
+    pub fn open(name: Option<&str>) -> Result<Self, String> {
+        let (stream, handle) = match name {
+            Some(name) => {
+                let device = cpal::default_host()
+                    .output_devices()
+                    .map_err(|e| e.to_string())?
+                    .find(|d| d.name().map(|n| n == name).unwrap_or(false))
+                    .ok_or(format!("No output device named {}", name))?;
+                OutputStream::try_from_device(&device).map_err(|e| e.to_string())?
+            }
+            None => OutputStream::try_default().map_err(|e| e.to_string())?,
+        };
+        let (sink, queue) = Sink::new_idle();
+        handle
+            .play_raw(Tap::new(UniformSourceIterator::<_, f32>::new(
+                queue,
+                CHANNELS,
+                SAMPLE_RATE,
+            )))
+            .map_err(|e| e.to_string())?;
+        Ok(Self::new(sink, Backing::Device { _stream: stream }))
+    }
+
+    /// Opens the device with the given name, falling back to the default device and finally to a
+    /// sink that plays nothing, so the player keeps working without any device
+    pub fn open_or_fallback(name: Option<&str>) -> Self {
+        if let Some(name) = name {
+            match Self::open(Some(name)) {
+                Ok(output) => return output,
+                Err(e) => println!("Failed to open {}, using the default device: {}", name, e),
+            }
+        }
+        match Self::open(None) {
+            Ok(output) => output,
+            Err(e) => {
+                println!("Failed to open an output device: {}", e);
+                let (sink, idle) = Sink::new_idle();
+                Self::new(sink, Backing::Idle { _queue: idle })
+            }
+        }
+    }
+
+    /// Opens the output of the backend, `device` is the name of the device the `Device` backend
+    /// uses
+    pub fn start(backend: &Backend, device: Option<&str>) -> Self {
+        match backend {
+            Backend::Device => Self::open_or_fallback(device),
+            Backend::Null { speed } => Self::consume(*speed, None),
+            Backend::Wav { path, speed } => match create_wav(path) {
+                Ok(writer) => Self::consume(*speed, Some(writer)),
+                Err(e) => {
+                    println!("Failed to create {}: {}", path.to_string_lossy(), e);
+                    Self::open_or_fallback(device)
+                }
+            },
+        }
+    }
+
+    /// Creates a sink that is played by a thread instead of a device, writing the samples to
+    /// the WAV writer if there is one
+    fn consume(speed: f32, mut writer: Option<WavWriter<BufWriter<File>>>) -> Self {
+        let (sink, queue) = Sink::new_idle();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let chunk = CHUNK_LENGTH.as_secs_f32() * SAMPLE_RATE as f32 * CHANNELS as f32;
+        let tick = CHUNK_LENGTH.div_f32(speed.max(0.01));
+        let handle = thread::spawn(move || {
+            let mut source = Tap::new(UniformSourceIterator::<_, f32>::new(
+                queue,
+                CHANNELS,
+                SAMPLE_RATE,
+            ));
+            let mut next = Instant::now();
+            let mut chunks: u32 = 0;
+            while !stopped.load(SeqCst) {
+                for _ in 0..chunk as usize {
+                    let sample = source.next().unwrap_or(0.0);
+                    if let Some(w) = writer.as_mut() {
+                        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                        if let Err(e) = w.write_sample(sample) {
+                            println!("Failed to write the recording: {}", e);
+                            writer = None;
+                        }
+                    }
+                }
+                chunks = chunks.wrapping_add(1);
+                // Keep the header up to date so the file is readable if the player crashes
+                if chunks % 100 == 0 {
+                    if let Some(Err(e)) = writer.as_mut().map(|w| w.flush()) {
+                        println!("Failed to write the recording: {}", e);
+                    }
+                }
+                next += tick;
+                if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            if let Some(Err(e)) = writer.map(|w| w.finalize()) {
+                println!("Failed to finish the recording: {}", e);
+            }
+        });
+        let handle = Some(handle);
+        Self::new(sink, Backing::Thread { stop, handle })
+    }
+
+    fn new(sink: Sink, backing: Backing) -> Self {
+        Output {
+            sink,
+            backing,
+            progress: Arc::new(AtomicUsize::new(0)),
+            last_progress: (0, Instant::now()),
+        }
+    }
+
+    /// Wraps a source so the output can tell whether the device is still playing it
+    pub fn track<S>(&self, source: S) -> impl Source<Item = f32> + Send + 'static
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let progress = self.progress.clone();
+        source.periodic_access(Duration::from_millis(100), move |_| {
+            progress.fetch_add(1, SeqCst);
+        })
+    }
+
+    /// Returns false if there is no device or the device has stopped asking for samples while a
+    /// song is playing, which happens when it is unplugged or the sound server restarts
+    pub fn is_alive(&mut self) -> bool {
+        match self.backing {
+            Backing::Device { .. } => {}
+            Backing::Idle { .. } => return false,
+            Backing::Thread { .. } => return true,
+        }
+        let progress = self.progress.load(SeqCst);
+        if progress != self.last_progress.0 || self.sink.is_paused() || self.sink.empty() {
+            self.last_progress = (progress, Instant::now());
+            return true;
+        }
+        self.last_progress.1.elapsed() < STALL_TIMEOUT
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Backing::Thread { stop, handle } = &mut self.backing {
+            stop.store(true, SeqCst);
+            // Wait for the recording to be finished
+            if let Some(handle) = handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+fn create_wav(path: &Path) -> Result<WavWriter<BufWriter<File>>, hound::Error> {
+    let spec = WavSpec {
+        channels: CHANNELS,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    WavWriter::create(path, spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, thread};
+
+    use hound::WavReader;
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn test_wav_backend_records() {
+        let path = env::temp_dir().join("ssmp-test-recording.wav");
+        let mut output = Output::start(
+            &Backend::Wav {
+                path: path.clone(),
+                speed: 50.0,
+            },
+            None,
+        );
+        assert!(output.is_alive());
+        output
+            .sink
+            .append(SamplesBuffer::new(2, SAMPLE_RATE, vec![0.5f32; 8820]));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !output.sink.empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(output.sink.empty());
+        drop(output);
+
+        let mut reader = WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, CHANNELS);
+        assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+        let loud = reader
+            .samples::<i16>()
+            .filter(|s| s.as_ref().map(|s| *s > i16::MAX / 3).unwrap_or(false))
+            .count();
+        assert!((8800..=8820).contains(&loud), "{}", loud);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,15 +1,29 @@
 use relm4::Worker;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use rodio::{OutputStream, Sink, Source};
+use rodio::decoder::DecoderError;
+use rodio::{Sink, Source};
 use tokio::time::Instant;
 
 use crate::commands::PlayerMessage;
+use crate::conf::Configuration;
+use crate::crossfade::{Crossfade, FadeHandle, SongSource};
+use crate::effects::stretch::TimeStretch;
+use crate::effects::{Effects, EffectsSource};
+use crate::events::{self, Tracker};
+use crate::loudness::ReplayGain;
+use crate::output::{Backend, Output};
 use crate::player_state::PlayerState;
+use crate::song::Song;
 use crate::MainMessage;
 
+/// How long to wait before the first attempt to get a lost device back
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
 pub(crate) struct Player {
     sender: Sender<PlayerMessage>,
 }
@@ -24,119 +38,499 @@ impl Worker for Player {
     fn init(_init: Self::Init, sender: relm4::ComponentSender<Self>) -> Self {
         let (ps, pr) = channel();
         thread::spawn(move || {
-            let mut state = PlayerState::new();
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let sink = Sink::try_new(&stream_handle).unwrap();
-            sink.set_volume(state.volume);
-            let mut t = Instant::now();
-            loop {
-                // Add the next song to the queue if the queue is empty
-                if sink.empty() && !state.queue.is_empty() {
-                    let song = state.queue.pop_front();
-                    if let Some(song) = song {
-                        let source = song.create_source();
-                        match source {
-                            Ok(source) => {
-                                state.total_duration = mp3_duration::from_path(&song.path).ok();
-                                state.now_playing = Some(song);
-                                sink.append(source);
-                                t = Instant::now();
-                            }
-                            Err(e) => println!("Error reached when appending: {:#?}", e),
-                        }
+            run(pr, Configuration::get_conf().backend, |state| {
+                sender
+                    .output(MainMessage::StateUpdated(state.clone()))
+                    .expect("For the application to be running")
+            })
+        });
+        Self { sender: ps }
+    }
+
+    fn update(&mut self, message: Self::Input, _sender: relm4::ComponentSender<Self>) {
+        self.sender.send(message).unwrap();
+    }
+}
+
+/// Runs the player until the sender of the messages is dropped, reporting the state after every
+/// step
+pub(crate) fn run<F>(messages: Receiver<PlayerMessage>, backend: Backend, mut report: F)
+where
+    F: FnMut(&PlayerState),
+{
+    let mut state = PlayerState::new();
+    let mut output = Output::start(&backend, state.output_device.as_deref());
+    output.sink.set_volume(state.volume);
+    // The ReplayGain factor of the current song
+    let mut gain = 1.0;
+    // The effects shared with the source that is playing
+    let effects = Arc::new(Mutex::new(state.effects.clone()));
+    // The tempo the stretcher plays at, the rest of the speed comes from the sink
+    let tempo = Arc::new(Mutex::new(1.0));
+    // Hands the next song to the song that is playing
+    let mut fade = FadeHandle::default();
+    apply_speed(&output.sink, &tempo, &state);
+    let mut t = Instant::now();
+    // When the next attempt to get the device back is made and how long to wait after it
+    let mut retry: Option<(Instant, Duration)> = None;
+    // What the listeners of the events were last told
+    let mut tracker = Tracker::default();
+    loop {
+        if !output.is_alive() && retry.is_none() {
+            let error = "Lost the audio output device".to_string();
+            println!("{}", error);
+            state.error = Some(error);
+            retry = Some((Instant::now(), MIN_RETRY_DELAY));
+        }
+        if let Some((at, delay)) = retry {
+            if Instant::now() >= at {
+                match Output::open(state.output_device.as_deref()).or_else(|_| Output::open(None)) {
+                    Ok(new) => {
+                        let paused = output.sink.is_paused();
+                        output = new;
+                        t = restore(&output, &state, paused, gain, &tempo, &effects, &mut fade);
+                        state.error = None;
+                        retry = None;
                     }
-                } else if sink.empty() && state.queue.is_empty() {
-                    state.now_playing = None;
-                    state.elapsed_duration = None;
-                    state.total_duration = None;
-                }
-                if state.now_playing.is_some() && !sink.is_paused() {
-                    state.elapsed_duration = Some(t.elapsed().mul_f32(sink.speed()));
-                }
-
-                // Handle a message if one is recieved
-                let message_or_error = pr.try_recv();
-                if let Ok(message) = message_or_error {
-                    match message {
-                        PlayerMessage::Stop => {
-                            state.queue.clear();
-                            sink.stop();
-                        }
-                        PlayerMessage::Pause => sink.pause(),
-                        PlayerMessage::Play => {
-                            sink.play();
-                            t = Instant::now()
-                                .checked_sub(
-                                    state.elapsed_duration.unwrap_or(Duration::from_secs(0)),
-                                )
-                                .unwrap();
-                        }
-                        PlayerMessage::Volume(v) => {
-                            sink.set_volume(v);
-                            state.volume = v;
+                    Err(e) => {
+                        state.error = Some(format!("No audio output device: {}", e));
+                        retry = Some((Instant::now() + delay, (delay * 2).min(MAX_RETRY_DELAY)));
+                    }
+                }
+            }
+        }
+
+        // Add the next song to the queue if the queue is empty
+        if output.sink.empty() && !state.queue.is_empty() {
+            let song = state.queue.pop_front();
+            if let Some(song) = song {
+                match append_song(&output, &song, Duration::ZERO, &tempo, &effects) {
+                    Ok((handle, length)) => {
+                        fade = handle;
+                        state.total_duration = mp3_duration::from_path(&song.path).ok().or(length);
+                        gain = ReplayGain::read(&song).factor(state.replay_gain);
+                        output.sink.set_volume(state.volume * gain);
+                        state.now_playing = Some(song);
+                        t = Instant::now();
+                        if retry.is_none() {
+                            state.error = None;
                         }
-                        PlayerMessage::Skip(list) => {
-                            let mut sorted = list.clone();
-                            sorted.sort_by(|a, b| b.cmp(a));
-                            for index in sorted.as_ref() {
-                                match index {
-                                    0 => sink.stop(),
-                                    _ => {
-                                        state.queue.remove(*index - 1);
-                                    }
-                                }
+                    }
+                    Err(e) => {
+                        println!("Error reached when appending: {:#?}", e);
+                        state.error = Some(format!("Failed to play {}: {}", song.name, e));
+                    }
+                }
+            }
+        } else if output.sink.empty() && state.queue.is_empty() {
+            state.now_playing = None;
+            state.elapsed_duration = None;
+            state.total_duration = None;
+        }
+        state.paused = output.sink.is_paused();
+        if state.now_playing.is_some() && !output.sink.is_paused() && retry.is_none() {
+            state.elapsed_duration = Some(t.elapsed().mul_f32(state.speed));
+        }
+
+        // Start fading over to the next song once the current one is about to end
+        let remaining = state
+            .total_duration
+            .zip(state.elapsed_duration)
+            .map(|(total, elapsed)| total.saturating_sub(elapsed));
+        let crossfade = Duration::from_secs_f32(state.crossfade);
+        if state.crossfade > 0.0
+            && remaining.is_some_and(|r| r <= crossfade)
+            && !state.queue.is_empty()
+            && !fade.is_fading()
+            && !output.sink.is_paused()
+            && retry.is_none()
+        {
+            let song = state.queue.pop_front().unwrap();
+            match song_source(&song, Duration::ZERO, &tempo, &effects) {
+                Ok((source, length)) => {
+                    let next_gain = ReplayGain::read(&song).factor(state.replay_gain);
+                    // The fade is played after the stretcher, where the song goes by at the tempo
+                    let fade_length = remaining.unwrap().div_f32(*tempo.lock().unwrap());
+                    fade.fade_to(source, gain / next_gain, fade_length);
+                    gain = next_gain;
+                    output.sink.set_volume(state.volume * gain);
+                    state.total_duration = mp3_duration::from_path(&song.path).ok().or(length);
+                    state.elapsed_duration = Some(Duration::ZERO);
+                    state.now_playing = Some(song);
+                    t = Instant::now();
+                }
+                Err(e) => {
+                    println!("Error reached when fading over: {:#?}", e);
+                    state.error = Some(format!("Failed to play {}: {}", song.name, e));
+                }
+            }
+        }
+
+        // Handle a message if one is recieved
+        let message_or_error = messages.try_recv();
+        if let Err(TryRecvError::Disconnected) = message_or_error {
+            break;
+        }
+        if let Ok(message) = message_or_error {
+            match message {
+                PlayerMessage::Stop => {
+                    state.queue.clear();
+                    output.sink.stop();
+                }
+                PlayerMessage::Pause => output.sink.pause(),
+                PlayerMessage::Play => {
+                    output.sink.play();
+                    t = Instant::now()
+                        .checked_sub(
+                            state
+                                .elapsed_duration
+                                .unwrap_or(Duration::from_secs(0))
+                                .div_f32(state.speed),
+                        )
+                        .unwrap();
+                }
+                PlayerMessage::Volume(v) => {
+                    output.sink.set_volume(v * gain);
+                    state.volume = v;
+                }
+                PlayerMessage::Skip(list) => {
+                    let mut sorted = list.clone();
+                    sorted.sort_by(|a, b| b.cmp(a));
+                    for index in sorted.as_ref() {
+                        match index {
+                            0 => output.sink.stop(),
+                            _ => {
+                                state.queue.remove(*index - 1);
                             }
                         }
-                        PlayerMessage::Add(s) => {
-                            state.queue.push_back(s);
-                        }
-                        PlayerMessage::Clear => state.queue.clear(),
-                        PlayerMessage::Speed(s) => {
-                            t = Instant::now()
-                                .checked_sub(
-                                    state
-                                        .elapsed_duration
-                                        .unwrap_or(Duration::new(0, 0).mul_f32(s)),
-                                )
-                                .unwrap();
-                            sink.set_speed(s);
+                    }
+                }
+                PlayerMessage::Add(s) => {
+                    state.queue.push_back(s);
+                }
+                PlayerMessage::Clear => state.queue.clear(),
+                PlayerMessage::Speed(s) => {
+                    let s = PlayerMessage::clamp_speed(s);
+                    t = Instant::now()
+                        .checked_sub(
+                            state
+                                .elapsed_duration
+                                .unwrap_or(Duration::new(0, 0))
+                                .div_f32(s),
+                        )
+                        .unwrap();
+                    state.speed = s;
+                    apply_speed(&output.sink, &tempo, &state);
+                }
+                PlayerMessage::Pitch(p) => {
+                    state.pitch = PlayerMessage::clamp_pitch(p);
+                    apply_speed(&output.sink, &tempo, &state);
+                }
+                PlayerMessage::ReOrder(origin, mut dest) => {
+                    let elem = state.queue.remove(origin);
+                    if let Some(song) = elem {
+                        if dest >= origin {
+                            dest -= 1;
                         }
-                        PlayerMessage::ReOrder(origin, mut dest) => {
-                            let elem = state.queue.remove(origin);
-                            if let Some(song) = elem {
-                                if dest >= origin {
-                                    dest -= 1;
-                                }
-                                state.queue.insert(dest.min(state.queue.len()), song)
+                        state.queue.insert(dest.min(state.queue.len()), song)
+                    }
+                }
+                PlayerMessage::ReplayGain(mode) => {
+                    state.replay_gain = mode;
+                    if let Some(song) = &state.now_playing {
+                        gain = ReplayGain::read(song).factor(mode);
+                    }
+                    output.sink.set_volume(state.volume * gain);
+                    if let Err(e) = Configuration::update(|c| c.replay_gain = mode) {
+                        println!("Failed to save the replay gain mode: {}", e);
+                    }
+                }
+                PlayerMessage::Equalizer(bands) => {
+                    state.effects.equalizer = bands;
+                    update_effects(&effects, &state.effects);
+                }
+                PlayerMessage::EqualizerPreset(preset) => {
+                    state.effects.equalizer = preset.bands();
+                    update_effects(&effects, &state.effects);
+                }
+                PlayerMessage::BassBoost(b) => {
+                    state.effects.bass_boost = b;
+                    update_effects(&effects, &state.effects);
+                }
+                PlayerMessage::Mono(m) => {
+                    state.effects.mono = m;
+                    update_effects(&effects, &state.effects);
+                }
+                PlayerMessage::Limiter(l) => {
+                    state.effects.limiter = l;
+                    update_effects(&effects, &state.effects);
+                }
+                PlayerMessage::Seek(n) => {
+                    // FIX: This is a terrible way to implement seeking and is only used
+                    // because I am yet to find a better one.
+                    output.sink.stop();
+                    if let Some(song) = &state.now_playing {
+                        let dur = Duration::from_secs(n);
+                        match append_song(&output, song, dur, &tempo, &effects) {
+                            Ok((handle, _)) => {
+                                fade = handle;
+                                t = Instant::now()
+                                    .checked_sub(dur.div_f32(state.speed))
+                                    .unwrap();
                             }
+                            Err(e) => println!("Failed seek because {:?}", e),
                         }
-                        PlayerMessage::Seek(n) => {
-                            // FIX: This is a terrible way to implement seeking and is only used
-                            // because I am yet to find a better one.
-                            sink.stop();
-                            if let Some(song) = &state.now_playing {
-                                match song.create_source() {
-                                    Ok(s) => {
-                                        let dur = Duration::from_secs(n);
-                                        sink.append(s.skip_duration(dur));
-                                        t = Instant::now().checked_sub(dur).unwrap();
-                                    }
-                                    Err(e) => println!("Failed seek because {:?}", e),
-                                }
-                            }
+                    }
+                }
+                PlayerMessage::OutputDevice(name) => match Output::open(name.as_deref()) {
+                    Ok(new) => {
+                        let paused = output.sink.is_paused();
+                        output = new;
+                        t = restore(&output, &state, paused, gain, &tempo, &effects, &mut fade);
+                        state.error = None;
+                        retry = None;
+                        state.output_device = name;
+                        let device = state.output_device.clone();
+                        if let Err(e) = Configuration::update(|c| c.output_device = device) {
+                            println!("Failed to save the output device: {}", e);
                         }
                     }
+                    Err(e) => println!("Failed to switch the output device: {}", e),
+                },
+                PlayerMessage::Crossfade(c) => {
+                    state.crossfade = PlayerMessage::clamp_crossfade(c);
+                    let crossfade = state.crossfade;
+                    if let Err(e) = Configuration::update(|c| c.crossfade = crossfade) {
+                        println!("Failed to save the crossfade: {}", e);
+                    }
                 }
-                sender
-                    .output(MainMessage::StateUpdated(state.clone()))
-                    .expect("For the application to be running");
-                thread::sleep(Duration::from_millis(1));
             }
+        }
+        report(&state);
+        for event in tracker.update(&state) {
+            events::publish(event);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// The sink resamples to shift the pitch, which also speeds the song up, so the stretcher
+/// makes up the difference to reach the requested speed
+fn apply_speed(sink: &Sink, tempo: &Arc<Mutex<f32>>, state: &PlayerState) {
+    let ratio = 2f32.powf(state.pitch / 12.0);
+    sink.set_speed(ratio);
+    *tempo.lock().unwrap() = state.speed / ratio;
+}
+
+/// Hands the new effects to the playing source and saves them so they are used on the next start
+fn update_effects(shared: &Arc<Mutex<Effects>>, effects: &Effects) {
+    *shared.lock().unwrap() = effects.clone();
+    if let Err(e) = Configuration::update(|c| c.effects = effects.clone()) {
+        println!("Failed to save the effects: {}", e);
+    }
+}
+
+/// Decodes the song through the effects, starting `start` into the song. Returns the length of
+/// the song too, if the decoder knows it.
+fn song_source(
+    song: &Song,
+    start: Duration,
+    tempo: &Arc<Mutex<f32>>,
+    effects: &Arc<Mutex<Effects>>,
+) -> Result<(SongSource, Option<Duration>), DecoderError> {
+    let source = song.create_source()?;
+    let length = source.total_duration();
+    let source = EffectsSource::new(
+        TimeStretch::new(source.skip_duration(start).convert_samples(), tempo.clone()),
+        effects.clone(),
+    );
+    Ok((Box::new(source), length))
+}
+
+/// Appends the song to the sink, starting `start` into the song. Returns the handle that fades it
+/// over to the next song and the length of the song, if the decoder knows it.
+fn append_song(
+    output: &Output,
+    song: &Song,
+    start: Duration,
+    tempo: &Arc<Mutex<f32>>,
+    effects: &Arc<Mutex<Effects>>,
+) -> Result<(FadeHandle, Option<Duration>), DecoderError> {
+    let (source, length) = song_source(song, start, tempo, effects)?;
+    let (crossfade, handle) = Crossfade::new(source);
+    output.sink.append(output.track(crossfade));
+    Ok((handle, length))
+}
+
+/// Sets up a new output like the one it replaces and continues the current song where the old
+/// one left off, replacing the handle of the fades. Returns the instant the song would have
+/// started playing at.
+fn restore(
+    output: &Output,
+    state: &PlayerState,
+    paused: bool,
+    gain: f32,
+    tempo: &Arc<Mutex<f32>>,
+    effects: &Arc<Mutex<Effects>>,
+    fade: &mut FadeHandle,
+) -> Instant {
+    output.sink.set_volume(state.volume * gain);
+    apply_speed(&output.sink, tempo, state);
+    if paused {
+        output.sink.pause();
+    }
+    let elapsed = state.elapsed_duration.unwrap_or_default();
+    if let Some(song) = &state.now_playing {
+        match append_song(output, song, elapsed, tempo, effects) {
+            Ok((handle, _)) => *fade = handle,
+            Err(e) => println!("Failed to resume the song: {:?}", e),
+        }
+    }
+    Instant::now()
+        .checked_sub(elapsed.div_f32(state.speed))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, f32::consts::PI, sync::mpsc::channel, thread, time::Duration};
+
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::format::Format;
+
+    /// Writes a sine of the given length to a WAV file and returns it as a song
+    fn sine_song(name: &str, secs: f32) -> Song {
+        let path = env::temp_dir().join(format!("ssmp-test-{}.wav", name));
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..(secs * 8000.0) as usize {
+            let s = (2.0 * PI * 440.0 * i as f32 / 8000.0).sin();
+            writer.write_sample((s * 8000.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        Song {
+            id: Song::gen_id(&path),
+            name: name.to_string(),
+            artist: None,
+            url: None,
+            path,
+            format: Format::UNSUPPORTED,
+        }
+    }
+
+    /// Runs the player on a fast null backend until `done` returns true for a reported state.
+    /// Returns every state that was reported.
+    fn run_until<F>(messages: Vec<PlayerMessage>, done: F) -> Vec<PlayerState>
+    where
+        F: FnMut(&PlayerState) -> bool,
+    {
+        run_at(20.0, messages, done)
+    }
+
+    /// Like `run_until`, with the null backend playing at `speed`
+    fn run_at<F>(speed: f32, messages: Vec<PlayerMessage>, mut done: F) -> Vec<PlayerState>
+    where
+        F: FnMut(&PlayerState) -> bool,
+    {
+        let (ps, pr) = channel();
+        let (ss, sr) = channel();
+        let player = thread::spawn(move || {
+            run(pr, Backend::Null { speed }, |state| {
+                let _ = ss.send(state.clone());
+            })
         });
-        Self { sender: ps }
+        for message in messages {
+            ps.send(message).unwrap();
+        }
+        let mut states = vec![];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok(state) = sr.recv_timeout(Duration::from_secs(1)) {
+                let finished = done(&state);
+                states.push(state);
+                if finished {
+                    break;
+                }
+            }
+        }
+        drop(ps);
+        player.join().unwrap();
+        states
     }
 
-    fn update(&mut self, message: Self::Input, _sender: relm4::ComponentSender<Self>) {
-        self.sender.send(message).unwrap();
+    fn playing(state: &PlayerState) -> Option<&str> {
+        state.now_playing.as_ref().map(|s| s.name.as_str())
+    }
+
+    #[test]
+    fn test_queue_advances() {
+        let mut started = false;
+        let states = run_until(
+            vec![
+                PlayerMessage::Add(sine_song("first", 0.5)),
+                PlayerMessage::Add(sine_song("second", 0.5)),
+            ],
+            |s| {
+                started |= s.now_playing.is_some();
+                started && s.now_playing.is_none() && s.queue.is_empty()
+            },
+        );
+        let played: Vec<&str> = states.iter().filter_map(playing).dedup().collect();
+        assert_eq!(played, vec!["first", "second"]);
+        assert!(playing(states.last().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_seek() {
+        let states = run_until(
+            vec![
+                PlayerMessage::Add(sine_song("seek", 4.0)),
+                PlayerMessage::Seek(3),
+            ],
+            |s| {
+                s.elapsed_duration
+                    .is_some_and(|d| d >= Duration::from_secs(3))
+            },
+        );
+        let last = states.last().unwrap();
+        assert_eq!(playing(last), Some("seek"));
+        assert!(last.elapsed_duration.unwrap() >= Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_crossfade() {
+        // The elapsed time is measured on the clock, so the null backend has to play in real time
+        let mut started = false;
+        let states = run_at(
+            1.0,
+            vec![
+                PlayerMessage::Crossfade(0.3),
+                PlayerMessage::Add(sine_song("fade-first", 0.6)),
+                PlayerMessage::Add(sine_song("fade-second", 0.6)),
+            ],
+            |s| {
+                started |= s.now_playing.is_some();
+                started && s.now_playing.is_none() && s.queue.is_empty()
+            },
+        );
+        let played: Vec<&str> = states.iter().filter_map(playing).dedup().collect();
+        assert_eq!(played, vec!["fade-first", "fade-second"]);
+        // The second song starts before the first one has finished
+        let last_of_first = states
+            .iter()
+            .filter(|s| playing(s) == Some("fade-first"))
+            .filter_map(|s| s.elapsed_duration)
+            .max()
+            .unwrap();
+        assert!(last_of_first < Duration::from_millis(500));
+        assert!(playing(states.last().unwrap()).is_none());
     }
 }
@@ -2,7 +2,7 @@ use std::{collections::VecDeque, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{conf::Configuration, song::Song};
+use crate::{conf::Configuration, effects::Effects, loudness::ReplayGainMode, song::Song};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PlayerState {
@@ -10,20 +10,37 @@ pub struct PlayerState {
     pub queue: VecDeque<Song>,
     pub volume: f32,
     pub speed: f32,
+    /// The pitch shift in semitones
+    pub pitch: f32,
     pub paused: bool,
+    pub replay_gain: ReplayGainMode,
+    pub effects: Effects,
+    pub output_device: Option<String>,
+    /// How many seconds the end of a song overlaps with the next one, 0 plays them one after
+    /// the other
+    #[serde(default)]
+    pub crossfade: f32,
+    /// Set while the player can't play, like when the output device is lost
+    pub error: Option<String>,
     pub total_duration: Option<Duration>,
     pub elapsed_duration: Option<Duration>,
 }
 
 impl PlayerState {
     pub fn new() -> Self {
-        let vol = Configuration::get_conf().default_volume;
+        let conf = Configuration::get_conf();
         Self {
             now_playing: None,
             queue: VecDeque::new(),
-            volume: vol,
+            volume: conf.default_volume,
             speed: 1.0,
+            pitch: 0.0,
             paused: false,
+            replay_gain: conf.replay_gain,
+            effects: conf.effects,
+            output_device: conf.output_device,
+            crossfade: conf.crossfade,
+            error: None,
             total_duration: None,
             elapsed_duration: None,
         }
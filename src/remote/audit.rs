@@ -0,0 +1,73 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{Method, StatusCode};
+use serde::Serialize;
+
+/// How many accesses are remembered
+const AUDIT_LENGTH: usize = 1000;
+
+static AUDIT: Mutex<VecDeque<AuditEntry>> = Mutex::new(VecDeque::new());
+
+/// A request to the remote and how it was answered
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct AuditEntry {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// The label of the key, `None` if no known key was sent
+    pub key: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+}
+
+/// Remembers which key accessed what, denied requests are printed as well
+pub(super) fn record(key: Option<&str>, method: &Method, path: &str, status: StatusCode) {
+    let entry = AuditEntry {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        key: key.map(str::to_string),
+        method: method.to_string(),
+        path: path.to_string(),
+        status: status.as_u16(),
+    };
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        println!(
+            "Denied {} {} for key {}",
+            entry.method,
+            entry.path,
+            entry.key.as_deref().unwrap_or("none")
+        );
+    }
+    let mut audit = AUDIT.lock().unwrap();
+    if audit.len() >= AUDIT_LENGTH {
+        audit.pop_front();
+    }
+    audit.push_back(entry);
+}
+
+/// The remembered accesses, oldest first
+pub(super) fn entries() -> Vec<AuditEntry> {
+    AUDIT.lock().unwrap().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        record(Some("guest"), &Method::GET, "/test/list", StatusCode::OK);
+        record(None, &Method::POST, "/test/play", StatusCode::UNAUTHORIZED);
+        let entries = entries();
+        let list = entries.iter().find(|e| e.path == "/test/list").unwrap();
+        assert_eq!((list.key.as_deref(), list.status), (Some("guest"), 200));
+        let play = entries.iter().find(|e| e.path == "/test/play").unwrap();
+        assert_eq!((play.key.as_ref(), play.status), (None, 401));
+    }
+}
@@ -1,6 +1,15 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::conf::Configuration;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum Permission {
     VolumeControl((f32, f32)),
     Seek,
@@ -8,35 +17,414 @@ pub enum Permission {
     Download,
     PlayPause,
     Info,
+    /// Allows changing the library, like merging duplicate songs
+    Library,
+    /// Allows changing the equalizer and other effects
+    Effects,
     All,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A key of the remote. Only a salted hash of the key is kept, so the configuration doesn't give
+/// the keys away.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Key {
-    pub key: String,
+    /// A name to tell the keys apart, no two keys have the same label
+    #[serde(default)]
+    pub label: String,
+    /// The key in plain text, only read from old configurations and replaced by the hash
+    #[serde(default, skip_serializing)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub salt: String,
+    /// Hex encoded SHA-256 of the salt followed by the key
+    #[serde(default)]
+    pub hash: String,
     pub permissions: Vec<Permission>,
+    /// Seconds since the unix epoch after which the key no longer works
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// How many more requests the key can be used for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uses_left: Option<u64>,
 }
 
 impl Default for Key {
     fn default() -> Self {
+        Key::new(
+            "default",
+            "",
+            vec![Permission::Add, Permission::Download, Permission::Info],
+        )
+    }
+}
+
+impl Key {
+    /// Creates a key that is used by sending `key`, which isn't stored
+    pub fn new(label: &str, key: &str, permissions: Vec<Permission>) -> Self {
+        let salt = random_hex(16);
         Key {
-            key: "".to_owned(),
-            permissions: vec![Permission::Add, Permission::Download, Permission::Info],
+            label: label.to_string(),
+            key: None,
+            hash: hash(&salt, key),
+            salt,
+            permissions,
+            expires: None,
+            uses_left: None,
         }
     }
+
+    pub fn matches(&self, key: &str) -> bool {
+        constant_time_eq(&hash(&self.salt, key), &self.hash)
+    }
+
+    /// Whether the key has neither expired nor been used up
+    pub fn is_usable(&self) -> bool {
+        self.expires.is_none_or(|e| now() < e) && self.uses_left != Some(0)
+    }
 }
 
-impl Key {
-    pub fn convert_all(&mut self) {
-        if self.permissions.contains(&Permission::All) {
-            self.permissions = vec![
-                Permission::VolumeControl((0.0, 10.0)),
-                Permission::Seek,
-                Permission::All,
-                Permission::Download,
-                Permission::PlayPause,
-                Permission::Info,
-            ]
+/// Replaces the plain text keys of an old configuration with hashes, giving keys without a label
+/// one. Returns whether anything was changed.
+pub fn hash_plain_keys(keys: &mut [Key]) -> bool {
+    let mut changed = false;
+    for (i, k) in keys.iter_mut().enumerate() {
+        if let Some(plain) = k.key.take() {
+            k.salt = random_hex(16);
+            k.hash = hash(&k.salt, &plain);
+            changed = true;
+        }
+        if k.label.is_empty() {
+            k.label = format!("key{}", i + 1);
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn hash(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(key.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compares every byte so the time taken doesn't tell how much of a hash or signature matched
+pub(super) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+pub(super) fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// What is shown about a key, everything but its hash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    pub label: String,
+    pub permissions: Vec<Permission>,
+    pub expires: Option<u64>,
+    pub uses_left: Option<u64>,
+}
+
+/// How long counted uses may stay unsaved before they are written to the configuration
+const USES_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The keys of the remote, kept in memory so the configuration isn't read on every request.
+/// Changes are written back to the configuration, counted uses only every
+/// `USES_SAVE_INTERVAL` and when the store is dropped.
+pub struct KeyStore {
+    keys: Mutex<Vec<Key>>,
+    persistent: bool,
+    /// When a use was first counted without being saved
+    unsaved_since: Mutex<Option<Instant>>,
+}
+
+impl KeyStore {
+    /// The keys of the configuration
+    pub fn load() -> Self {
+        let mut keys = Configuration::get_conf().keys;
+        hash_plain_keys(&mut keys);
+        KeyStore {
+            keys: Mutex::new(keys),
+            persistent: true,
+            unsaved_since: Mutex::new(None),
+        }
+    }
+
+    /// Keys that are only kept in memory
+    pub fn new(keys: Vec<Key>) -> Self {
+        KeyStore {
+            keys: Mutex::new(keys),
+            persistent: false,
+            unsaved_since: Mutex::new(None),
+        }
+    }
+
+    /// Returns the key that was sent if it exists and is usable, counting the use
+    pub fn authenticate(&self, key: &str) -> Option<Key> {
+        let mut keys = self.keys.lock().unwrap();
+        let k = keys.iter_mut().find(|k| k.matches(key))?;
+        if !k.is_usable() {
+            return None;
+        }
+        let found = k.clone();
+        if let Some(uses) = k.uses_left.as_mut() {
+            *uses -= 1;
+            let mut unsaved = self.unsaved_since.lock().unwrap();
+            let since = *unsaved.get_or_insert_with(Instant::now);
+            if since.elapsed() >= USES_SAVE_INTERVAL {
+                if let Err(e) = self.save(&keys) {
+                    println!("Failed to save the keys: {}", e);
+                }
+                *unsaved = None;
+            }
+        }
+        Some(found)
+    }
+
+    /// Writes the uses counted since the last save
+    pub fn flush(&self) -> Result<(), String> {
+        let keys = self.keys.lock().unwrap();
+        let mut unsaved = self.unsaved_since.lock().unwrap();
+        if unsaved.is_some() {
+            self.save(&keys)?;
+            *unsaved = None;
+        }
+        Ok(())
+    }
+
+    /// Creates a key and returns it, the key can't be seen again afterwards. Keys that have
+    /// expired or been used up are removed.
+    pub fn add(
+        &self,
+        label: &str,
+        permissions: Vec<Permission>,
+        expires_in: Option<Duration>,
+        uses: Option<u64>,
+    ) -> Result<String, KeyError> {
+        if label.is_empty() {
+            return Err(KeyError::InvalidLabel);
+        }
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|k| k.is_usable());
+        if keys.iter().any(|k| k.label == label) {
+            return Err(KeyError::LabelTaken);
         }
+        let plain: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        let mut key = Key::new(label, &plain, permissions);
+        key.expires = expires_in.map(|d| now().saturating_add(d.as_secs()));
+        key.uses_left = uses;
+        keys.push(key);
+        self.save(&keys).map_err(KeyError::Save)?;
+        Ok(plain)
+    }
+
+    pub fn remove(&self, label: &str) -> Result<(), KeyError> {
+        let mut keys = self.keys.lock().unwrap();
+        let len = keys.len();
+        keys.retain(|k| k.label != label);
+        if keys.len() == len {
+            return Err(KeyError::NotFound);
+        }
+        self.save(&keys).map_err(KeyError::Save)
+    }
+
+    /// Whether the key with the label still exists and is usable, without counting a use
+    pub fn is_active(&self, label: &str) -> bool {
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|k| k.label == label && k.is_usable())
+    }
+
+    pub fn list(&self) -> Vec<KeyInfo> {
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|k| KeyInfo {
+                label: k.label.clone(),
+                permissions: k.permissions.clone(),
+                expires: k.expires,
+                uses_left: k.uses_left,
+            })
+            .collect()
+    }
+
+    fn save(&self, keys: &[Key]) -> Result<(), String> {
+        if !self.persistent {
+            return Ok(());
+        }
+        Configuration::update(|c| c.keys = keys.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for KeyStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Failed to save the keys: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyError {
+    InvalidLabel,
+    LabelTaken,
+    NotFound,
+    /// The key would never work or work for longer than allowed
+    InvalidExpiry,
+    Save(String),
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::InvalidLabel => write!(f, "A key needs a label"),
+            KeyError::LabelTaken => write!(f, "There already is a key with that label"),
+            KeyError::NotFound => write!(f, "There is no key with that label"),
+            KeyError::InvalidExpiry => write!(f, "That is not a valid time for a key to work"),
+            KeyError::Save(e) => write!(f, "Failed to save the keys: {}", e),
+        }
+    }
+}
+
+/// The volume range of a key with `All`
+const FULL_VOLUME_RANGE: (f32, f32) = (0.0, 10.0);
+
+impl Permission {
+    /// Whether holding this permission allows what `required` stands for. `All` allows
+    /// everything and any volume range allows changing the volume, the range itself is checked
+    /// against the volume that is asked for.
+    pub fn grants(&self, required: &Permission) -> bool {
+        match (self, required) {
+            (Permission::All, _) => true,
+            (Permission::VolumeControl(_), Permission::VolumeControl(_)) => true,
+            (held, required) => held == required,
+        }
+    }
+}
+
+/// Whether the held permissions include every required permission
+pub fn has_permissions(held: &[Permission], required: &[Permission]) -> bool {
+    required.iter().all(|r| held.iter().any(|h| h.grants(r)))
+}
+
+/// The range the volume may be set to, `None` if the permissions don't allow changing it
+pub fn volume_range(held: &[Permission]) -> Option<(f32, f32)> {
+    if held.contains(&Permission::All) {
+        return Some(FULL_VOLUME_RANGE);
+    }
+    held.iter().find_map(|p| match p {
+        Permission::VolumeControl(range) => Some(*range),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_permissions() {
+        let held = [Permission::Info, Permission::Seek];
+        assert!(has_permissions(&held, &[Permission::Info]));
+        assert!(has_permissions(
+            &held,
+            &[Permission::Info, Permission::Seek]
+        ));
+        assert!(has_permissions(&held, &[]));
+        assert!(!has_permissions(
+            &held,
+            &[Permission::Info, Permission::Add]
+        ));
+        assert!(!has_permissions(&[], &[Permission::Info]));
+        assert!(has_permissions(
+            &[Permission::All],
+            &[Permission::Library, Permission::Effects]
+        ));
+        assert!(has_permissions(
+            &[Permission::VolumeControl((0.0, 1.0))],
+            &[Permission::VolumeControl((0.0, 10.0))]
+        ));
+    }
+
+    #[test]
+    fn test_hashed_keys() {
+        let key = Key::new("party", "secret", vec![Permission::Info]);
+        assert!(key.matches("secret"));
+        assert!(!key.matches("secreT"));
+        assert!(!serde_json::to_string(&key).unwrap().contains("secret"));
+        // Keys with the same text have different hashes
+        assert_ne!(key.hash, Key::new("b", "secret", vec![]).hash);
+
+        let mut keys: Vec<Key> =
+            serde_json::from_str(r#"[{"key": "music1sBest", "permissions": ["All"]}]"#).unwrap();
+        assert!(hash_plain_keys(&mut keys));
+        assert!(keys[0].matches("music1sBest"));
+        assert_eq!(
+            (keys[0].key.as_ref(), keys[0].label.as_str()),
+            (None, "key1")
+        );
+        assert!(!hash_plain_keys(&mut keys));
+    }
+
+    #[test]
+    fn test_key_store() {
+        let store = KeyStore::new(vec![]);
+        let guest = store
+            .add("guest", vec![Permission::Add], None, Some(2))
+            .unwrap();
+        assert_eq!(
+            store.add("guest", vec![], None, None),
+            Err(KeyError::LabelTaken)
+        );
+        assert!(store.authenticate("guest").is_none());
+        assert_eq!(store.authenticate(&guest).unwrap().label, "guest");
+        assert!(store.authenticate(&guest).is_some());
+        // Used up
+        assert!(store.authenticate(&guest).is_none());
+
+        let expired = store
+            .add("old", vec![], Some(Duration::ZERO), None)
+            .unwrap();
+        assert!(store.authenticate(&expired).is_none());
+        assert_eq!(store.remove("old"), Ok(()));
+        assert_eq!(store.remove("old"), Err(KeyError::NotFound));
+
+        // A key that practically never expires doesn't overflow into an expired one
+        let lasting = store
+            .add("lasting", vec![], Some(Duration::from_secs(u64::MAX)), None)
+            .unwrap();
+        assert!(store.authenticate(&lasting).is_some());
+    }
+
+    #[test]
+    fn test_volume_range() {
+        assert_eq!(volume_range(&[Permission::Info]), None);
+        assert_eq!(
+            volume_range(&[Permission::Info, Permission::VolumeControl((0.2, 0.8))]),
+            Some((0.2, 0.8))
+        );
+        assert_eq!(volume_range(&[Permission::All]), Some(FULL_VOLUME_RANGE));
     }
 }
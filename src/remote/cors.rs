@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Which web pages may use the remote from a browser
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CorsPolicy {
+    /// The origins pages may be on, like `https://player.example.com`, `*` allows any
+    pub origins: Vec<String>,
+    /// The headers pages may send besides the ones browsers always allow
+    pub headers: Vec<String>,
+    /// The methods pages may use, every route only allows the ones it answers to
+    pub methods: Vec<String>,
+    /// Lets pages send cookies and read the responses to them. Only works with listed origins,
+    /// and the session cookie only reaches pages on other sites over HTTPS.
+    pub credentials: bool,
+    /// How many seconds browsers may remember the answer to a preflight
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            origins: vec!["*".to_string()],
+            headers: ["key", "authorization", "content-type"]
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            methods: ["GET", "POST", "DELETE"]
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+/// The policy with its values parsed, made once for every route
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// `None` allows any origin
+    origins: Option<Vec<HeaderValue>>,
+    headers: Vec<HeaderName>,
+    methods: Vec<Method>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl CorsPolicy {
+    /// Parses the policy, values that can't be parsed are left out and printed
+    pub fn parse(&self) -> Cors {
+        let any_origin = self.origins.iter().any(|o| o == "*");
+        let origins = (!any_origin).then(|| {
+            self.origins
+                .iter()
+                .filter_map(|o| parsed("origin", o, HeaderValue::from_str(o.trim_end_matches('/'))))
+                .collect()
+        });
+        let headers = self
+            .headers
+            .iter()
+            .filter_map(|h| parsed("header", h, HeaderName::from_bytes(h.as_bytes())))
+            .collect();
+        let methods = self
+            .methods
+            .iter()
+            .filter_map(|m| parsed("method", m, Method::from_bytes(m.to_uppercase().as_bytes())))
+            .collect();
+        // Browsers refuse credentials from any origin, so they're only sent to listed ones
+        if self.credentials && any_origin {
+            println!("CORS credentials are only allowed for listed origins, not *");
+        }
+        Cors {
+            origins,
+            headers,
+            methods,
+            credentials: self.credentials && !any_origin,
+            max_age: self.max_age.map(Duration::from_secs),
+        }
+    }
+}
+
+fn parsed<T, E: std::fmt::Display>(kind: &str, value: &str, result: Result<T, E>) -> Option<T> {
+    result
+        .map_err(|e| println!("Ignoring the CORS {} {}: {}", kind, value, e))
+        .ok()
+}
+
+impl Cors {
+    /// Whether pages may send cookies, which they only may from listed origins
+    pub fn allows_credentials(&self) -> bool {
+        self.credentials
+    }
+
+    /// The layer of a route that answers to `methods`. Preflights are answered with the methods of
+    /// the route that the policy allows.
+    pub fn layer(&self, methods: &[Method]) -> CorsLayer {
+        let methods: Vec<Method> = methods
+            .iter()
+            .filter(|m| self.methods.contains(m))
+            .cloned()
+            .collect();
+        let origins = match &self.origins {
+            Some(origins) => AllowOrigin::list(origins.clone()),
+            None => AllowOrigin::any(),
+        };
+        let layer = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(self.headers.clone())
+            .allow_credentials(self.credentials);
+        match self.max_age {
+            Some(max_age) => layer.max_age(max_age),
+            None => layer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let policy = CorsPolicy {
+            origins: vec!["https://player.example.com/".to_string(), "\n".to_string()],
+            methods: vec!["get".to_string(), "POST".to_string()],
+            credentials: true,
+            ..Default::default()
+        };
+        let cors = policy.parse();
+        assert_eq!(
+            cors.origins,
+            Some(vec![HeaderValue::from_static("https://player.example.com")])
+        );
+        assert_eq!(cors.methods, vec![Method::GET, Method::POST]);
+        assert!(cors.credentials);
+        // Any origin can't be sent credentials
+        let cors = CorsPolicy {
+            credentials: true,
+            ..Default::default()
+        }
+        .parse();
+        assert_eq!((cors.origins, cors.credentials), (None, false));
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::error::Category;
+
+pub(crate) type ApiResult<T> = Result<T, ApiError>;
+
+/// Why a request failed, sent to the client as JSON with a code it can match on
+#[derive(Debug, PartialEq)]
+pub(crate) enum ApiError {
+    /// The request can't be read, like a missing body or a number that doesn't parse
+    BadRequest(String),
+    /// No key or an unknown key was sent
+    Unauthorized,
+    /// The key doesn't have the permission the route needs
+    Forbidden,
+    NotFound(String),
+    /// The request conflicts with the library as it is
+    Conflict(String),
+    /// The request can be read but its content isn't valid
+    Unprocessable(String),
+    /// A rate limit or quota has been used up, it can be tried again after the duration
+    TooManyRequests(Duration),
+    Internal(String),
+    /// A server the remote relies on, like a site a page is looked up on, failed
+    BadGateway(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Unprocessable(_) => "unprocessable",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Internal(_) => "internal",
+            ApiError::BadGateway(_) => "bad_gateway",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::Unauthorized => "A valid key is required",
+            ApiError::Forbidden => "The key does not have the permission for this request",
+            ApiError::TooManyRequests(_) => "The limit of the key has been reached",
+            ApiError::BadRequest(m)
+            | ApiError::NotFound(m)
+            | ApiError::Conflict(m)
+            | ApiError::Unprocessable(m)
+            | ApiError::Internal(m)
+            | ApiError::BadGateway(m) => m,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.code(),
+            message: self.message(),
+        };
+        let mut responce = (self.status(), Json(body)).into_response();
+        if let ApiError::TooManyRequests(retry) = self {
+            // Retry-After is in whole seconds, rounded up so the limit has been refilled by then
+            let seconds = retry.as_secs() + u64::from(retry.subsec_nanos() > 0);
+            responce
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.max(1).into());
+        }
+        responce
+    }
+}
+
+/// Bodies that aren't JSON are bad requests, JSON that doesn't fit the expected type can't be
+/// processed
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            Category::Data => ApiError::Unprocessable(e.to_string()),
+            Category::Syntax | Category::Eof => ApiError::BadRequest(e.to_string()),
+            Category::Io => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Volume {
+        volume: f32,
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let e: ApiError = serde_json::from_str::<Volume>("{\"volume\":")
+            .unwrap_err()
+            .into();
+        assert_eq!(e.status(), StatusCode::BAD_REQUEST);
+        let e: ApiError = serde_json::from_str::<Volume>("{\"volume\":\"loud\"}")
+            .unwrap_err()
+            .into();
+        assert_eq!(e.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let responce = ApiError::NotFound("No song with id 1".to_string()).into_response();
+        assert_eq!(responce.status(), StatusCode::NOT_FOUND);
+        assert_eq!(responce.headers()["content-type"], "application/json");
+        let e = ApiError::Forbidden;
+        let body = serde_json::to_value(ErrorBody {
+            error: e.code(),
+            message: e.message(),
+        })
+        .unwrap();
+        assert_eq!(body["error"], "forbidden");
+
+        let responce = ApiError::TooManyRequests(Duration::from_millis(1500)).into_response();
+        assert_eq!(responce.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(responce.headers()["retry-after"], "2");
+    }
+}
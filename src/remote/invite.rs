@@ -0,0 +1,196 @@
+use std::{
+    io::Cursor,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use image::{DynamicImage, GrayImage, ImageOutputFormat, Luma};
+use qrcode::{Color, QrCode};
+
+use crate::conf::Configuration;
+
+use super::{
+    auth::{KeyError, KeyStore, Permission},
+    tls,
+};
+
+/// How long a guest key works if nothing else is chosen
+pub const DEFAULT_MINUTES: u64 = 240;
+/// The longest a guest key can work, a week
+pub const MAX_MINUTES: u64 = 7 * 24 * 60;
+/// How many pixels one module of the code takes
+const MODULE_SIZE: u32 = 8;
+/// The light border around the code that scanners need
+const QUIET_ZONE: u32 = 4;
+
+/// A guest key together with the link that uses it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub label: String,
+    pub key: String,
+    pub url: String,
+}
+
+/// The permissions a guest gets if none are chosen
+pub fn default_permissions() -> Vec<Permission> {
+    vec![Permission::Add, Permission::Info]
+}
+
+/// Mints a guest key that works for `minutes` and links it to the remote at `base`. Without a
+/// label the first free one of guest, guest2, ... is used. Keys can't last longer than
+/// [`MAX_MINUTES`].
+pub fn create(
+    keys: &KeyStore,
+    base: &str,
+    label: Option<&str>,
+    permissions: Vec<Permission>,
+    minutes: u64,
+) -> Result<Invite, KeyError> {
+    if minutes == 0 || minutes > MAX_MINUTES {
+        return Err(KeyError::InvalidExpiry);
+    }
+    let label = match label {
+        Some(l) => l.to_string(),
+        None => free_label(keys),
+    };
+    let expires = Duration::from_secs(minutes * 60);
+    let key = keys.add(&label, permissions, Some(expires), None)?;
+    Ok(Invite {
+        url: invite_url(base, &key),
+        label,
+        key,
+    })
+}
+
+fn free_label(keys: &KeyStore) -> String {
+    let taken: Vec<String> = keys.list().into_iter().map(|k| k.label).collect();
+    (1..)
+        .map(|i| match i {
+            1 => "guest".to_string(),
+            i => format!("guest{}", i),
+        })
+        .find(|l| !taken.contains(l))
+        .unwrap()
+}
+
+/// The link a guest opens, it leads to the web client of the remote. The key is in the fragment,
+/// which browsers never send, so it stays out of requests and logs, and the client logs in with
+/// it.
+pub fn invite_url(base: &str, key: &str) -> String {
+    format!("{}/client#key={}", base.trim_end_matches('/'), key)
+}
+
+/// The address other devices reach the remote at, taken from the configured addresses. HTTPS
+/// addresses are preferred, and a listener on every interface is reached through the address of
+/// this machine in the local network.
+pub fn local_base() -> Option<String> {
+    let addresses: Vec<(SocketAddr, bool)> = Configuration::get_conf()
+        .ip
+        .iter()
+        .filter_map(|a| Some((a.address().parse().ok()?, a.is_https())))
+        .collect();
+    let (address, https) = addresses
+        .iter()
+        .filter(|(a, _)| !a.ip().is_loopback())
+        .max_by_key(|(a, https)| (*https, !a.ip().is_unspecified()))?;
+    let ip = match address.ip().is_unspecified() {
+        true => network_ip()?,
+        false => address.ip(),
+    };
+    let scheme = if *https { "https" } else { "http" };
+    Some(format!(
+        "{}://{}",
+        scheme,
+        SocketAddr::new(ip, address.port())
+    ))
+}
+
+/// The fingerprint of the certificate of the first HTTPS address, so guests can check they
+/// reached this player
+pub fn fingerprint() -> Option<String> {
+    let (certificate, _) = Configuration::get_conf()
+        .ip
+        .iter()
+        .find_map(|a| a.tls_files())?;
+    tls::fingerprint(&certificate).ok()
+}
+
+/// The address of the interface that would be used to reach other machines, connecting a UDP
+/// socket doesn't send anything
+pub(super) fn network_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.168.0.1:80").ok()?;
+    socket.local_addr().ok().map(|a| a.ip())
+}
+
+/// Renders `text` as a QR code, black on white
+pub fn qr_image(text: &str) -> Result<GrayImage, String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| e.to_string())?;
+    let width = code.width() as u32;
+    let colors = code.to_colors();
+    let size = (width + QUIET_ZONE * 2) * MODULE_SIZE;
+    Ok(GrayImage::from_fn(size, size, |x, y| {
+        let modules = QUIET_ZONE..QUIET_ZONE + width;
+        let (x, y) = (x / MODULE_SIZE, y / MODULE_SIZE);
+        let dark = modules.contains(&x)
+            && modules.contains(&y)
+            && colors[((y - QUIET_ZONE) * width + x - QUIET_ZONE) as usize] == Color::Dark;
+        match dark {
+            true => Luma([0]),
+            false => Luma([255]),
+        }
+    }))
+}
+
+/// The QR code of `text` as a PNG file
+pub fn qr_png(text: &str) -> Result<Vec<u8>, String> {
+    let mut png = Cursor::new(vec![]);
+    DynamicImage::ImageLuma8(qr_image(text)?)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .map_err(|e| e.to_string())?;
+    Ok(png.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invite_url() {
+        assert_eq!(
+            invite_url("http://192.168.1.5:8000/", "abc"),
+            "http://192.168.1.5:8000/client#key=abc"
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let keys = KeyStore::new(vec![]);
+        let first = create(&keys, "http://host", None, default_permissions(), 10).unwrap();
+        let second = create(&keys, "http://host", None, default_permissions(), 10).unwrap();
+        assert_eq!(
+            (first.label.as_str(), second.label.as_str()),
+            ("guest", "guest2")
+        );
+        assert_eq!(first.url, format!("http://host/client#key={}", first.key));
+        assert!(keys.authenticate(&second.key).is_some());
+        for minutes in [0, MAX_MINUTES + 1, u64::MAX] {
+            assert_eq!(
+                create(&keys, "http://host", None, default_permissions(), minutes),
+                Err(KeyError::InvalidExpiry)
+            );
+        }
+    }
+
+    #[test]
+    fn test_qr_png() {
+        let image = qr_image("http://host/#key=abc").unwrap();
+        assert_eq!(image.width() % MODULE_SIZE, 0);
+        // The quiet zone is light and the finder pattern in the corner is dark
+        assert_eq!(image.get_pixel(0, 0), &Luma([255]));
+        let corner = QUIET_ZONE * MODULE_SIZE;
+        assert_eq!(image.get_pixel(corner, corner), &Luma([0]));
+        let png = qr_png("http://host/#key=abc").unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+    }
+}
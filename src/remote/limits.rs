@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::conf::Configuration;
+
+use super::auth;
+
+const DAY: u64 = 24 * 60 * 60;
+/// The longest time a client is told to wait, for buckets that refill slowly or not at all
+const MAX_RETRY: Duration = Duration::from_secs(DAY);
+
+/// Which limit a route counts towards
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteClass {
+    Add,
+    Download,
+    Lookup,
+}
+
+/// A token bucket, it holds at most `capacity` requests and refills by `per_minute`. A bucket
+/// with a `per_minute` of 0 never refills, so it allows `capacity` requests until the program
+/// starts over.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub capacity: f64,
+    pub per_minute: f64,
+}
+
+/// How often keys and addresses may use the routes that add songs, download or look up pages.
+/// Keys with the `All` permission aren't limited.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimits {
+    /// The bucket of each key for `POST /add`
+    pub add: Option<Bucket>,
+    /// The bucket of each key for the download routes
+    pub download: Option<Bucket>,
+    /// The bucket of each key for `POST /lookup`
+    #[serde(alias = "proxy")]
+    pub lookup: Option<Bucket>,
+    /// The bucket of each address for every limited route together, shared by the keys used
+    /// from it
+    pub per_ip: Option<Bucket>,
+    /// The bucket of each address for logins and requests with a key or session that isn't
+    /// accepted. Only failures are counted, and while it's empty the address can't try any key.
+    pub failed_attempts: Option<Bucket>,
+    /// How many songs a key may download a day
+    pub daily_downloads: Option<u32>,
+    /// How many songs added by a key may wait in the queue at once
+    pub max_queued: Option<usize>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            add: Some(Bucket {
+                capacity: 10.0,
+                per_minute: 10.0,
+            }),
+            download: Some(Bucket {
+                capacity: 5.0,
+                per_minute: 2.0,
+            }),
+            lookup: Some(Bucket {
+                capacity: 20.0,
+                per_minute: 20.0,
+            }),
+            per_ip: Some(Bucket {
+                capacity: 30.0,
+                per_minute: 30.0,
+            }),
+            failed_attempts: Some(Bucket {
+                capacity: 10.0,
+                per_minute: 5.0,
+            }),
+            daily_downloads: Some(50),
+            max_queued: Some(10),
+        }
+    }
+}
+
+impl RateLimits {
+    fn bucket(&self, class: RouteClass) -> Option<Bucket> {
+        match class {
+            RouteClass::Add => self.add,
+            RouteClass::Download => self.download,
+            RouteClass::Lookup => self.lookup,
+        }
+    }
+}
+
+/// Who a bucket belongs to. Keys have a bucket for each route class, while an address has one
+/// for all of them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Key(String, RouteClass),
+    Ip(IpAddr),
+    /// The failed attempts of an address
+    Attempts(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tokens {
+    left: f64,
+    updated: Instant,
+}
+
+/// The remaining requests of a key for a route class
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BucketStatus {
+    pub class: RouteClass,
+    pub remaining: u32,
+    pub capacity: u32,
+}
+
+/// What a key has left of its limits
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LimitStatus {
+    pub buckets: Vec<BucketStatus>,
+    pub downloads_today: u32,
+    pub daily_downloads: Option<u32>,
+    pub queued: usize,
+    pub max_queued: Option<usize>,
+}
+
+/// Keeps track of how much every key and address has used its limits. Nothing is saved, so the
+/// limits start over when the program does.
+pub struct Limiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<Subject, Tokens>>,
+    /// The day since the unix epoch and how many songs the key downloaded on it
+    downloads: Mutex<HashMap<String, (u64, u32)>>,
+    /// The ids of the songs each key added
+    queued: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Limiter {
+    /// The limits of the configuration
+    pub fn load() -> Self {
+        Limiter::new(Configuration::get_conf().rate_limits)
+    }
+
+    pub fn new(limits: RateLimits) -> Self {
+        Limiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            downloads: Mutex::new(HashMap::new()),
+            queued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of the key and the one of the address. If either is empty
+    /// nothing is taken and the time until it has a token again is returned.
+    pub fn take(&self, key: &str, ip: Option<IpAddr>, class: RouteClass) -> Result<(), Duration> {
+        let subjects = [
+            self.limits
+                .bucket(class)
+                .map(|b| (Subject::Key(key.to_string(), class), b)),
+            ip.zip(self.limits.per_ip)
+                .map(|(ip, b)| (Subject::Ip(ip), b)),
+        ];
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut refilled = vec![];
+        for (subject, bucket) in subjects.into_iter().flatten() {
+            let tokens = refill(buckets.get(&subject).copied(), bucket, now);
+            if tokens.left < 1.0 {
+                return Err(retry_after(tokens, bucket));
+            }
+            refilled.push((subject, tokens));
+        }
+        for (subject, mut tokens) in refilled {
+            tokens.left -= 1.0;
+            buckets.insert(subject, tokens);
+        }
+        Ok(())
+    }
+
+    /// Whether the address may try a key, or the time until it may again after too many failed
+    /// attempts
+    pub fn check_attempt(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let Some((ip, bucket)) = ip.zip(self.limits.failed_attempts) else {
+            return Ok(());
+        };
+        let buckets = self.buckets.lock().unwrap();
+        let tokens = refill(
+            buckets.get(&Subject::Attempts(ip)).copied(),
+            bucket,
+            Instant::now(),
+        );
+        match tokens.left < 1.0 {
+            true => Err(retry_after(tokens, bucket)),
+            false => Ok(()),
+        }
+    }
+
+    /// Counts a key that wasn't accepted against the address
+    pub fn fail_attempt(&self, ip: Option<IpAddr>) {
+        let Some((ip, bucket)) = ip.zip(self.limits.failed_attempts) else {
+            return;
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let subject = Subject::Attempts(ip);
+        let mut tokens = refill(buckets.get(&subject).copied(), bucket, Instant::now());
+        tokens.left = (tokens.left - 1.0).max(0.0);
+        buckets.insert(subject, tokens);
+    }
+
+    /// Counts `songs` downloads towards the daily quota of the key, or returns the time until
+    /// the quota starts over if they don't fit in it
+    pub fn take_downloads(&self, key: &str, songs: u32) -> Result<(), Duration> {
+        let now = auth::now();
+        let today = now / DAY;
+        let mut downloads = self.downloads.lock().unwrap();
+        let (day, count) = downloads.entry(key.to_string()).or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *count = 0;
+        }
+        if let Some(max) = self.limits.daily_downloads {
+            if *count + songs > max {
+                return Err(Duration::from_secs((today + 1) * DAY - now));
+            }
+        }
+        *count += songs;
+        Ok(())
+    }
+
+    /// Remembers the songs the key adds if they fit in its share of the queue. `queue` is the
+    /// ids of the songs that are waiting.
+    pub fn add_to_queue(&self, key: &str, queue: &[&str], songs: &[&str]) -> Result<(), ()> {
+        let mut queued = self.queued.lock().unwrap();
+        let added = queued.entry(key.to_string()).or_default();
+        // Songs that have been played or removed no longer count
+        added.retain(|id| queue.contains(&id.as_str()));
+        if let Some(max) = self.limits.max_queued {
+            if added.len() + songs.len() > max {
+                return Err(());
+            }
+        }
+        added.extend(songs.iter().map(|s| s.to_string()));
+        Ok(())
+    }
+
+    pub fn status(&self, key: &str, queue: &[&str]) -> LimitStatus {
+        let now = Instant::now();
+        let buckets = self.buckets.lock().unwrap();
+        let buckets = [RouteClass::Add, RouteClass::Download, RouteClass::Lookup]
+            .into_iter()
+            .filter_map(|class| {
+                let bucket = self.limits.bucket(class)?;
+                let tokens = buckets.get(&Subject::Key(key.to_string(), class));
+                Some(BucketStatus {
+                    class,
+                    remaining: refill(tokens.copied(), bucket, now).left as u32,
+                    capacity: bucket.capacity as u32,
+                })
+            })
+            .collect();
+        let today = auth::now() / DAY;
+        let downloads_today = match self.downloads.lock().unwrap().get(key) {
+            Some((day, count)) if *day == today => *count,
+            _ => 0,
+        };
+        let queued = self
+            .queued
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|ids| ids.iter().filter(|id| queue.contains(&id.as_str())).count())
+            .unwrap_or_default();
+        LimitStatus {
+            buckets,
+            downloads_today,
+            daily_downloads: self.limits.daily_downloads,
+            queued,
+            max_queued: self.limits.max_queued,
+        }
+    }
+}
+
+/// The tokens of a bucket at `now`, a bucket that hasn't been used is full
+fn refill(tokens: Option<Tokens>, bucket: Bucket, now: Instant) -> Tokens {
+    match tokens {
+        Some(t) => {
+            let minutes = now.duration_since(t.updated).as_secs_f64() / 60.0;
+            Tokens {
+                left: (t.left + minutes * bucket.per_minute.max(0.0)).min(bucket.capacity),
+                updated: now,
+            }
+        }
+        None => Tokens {
+            left: bucket.capacity,
+            updated: now,
+        },
+    }
+}
+
+/// How long until the bucket has a token again, at most `MAX_RETRY`
+fn retry_after(tokens: Tokens, bucket: Bucket) -> Duration {
+    let seconds = (1.0 - tokens.left) / bucket.per_minute * 60.0;
+    match seconds.is_finite() && seconds >= 0.0 {
+        true => Duration::from_secs_f64(seconds).min(MAX_RETRY),
+        false => MAX_RETRY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            add: Some(Bucket {
+                capacity: 2.0,
+                per_minute: 1.0,
+            }),
+            download: None,
+            lookup: None,
+            per_ip: Some(Bucket {
+                capacity: 3.0,
+                per_minute: 1.0,
+            }),
+            failed_attempts: Some(Bucket {
+                capacity: 2.0,
+                per_minute: 0.0,
+            }),
+            daily_downloads: Some(3),
+            max_queued: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let limiter = Limiter::new(limits());
+        let ip = Some("192.168.1.5".parse().unwrap());
+        assert!(limiter.take("a", ip, RouteClass::Add).is_ok());
+        assert!(limiter.take("a", ip, RouteClass::Add).is_ok());
+        let retry = limiter.take("a", ip, RouteClass::Add).unwrap_err();
+        assert!(Duration::from_secs(55) < retry && retry <= Duration::from_secs(60));
+        // Another key from the same address only has what is left of the address
+        assert!(limiter.take("b", ip, RouteClass::Add).is_ok());
+        assert!(limiter.take("b", ip, RouteClass::Add).is_err());
+        assert!(limiter.take("b", None, RouteClass::Add).is_ok());
+        // Classes without a bucket are only limited by address, which shares one bucket
+        // between the classes
+        assert!(limiter.take("a", None, RouteClass::Lookup).is_ok());
+        assert!(limiter.take("c", ip, RouteClass::Lookup).is_err());
+
+        let status = limiter.status("a", &[]);
+        assert_eq!(status.buckets[0].remaining, 0);
+        assert_eq!(status.buckets.len(), 1);
+    }
+
+    #[test]
+    fn test_attempts() {
+        let limiter = Limiter::new(limits());
+        let ip = Some("192.168.1.5".parse().unwrap());
+        limiter.fail_attempt(ip);
+        assert!(limiter.check_attempt(ip).is_ok());
+        limiter.fail_attempt(ip);
+        // The bucket never refills, but the time to wait is still bounded
+        assert_eq!(limiter.check_attempt(ip), Err(MAX_RETRY));
+        assert!(limiter
+            .check_attempt(Some("192.168.1.6".parse().unwrap()))
+            .is_ok());
+        assert!(limiter.check_attempt(None).is_ok());
+
+        let mut limits = limits();
+        limits.add = Some(Bucket {
+            capacity: 0.0,
+            per_minute: 0.0,
+        });
+        let limiter = Limiter::new(limits);
+        assert_eq!(limiter.take("a", None, RouteClass::Add), Err(MAX_RETRY));
+    }
+
+    #[test]
+    fn test_quotas() {
+        let limiter = Limiter::new(limits());
+        assert!(limiter.take_downloads("a", 2).is_ok());
+        let retry = limiter.take_downloads("a", 2).unwrap_err();
+        assert!(retry <= Duration::from_secs(DAY));
+        assert!(limiter.take_downloads("a", 1).is_ok());
+
+        assert!(limiter.add_to_queue("a", &[], &["1", "2"]).is_ok());
+        assert!(limiter.add_to_queue("a", &["1", "2"], &["3"]).is_err());
+        // Once a song has been played there is room again
+        assert!(limiter.add_to_queue("a", &["2"], &["3"]).is_ok());
+        let status = limiter.status("a", &["2", "3"]);
+        assert_eq!((status.queued, status.downloads_today), (2, 3));
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use regex::Regex;
+use reqwest::{redirect::Policy, Client, Url};
+use serde::Serialize;
+
+/// The largest page that is read
+const MAX_PAGE_SIZE: usize = 2 * 1024 * 1024;
+/// How long a page may take to be fetched
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: usize = 3;
+
+/// What is known about a page, read from its title and OpenGraph tags
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Metadata {
+    /// Where the page was found after redirects
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+    /// The OpenGraph type, like video.other
+    pub kind: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LookupError {
+    InvalidUrl(String),
+    /// The host isn't allowed or resolves to an address in the local network
+    NotAllowed(String),
+    /// The page couldn't be fetched
+    Failed(String),
+}
+
+impl std::fmt::Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LookupError::InvalidUrl(e) => write!(f, "Invalid url: {}", e),
+            LookupError::NotAllowed(e) => write!(f, "Not allowed: {}", e),
+            LookupError::Failed(e) => write!(f, "Failed to fetch the page: {}", e),
+        }
+    }
+}
+
+/// The hosts that can be looked up unless the configuration says otherwise
+pub fn default_hosts() -> Vec<String> {
+    ["youtube.com", "youtu.be", "ytimg.com"]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Fetches the page at `url` and reads its metadata. Only pages on the `allowed` hosts or their
+/// subdomains are fetched, and never from an address in the local network, so the remote can't
+/// be used to reach other machines. Redirects are followed by hand so every hop is checked.
+pub async fn lookup(url: &str, allowed: &[String]) -> Result<Metadata, LookupError> {
+    let mut url = Url::parse(url.trim()).map_err(|e| LookupError::InvalidUrl(e.to_string()))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let host = check_url(&url, allowed)?;
+        let address = resolve(&host, &url).await?;
+        // The checked address is used, so the name can't resolve to another one when fetching
+        let client = Client::builder()
+            .redirect(Policy::none())
+            .timeout(LOOKUP_TIMEOUT)
+            .resolve(&host, address)
+            .build()
+            .map_err(|e| LookupError::Failed(e.to_string()))?;
+        let mut response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| LookupError::Failed(e.to_string()))?;
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get("location")
+                .and_then(|l| l.to_str().ok())
+                .ok_or(LookupError::Failed(
+                    "Redirect without a location".to_string(),
+                ))?;
+            url = url
+                .join(location)
+                .map_err(|e| LookupError::InvalidUrl(e.to_string()))?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(LookupError::Failed(response.status().to_string()));
+        }
+        if response.content_length().unwrap_or_default() > MAX_PAGE_SIZE as u64 {
+            return Err(LookupError::Failed("The page is too large".to_string()));
+        }
+        let mut page = vec![];
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| LookupError::Failed(e.to_string()))?
+        {
+            if page.len() + chunk.len() > MAX_PAGE_SIZE {
+                return Err(LookupError::Failed("The page is too large".to_string()));
+            }
+            page.extend_from_slice(&chunk);
+        }
+        return Ok(parse_metadata(&url, &String::from_utf8_lossy(&page)));
+    }
+    Err(LookupError::Failed("Too many redirects".to_string()))
+}
+
+/// Returns the host of the url if it may be fetched
+fn check_url(url: &Url, allowed: &[String]) -> Result<String, LookupError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(LookupError::InvalidUrl(format!(
+            "{} urls can't be looked up",
+            url.scheme()
+        )));
+    }
+    if !matches!(url.port_or_known_default(), Some(80 | 443)) {
+        return Err(LookupError::NotAllowed(
+            "Only the default ports".to_string(),
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or(LookupError::InvalidUrl("The url has no host".to_string()))?
+        .to_lowercase();
+    let allowed = allowed
+        .iter()
+        .any(|a| host == *a || host.ends_with(&format!(".{}", a)));
+    match allowed {
+        true => Ok(host),
+        false => Err(LookupError::NotAllowed(host)),
+    }
+}
+
+/// Resolves the host, every address it has must be public
+async fn resolve(host: &str, url: &Url) -> Result<SocketAddr, LookupError> {
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| LookupError::Failed(e.to_string()))?
+        .collect();
+    if let Some(a) = addresses.iter().find(|a| !is_public(a.ip())) {
+        return Err(LookupError::NotAllowed(format!("{} is {}", host, a.ip())));
+    }
+    addresses
+        .first()
+        .copied()
+        .ok_or(LookupError::Failed(format!("{} has no address", host)))
+}
+
+/// Whether the address is reachable on the internet, private, loopback, link-local and other
+/// special ranges aren't
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && b & 0xfe == 18)
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // Link-local, fe80::/10
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 address leads to, for the forms that carry one: IPv4-mapped
+/// `::ffff:a.b.c.d`, IPv4-compatible `::a.b.c.d`, NAT64 `64:ff9b::/96` and 6to4 `2002::/16`
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let ipv4 = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+    match s {
+        [0, 0, 0, 0, 0, 0xffff, high, low] | [0, 0, 0, 0, 0, 0, high, low] => Some(ipv4(high, low)),
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(ipv4(high, low)),
+        [0x2002, high, low, ..] => Some(ipv4(high, low)),
+        _ => None,
+    }
+}
+
+/// Reads the title and OpenGraph tags of a page
+fn parse_metadata(url: &Url, page: &str) -> Metadata {
+    let meta_re = Regex::new(r"(?is)<meta\s[^>]*>").unwrap();
+    let attribute_re = Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    let mut tags = HashMap::new();
+    for tag in meta_re.find_iter(page) {
+        let attributes: HashMap<String, &str> = attribute_re
+            .captures_iter(tag.as_str())
+            .filter_map(|c| {
+                let value = c.get(2).or(c.get(3))?;
+                Some((c[1].to_lowercase(), value.as_str()))
+            })
+            .collect();
+        let name = attributes.get("property").or(attributes.get("name"));
+        if let (Some(name), Some(content)) = (name, attributes.get("content")) {
+            tags.entry(name.to_lowercase())
+                .or_insert(decode_entities(content.trim()));
+        }
+    }
+    let title_re = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    let title = tags
+        .get("og:title")
+        .or(tags.get("twitter:title"))
+        .cloned()
+        .or(title_re
+            .captures(page)
+            .map(|c| decode_entities(c[1].trim())));
+    Metadata {
+        url: url.to_string(),
+        title: title.filter(|t| !t.is_empty()),
+        description: tags
+            .get("og:description")
+            .or(tags.get("description"))
+            .cloned(),
+        image: tags
+            .get("og:image")
+            .and_then(|i| url.join(i).ok())
+            .map(|i| i.to_string()),
+        site_name: tags.get("og:site_name").cloned(),
+        kind: tags.get("og:type").cloned(),
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        let allowed = default_hosts();
+        let check = |url: &str| check_url(&Url::parse(url).unwrap(), &allowed);
+        assert_eq!(
+            check("https://www.youtube.com/watch?v=1"),
+            Ok("www.youtube.com".to_string())
+        );
+        assert!(check("https://youtu.be/1").is_ok());
+        assert!(matches!(
+            check("https://notyoutube.com/"),
+            Err(LookupError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            check("https://youtube.com:8080/"),
+            Err(LookupError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            check("file:///etc/passwd"),
+            Err(LookupError::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+            "::192.168.1.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "192.0.0.170",
+            "198.18.0.1",
+            "198.19.255.255",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public("142.250.74.46".parse().unwrap()));
+        assert!(is_public("2a00:1450:4001::200e".parse().unwrap()));
+        assert!(is_public("64:ff9b::8efa:4a2e".parse().unwrap()));
+        assert!(is_public("198.20.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_local_hosts() {
+        let allowed = vec!["localhost".to_string()];
+        let found = lookup("http://localhost/", &allowed).await;
+        assert!(matches!(found, Err(LookupError::NotAllowed(_))));
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let page = r#"<html><head><title>Fallback</title>
+            <meta property="og:title" content="Song &amp; Dance">
+            <meta content='A video' name="description">
+            <meta property="og:image" content="/thumb.jpg" />
+            <meta property="og:type" content="video.other">
+            </head></html>"#;
+        let url = Url::parse("https://www.youtube.com/watch?v=1").unwrap();
+        let metadata = parse_metadata(&url, page);
+        assert_eq!(metadata.title.as_deref(), Some("Song & Dance"));
+        assert_eq!(metadata.description.as_deref(), Some("A video"));
+        assert_eq!(
+            metadata.image.as_deref(),
+            Some("https://www.youtube.com/thumb.jpg")
+        );
+        assert_eq!(metadata.kind.as_deref(), Some("video.other"));
+        let metadata = parse_metadata(&url, "<title> Only a title </title>");
+        assert_eq!(metadata.title.as_deref(), Some("Only a title"));
+    }
+}
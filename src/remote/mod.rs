@@ -1,71 +1,148 @@
 use std::{
-    collections::HashMap,
-    ops::Deref,
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    net::{IpAddr, SocketAddr, TcpListener as StdTcpListener},
+    sync::{Arc, Mutex},
     time::Duration,
 };
+mod audit;
 pub(crate) mod auth;
+pub(crate) mod cors;
+mod error;
+pub(crate) mod invite;
+pub(crate) mod limits;
+pub(crate) mod lookup;
+mod session;
+mod song_file;
+mod sse;
+mod stream;
+pub(crate) mod tls;
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{ConnectInfo, DefaultBodyLimit, FromRequestParts, Path, Query, State},
+    handler::Handler,
+    http::{
+        header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+        Uri,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post, MethodRouter},
+    Json, Router, Server,
+};
+use axum_server::HttpConfig;
 use base64::{engine, Engine};
 
-use futures::future::join_all;
 use itertools::Itertools;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
-    sync::mpsc::Sender,
+    net::TcpListener,
+    sync::{mpsc::Sender, Notify},
+    task,
 };
+use tower_http::timeout::TimeoutLayer;
 
 use crate::{
     commands::PlayerMessage,
+    conf::Configuration,
     downloader,
-    files::list_songs,
+    downloads::{self, Job, JobError, JobId, JobState, Playlist},
+    effects::{Effects, EffectsUpdate},
+    events::Event,
+    files::{
+        duplicates::{self, DuplicateGroup},
+        list_songs, playlists,
+    },
+    loudness::ReplayGainMode,
+    output::{self, OutputDevice},
     player_state::PlayerState,
     song::{Song, SongWithImage},
+    tags::{self, TagEdit},
 };
-use std::sync::atomic::Ordering::SeqCst;
 use std::*;
-use tokio::task;
-
-use crate::conf::*;
+use std::{fmt::Display, str::FromStr};
 
-use self::auth::Permission;
+use self::{
+    auth::{KeyError, KeyInfo, KeyStore, Permission},
+    cors::Cors,
+    error::{ApiError, ApiResult},
+    limits::{LimitStatus, Limiter, RouteClass},
+    lookup::{LookupError, Metadata},
+    session::{Session, SessionStore},
+    tls::ListenAddress,
+};
 
-static CORS_HEADERS: &str = "Access-Control-Allow-Methods: POST, GET, OPTIONS\r\nAccess-Control-Allow-Headers: Key\r\nAccess-Control-Allow-Origin: *";
+/// The largest request body that is accepted
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// How long a request may take to be answered, streams can go on for longer once they have
+/// been answered
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a client may take to send the headers of a request
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct RemoteHandler {
     ps: Sender<PlayerMessage>,
     state: Arc<Mutex<PlayerState>>,
+    keys: Arc<KeyStore>,
+    sessions: Arc<SessionStore>,
+    limits: Arc<Limiter>,
     address_listeners: Vec<AddressListener>,
 }
 
 struct AddressListener {
-    address: String,
-    stop_handle: Arc<AtomicBool>,
+    address: ListenAddress,
+    stop_handle: Arc<Notify>,
     ps: Sender<PlayerMessage>,
     state: Arc<Mutex<PlayerState>>,
+    keys: Arc<KeyStore>,
+    sessions: Arc<SessionStore>,
+    limits: Arc<Limiter>,
 }
 
-struct Request {
-    method: String,
-    protocol: String,
-    _headers: HashMap<String, String>,
+/// What the handlers of the routes share
+#[derive(Clone)]
+struct Remote {
+    ps: Sender<PlayerMessage>,
+    state: Arc<Mutex<PlayerState>>,
+    keys: Arc<KeyStore>,
+    sessions: Arc<SessionStore>,
+    limits: Arc<Limiter>,
+    /// Whether the requests come over HTTPS
+    https: bool,
+    /// Whether pages on other sites may use the session cookie, set by `router`
+    cross_site: bool,
+}
+
+/// The key or session that came with the request, put in the request by `authorize`
+#[derive(Clone)]
+struct Auth {
+    /// The label of the key, sessions have the label of the key they were started with
+    label: String,
     permissions: Vec<Permission>,
-    body: Option<String>,
 }
 
-enum ResponceTypes<'a> {
-    Success(Option<&'a str>),
-    Forbidden,
-    BadRequest(Option<&'a str>),
-    NotFound,
+/// What a request was sent with to prove who sent it
+#[derive(Debug, PartialEq)]
+enum Credentials {
+    /// A session token from the `Authorization` header or the session cookie
+    Token(String),
+    /// A session token from the query, only accepted if the session is short lived
+    QueryToken(String),
+    /// A key from the `key` header, keys are never read from the query
+    Key(String),
+    None,
 }
 
-macro_rules! check_permissions {
-    ($a: expr, $b: expr) => {{
-        if let Err(e) = $b.check_permissions($a) {
-            return e;
-        };
-    }};
+/// A route of the remote together with the permission a key needs to use it. Routes can only be
+/// added through `routes`, so none can be added without saying who may use it. Routes without a
+/// permission are open to anyone and check the credentials they're sent themselves.
+struct Route {
+    method: Method,
+    path: &'static str,
+    required: Option<Permission>,
+    handler: MethodRouter<Remote>,
+    /// The rate limit the route counts towards
+    class: Option<RouteClass>,
 }
 
 macro_rules! send_until_succ {
@@ -76,388 +153,1157 @@ macro_rules! send_until_succ {
     };
 }
 
-macro_rules! require_body {
-    ($b: expr) => {
-        match $b {
-            Some(b) => b,
-            None => {
-                return ResponceTypes::BadRequest(Some("This request requires a body"))
-                    .get_responce()
-            }
+impl Route {
+    fn get<H, T>(path: &'static str, required: Permission, handler: H) -> Self
+    where
+        H: Handler<T, Remote>,
+        T: 'static,
+    {
+        Self::new(Method::GET, path, Some(required), get(handler))
+    }
+
+    fn post<H, T>(path: &'static str, required: Permission, handler: H) -> Self
+    where
+        H: Handler<T, Remote>,
+        T: 'static,
+    {
+        Self::new(Method::POST, path, Some(required), post(handler))
+    }
+
+    fn delete<H, T>(path: &'static str, required: Permission, handler: H) -> Self
+    where
+        H: Handler<T, Remote>,
+        T: 'static,
+    {
+        Self::new(Method::DELETE, path, Some(required), delete(handler))
+    }
+
+    /// A route that needs no key, like logging in
+    fn public_post<H, T>(path: &'static str, handler: H) -> Self
+    where
+        H: Handler<T, Remote>,
+        T: 'static,
+    {
+        Self::new(Method::POST, path, None, post(handler))
+    }
+
+    fn public_get<H, T>(path: &'static str, handler: H) -> Self
+    where
+        H: Handler<T, Remote>,
+        T: 'static,
+    {
+        Self::new(Method::GET, path, None, get(handler))
+    }
+
+    fn new(
+        method: Method,
+        path: &'static str,
+        required: Option<Permission>,
+        handler: MethodRouter<Remote>,
+    ) -> Self {
+        Route {
+            method,
+            path,
+            required,
+            handler,
+            class: None,
         }
-    };
+    }
+
+    fn limited(mut self, class: RouteClass) -> Self {
+        self.class = Some(class);
+        self
+    }
 }
 
-impl ResponceTypes<'_> {
-    fn get_responce(&self) -> String {
-        match self {
-            ResponceTypes::Success(d) => {
-                match d {
-                    Some(body) => format!("HTTP/1.1 200 Ok \r\n{}\r\nContent-Type: text/json\r\nContent-Length: {}\r\n\r\n{}",CORS_HEADERS, body.as_bytes().len(), body),
-                    None => format!("HTTP/1.1 200 Ok \r\n{}\r\n\r\n",CORS_HEADERS)
-                }
+/// Every route of the remote and the permission it needs
+fn routes() -> Vec<Route> {
+    use Permission::*;
+    // Any volume range allows changing the replay gain, the range of the key is checked when the
+    // volume is set
+    let volume = || VolumeControl((0.0, 0.0));
+    vec![
+        Route::get("/", Info, info),
+        Route::get("/list", Info, list),
+        Route::get("/picture", Info, picture),
+        Route::get("/picture/list", Info, picture_list),
+        Route::get("/duplicates", Info, list_duplicates),
+        Route::post("/duplicates/merge", Library, merge_duplicates),
+        Route::post("/duplicates/hide", Library, hide_duplicates),
+        Route::post("/play", PlayPause, play),
+        Route::post("/pause", PlayPause, pause),
+        Route::post("/skip", Seek, skip),
+        Route::post("/reorder", Seek, reorder),
+        Route::post("/add", Add, add).limited(RouteClass::Add),
+        Route::post("/download", Download, download).limited(RouteClass::Download),
+        Route::post("/download/add", Download, download_add).limited(RouteClass::Download),
+        Route::post("/download/playlist", Download, download_playlist)
+            .limited(RouteClass::Download),
+        Route::get("/downloads", Download, list_downloads),
+        Route::post("/downloads/:id/cancel", Download, cancel_download),
+        Route::post("/downloads/:id/retry", Download, retry_download).limited(RouteClass::Download),
+        Route::post("/volume", volume(), set_volume),
+        Route::post("/speed", Seek, speed),
+        Route::post("/pitch", Seek, pitch),
+        Route::post("/replaygain", volume(), replay_gain),
+        Route::get("/devices", Info, devices),
+        Route::get("/effects", Info, effects),
+        Route::post("/effects", Effects, set_effects),
+        Route::post("/seek", Seek, seek),
+        Route::post("/lookup", Download, lookup).limited(RouteClass::Lookup),
+        Route::get("/song/:id/tags", Info, song_tags),
+        Route::post("/song/:id/tags", Library, edit_song_tags),
+        Route::get("/song/:id/file", Info, song_file),
+        Route::get("/stream", Info, stream_audio),
+        Route::get("/events", Info, event_stream),
+        Route::get("/audit", All, audit_log),
+        Route::get("/keys", All, list_keys),
+        Route::post("/keys", All, add_key),
+        Route::delete("/keys/:label", All, remove_key),
+        Route::get("/invite.png", All, invite_png),
+        Route::public_post("/login", login),
+        Route::public_post("/logout", logout),
+        Route::public_get("/me", key_status),
+        Route::public_get("/client", client_page),
+        Route::get("/sessions", All, list_sessions),
+        Route::delete("/sessions/:id", All, end_session),
+    ]
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Auth {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Auth>()
+            .cloned()
+            .ok_or(ApiError::Internal("The route is not guarded".to_string()))
+    }
+}
 
+/// Lets the request through if its key has the permission the route needs and hasn't used up
+/// the rate limit of the route, and records the access in the audit log
+async fn authorize(
+    State((r, required, class)): State<(Remote, Permission, Option<RouteClass>)>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    // Requests made without a listener, like in the tests, have no address
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip());
+    let found = r.identify_from(request.headers(), request.uri(), ip);
+    let limited = |auth: &Auth| match class {
+        Some(class) if auth.is_limited() => r.limits.take(&auth.label, ip, class),
+        _ => Ok(()),
+    };
+    let responce = match &found {
+        Err(retry) => ApiError::TooManyRequests(*retry).into_response(),
+        Ok(None) => ApiError::Unauthorized.into_response(),
+        Ok(Some(auth)) if !auth::has_permissions(&auth.permissions, &[required]) => {
+            ApiError::Forbidden.into_response()
+        }
+        Ok(Some(auth)) => match limited(auth) {
+            Err(retry) => ApiError::TooManyRequests(retry).into_response(),
+            Ok(_) => {
+                request.extensions_mut().insert(auth.clone());
+                next.run(request).await
             }
-            ResponceTypes::Forbidden => format!("HTTP/1.1 401 Unauthorized \r\n{}\r\n\r\n", CORS_HEADERS),
-            ResponceTypes::BadRequest(s) => {
-                match s {
-                    Some(body) =>format!("HTTP/1.1 402 Bad request\r\n{}\r\nContent-Type: text/plain\r\nContent-Lenght: {}\r\n\r\n{}",CORS_HEADERS, body.as_bytes().len(), body),
-                    None => format!("HTTP/1.1 402 Bad request\r\n{}\r\n\r\n", CORS_HEADERS)
-                }
+        },
+    };
+    let label = match &found {
+        Ok(Some(auth)) => Some(auth.label.as_str()),
+        _ => None,
+    };
+    audit::record(label, &method, &path, responce.status());
+    responce
+}
+
+impl Auth {
+    /// Keys with every permission aren't held to the rate limits and quotas
+    fn is_limited(&self) -> bool {
+        !self.permissions.contains(&Permission::All)
+    }
+
+    /// Keys that aren't limited see every download, others only their own
+    fn sees_job(&self, job: &Job) -> bool {
+        !self.is_limited() || job.owner.as_deref() == Some(self.label.as_str())
+    }
+
+    /// Whether the event may be sent to the key, downloads need the `Download` permission and
+    /// are only sent to keys that see the job
+    fn sees_event(&self, event: &Event) -> bool {
+        match event {
+            Event::Download(job) => {
+                auth::has_permissions(&self.permissions, &[Permission::Download])
+                    && self.sees_job(job)
             }
-            ResponceTypes::NotFound => format!("HTTP/1.1 404 Not Found\r\n{}\r\n\r\n", CORS_HEADERS),
+            _ => true,
         }
     }
 }
 
-impl Request {
-    pub fn check_permissions(&self, required_permissions: &[Permission]) -> Result<(), String> {
-        if self
-            .permissions
-            .iter()
-            .all(|p| required_permissions.contains(p))
-        {
-            return Err(ResponceTypes::Forbidden.get_responce());
-        }
-        Ok(())
+/// Finds the credentials of a request. A bearer token comes first, then the `key` header, the
+/// session cookie and at last a session token in the query, which players and media elements
+/// that can't send headers use. Keys are never taken from the query, as URLs end up in logs.
+fn credentials(headers: &HeaderMap, uri: &Uri) -> Credentials {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    if let Some(token) = header(header::AUTHORIZATION).and_then(|a| a.strip_prefix("Bearer ")) {
+        return Credentials::Token(token.trim().to_string());
+    }
+    if let Some(key) = header(HeaderName::from_static("key")) {
+        return Credentials::Key(key.to_string());
+    }
+    let cookie = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == session::COOKIE_NAME);
+    if let Some((_, token)) = cookie {
+        return Credentials::Token(token.to_string());
+    }
+    match uri.query().and_then(|q| query_param(q, "token")) {
+        Some(token) => Credentials::QueryToken(token),
+        None => Credentials::None,
     }
 }
 
-impl AddressListener {
-    async fn new(
-        address: String,
-        stop_handle: Arc<AtomicBool>,
-        ps: Sender<PlayerMessage>,
-        state: Arc<Mutex<PlayerState>>,
-    ) -> Result<AddressListener, String> {
-        let adrl = AddressListener {
-            address: address.to_string(),
-            stop_handle,
-            ps,
-            state,
+impl Remote {
+    /// Finds the key or session of the credentials, a use of the key is counted
+    fn identify(&self, credentials: Credentials) -> Option<Auth> {
+        let (label, permissions) = match credentials {
+            Credentials::Token(t) => self.session(&t).map(|s| (s.key, s.permissions)),
+            Credentials::QueryToken(t) => self
+                .session(&t)
+                .filter(Session::is_short_lived)
+                .map(|s| (s.key, s.permissions)),
+            Credentials::Key(k) => self.keys.authenticate(&k).map(|k| (k.label, k.permissions)),
+            Credentials::None => None,
+        }?;
+        Some(Auth { label, permissions })
+    }
+
+    /// Identifies the request. Keys can be guessed, so a wrong key is counted against the address
+    /// and an address that failed too many times gets the time until it may try again instead.
+    /// Tokens are too long to guess, requests with a wrong token or nothing at all aren't counted.
+    fn identify_from(
+        &self,
+        headers: &HeaderMap,
+        uri: &Uri,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<Auth>, Duration> {
+        let credentials = credentials(headers, uri);
+        if !matches!(credentials, Credentials::Key(_)) {
+            return Ok(self.identify(credentials));
+        }
+        self.limits.check_attempt(ip)?;
+        let auth = self.identify(credentials);
+        if auth.is_none() {
+            self.limits.fail_attempt(ip);
+        }
+        Ok(auth)
+    }
+
+    /// The ids of the songs waiting in the queue
+    fn queued_ids(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.queue.iter().map(|s| s.id.clone()).collect()
+    }
+
+    /// Counts songs towards the share of the queue the key may fill. When it's full the key can
+    /// try again once the current song has ended.
+    fn add_to_queue(&self, label: &str, songs: &[&str]) -> ApiResult<()> {
+        let queue = self.queued_ids();
+        let queue = queue.iter().map(String::as_str).collect_vec();
+        self.limits.add_to_queue(label, &queue, songs).map_err(|_| {
+            let state = self.state.lock().unwrap();
+            let left = state
+                .total_duration
+                .zip(state.elapsed_duration)
+                .map(|(total, elapsed)| total.saturating_sub(elapsed))
+                .unwrap_or(Duration::from_secs(60));
+            ApiError::TooManyRequests(left)
+        })
+    }
+
+    /// The cookie that keeps the session token in browsers. Pages on the origins the CORS policy
+    /// sends credentials to are on other sites, and browsers only send them cookies that allow
+    /// any site and are secure, so the cookie only allows that when the policy asks for it over
+    /// HTTPS.
+    fn session_cookie(&self, token: &str, max_age: u64) -> String {
+        let same_site = match self.cross_site {
+            true => "None",
+            false => "Strict",
         };
-        match adrl.start().await {
-            Ok(_) => Ok(adrl),
-            Err(e) => Err(format!("Unable to create listener for {e}")),
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite={}; Max-Age={}",
+            session::COOKIE_NAME,
+            token,
+            same_site,
+            max_age
+        );
+        // Browsers only send secure cookies over HTTPS, so the token never travels in cleartext
+        if self.https {
+            cookie.push_str("; Secure");
         }
+        cookie
     }
 
-    async fn start(&self) -> Result<(), std::io::Error> {
-        let lister = TcpListener::bind(self.address.as_str()).await?;
-        let sh = self.stop_handle.clone();
-        let psx = self.ps.clone();
-        let state = self.state.clone();
-        tokio::spawn(async move {
-            let handle = task::spawn(async move {
-                loop {
-                    let (s, _a) = lister.accept().await.unwrap();
-                    Self::handle_request(s, psx.clone(), state.clone()).await;
-                }
-            });
-            tokio::spawn(async move {
-                loop {
-                    match sh.load(SeqCst) {
-                        true => break,
-                        false => {
-                            tokio::time::sleep(Duration::from_millis(500)).await;
-                        }
-                    }
+    /// The session of the token, as long as the key it was started with still works
+    fn session(&self, token: &str) -> Option<Session> {
+        self.sessions
+            .verify(token)
+            .filter(|s| self.keys.is_active(&s.key))
+    }
+}
+
+/// Returns the percent decoded value of a parameter in a query string
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let (_, value) = query
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| *k == name)?;
+    percent_decode_str(value)
+        .decode_utf8()
+        .ok()
+        .map(|v| v.into_owned())
+}
+
+fn require_body(body: String) -> ApiResult<String> {
+    match body.is_empty() {
+        false => Ok(body),
+        true => Err(ApiError::BadRequest(
+            "This request requires a body".to_string(),
+        )),
+    }
+}
+
+fn parse_body<T>(body: String) -> ApiResult<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    require_body(body)?
+        .trim()
+        .parse()
+        .map_err(|e: T::Err| ApiError::BadRequest(e.to_string()))
+}
+
+fn song_by_id(id: &str) -> ApiResult<Song> {
+    Song::from_id(id).ok_or(ApiError::NotFound(format!("No song with id {}", id)))
+}
+
+/// Builds the router from `routes`, guarding every route with its permission. Every path gets a
+/// CORS layer that answers preflights with the methods of its routes, unknown paths get none.
+/// Every request goes through the body size layer.
+fn router(mut remote: Remote, cors: &Cors) -> Router {
+    remote.cross_site = remote.https && cors.allows_credentials();
+    let mut router = Router::new();
+    for (path, routes) in routes().into_iter().into_group_map_by(|r| r.path) {
+        let methods = routes.iter().map(|r| r.method.clone()).collect_vec();
+        let handler = routes
+            .into_iter()
+            .map(|route| {
+                let mut handler = route.handler;
+                if let Some(required) = route.required {
+                    let state = (remote.clone(), required, route.class);
+                    let guard = middleware::from_fn_with_state(state, authorize);
+                    handler = handler.route_layer(guard);
                 }
+                handler = handler.layer(TimeoutLayer::new(REQUEST_TIMEOUT));
+                handler
             })
-            .await
-            .unwrap();
-            handle.abort();
-        });
-        Ok(())
+            .reduce(MethodRouter::merge)
+            .unwrap_or_default();
+        // The layer also wraps the fallback of the path, which is where preflights end up
+        router = router.route(path, handler.layer(cors.layer(&methods)));
     }
+    router
+        .fallback(not_found)
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(remote)
+}
 
-    async fn handle_request(
-        mut s: tokio::net::TcpStream,
-        ps: Sender<PlayerMessage>,
-        state: Arc<Mutex<PlayerState>>,
-    ) {
-        let request = Self::parse_request(BufReader::new(&mut s)).await;
-        match request {
-            Ok(r) => match r.protocol.trim() {
-                "HTTP/1.1" => {
-                    s.write_all(Self::handle_http1_1(r, ps, state).await.as_bytes())
-                        .await
-                        .unwrap();
-                }
-                _ => s
-                    .write_all(
-                        ResponceTypes::BadRequest(Some("Unsupported protocol"))
-                            .get_responce()
-                            .as_bytes(),
-                    )
-                    .await
-                    .unwrap(),
-            },
-            Err(e) => {
-                s.write_all(
-                    ResponceTypes::BadRequest(Some(&e))
-                        .get_responce()
-                        .as_bytes(),
-                )
-                .await
-                .unwrap();
-            }
-        }
+async fn audit_log() -> Json<Vec<audit::AuditEntry>> {
+    Json(audit::entries())
+}
+
+async fn list_keys(State(r): State<Remote>) -> Json<Vec<KeyInfo>> {
+    Json(r.keys.list())
+}
+
+/// A key to create, it expires after `expires_in` seconds or `uses` requests if they are given
+#[derive(Deserialize)]
+struct NewKey {
+    label: String,
+    permissions: Vec<Permission>,
+    expires_in: Option<u64>,
+    uses: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CreatedKey {
+    label: String,
+    key: String,
+}
+
+async fn add_key(State(r): State<Remote>, body: String) -> ApiResult<Json<CreatedKey>> {
+    let new: NewKey = serde_json::from_str(&require_body(body)?)?;
+    let expires_in = new.expires_in.map(Duration::from_secs);
+    let key = r
+        .keys
+        .add(&new.label, new.permissions, expires_in, new.uses)
+        .map_err(key_error)?;
+    Ok(Json(CreatedKey {
+        label: new.label,
+        key,
+    }))
+}
+
+async fn remove_key(State(r): State<Remote>, Path(label): Path<String>) -> ApiResult<()> {
+    r.keys.remove(&label).map_err(key_error)?;
+    r.sessions.end_key(&label);
+    Ok(())
+}
+
+/// A key to log in with. The session gets the permissions of the key unless fewer are asked for
+/// and ends after `expires_in` seconds if that's sooner than usual.
+#[derive(Deserialize)]
+struct Login {
+    key: String,
+    permissions: Option<Vec<Permission>>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct LoggedIn {
+    token: String,
+    expires: u64,
+    permissions: Vec<Permission>,
+}
+
+/// Exchanges a key for a session token, which is sent back in the body for clients that use the
+/// `Authorization` header and as a cookie for browsers. Addresses that failed too many times
+/// can't log in for a while.
+async fn login(
+    State(r): State<Remote>,
+    connect: Option<ConnectInfo<SocketAddr>>,
+    body: String,
+) -> ApiResult<Response> {
+    let login: Login = serde_json::from_str(&require_body(body)?)?;
+    let ip = connect.map(|c| c.0.ip());
+    if let Err(retry) = r.limits.check_attempt(ip) {
+        let error = ApiError::TooManyRequests(retry);
+        audit::record(None, &Method::POST, "/login", error.status());
+        return Err(error);
     }
+    let key = r.keys.authenticate(&login.key);
+    if key.is_none() {
+        r.limits.fail_attempt(ip);
+    }
+    let started = match &key {
+        None => Err(ApiError::Unauthorized),
+        Some(k) => r
+            .sessions
+            .start(
+                k,
+                login.permissions,
+                login.expires_in.map(Duration::from_secs),
+            )
+            .map_err(|_| ApiError::Forbidden),
+    };
+    let label = key.as_ref().map(|k| k.label.as_str());
+    let status = started
+        .as_ref()
+        .map_or_else(|e| e.status(), |_| StatusCode::OK);
+    audit::record(label, &Method::POST, "/login", status);
+    let (token, session) = started?;
+    let max_age = session.expires.saturating_sub(session.created);
+    Ok((
+        [(header::SET_COOKIE, r.session_cookie(&token, max_age))],
+        Json(LoggedIn {
+            token,
+            expires: session.expires,
+            permissions: session.permissions,
+        }),
+    )
+        .into_response())
+}
 
-    async fn handle_http1_1(
-        r: Request,
-        ps: Sender<PlayerMessage>,
-        state: Arc<Mutex<PlayerState>>,
-    ) -> String {
-        match r.method.as_str() {
-            "GET /" => {
-                check_permissions!(&[Permission::Info], r);
-                let s = state.lock().unwrap();
-                ResponceTypes::Success(Some(&serde_json::to_string(s.deref()).unwrap()))
-                    .get_responce()
-            }
-            "GET /list" => {
-                check_permissions!(&[Permission::Info], r);
-                let json = serde_json::to_string(&list_songs()).unwrap();
-                ResponceTypes::Success(Some(&json)).get_responce()
-            }
-            "GET /picture" => {
-                check_permissions!(&[Permission::Info], r);
-                let body = require_body!(r.body);
-                let list = list_songs();
-                let mut song_img_list: Vec<SongWithImage> = Vec::new();
-                for line in body.lines() {
-                    if let Some(song) = list
-                        .iter()
-                        .find(|s| s.name == line || s.url == Some(line.to_owned()))
-                    {
-                        if let Some(img) = song.get_image() {
-                            let engine = engine::general_purpose::STANDARD;
-                            let image = engine.encode(img);
-                            song_img_list.push(SongWithImage {
-                                song: song.clone(),
-                                image: Some(image),
-                            });
-                        }
-                    }
-                }
-                ResponceTypes::Success(Some(&serde_json::to_string(&song_img_list).unwrap()))
-                    .get_responce()
-            }
-            "GET /picture/list" => {
-                let mut song_img_list: Vec<SongWithImage> = vec![];
-                for song in list_songs() {
-                    let image = song.get_image().map(|i| {
-                        let engine = engine::general_purpose::STANDARD;
-                        engine.encode(i)
+/// Ends the session the request was sent with and clears the cookie
+async fn logout(State(r): State<Remote>, headers: HeaderMap, uri: Uri) -> ApiResult<Response> {
+    let session = match credentials(&headers, &uri) {
+        Credentials::Token(t) => r.sessions.verify(&t),
+        _ => None,
+    };
+    let label = session.as_ref().map(|s| s.key.as_str());
+    let status = match session {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::UNAUTHORIZED,
+    };
+    audit::record(label, &Method::POST, "/logout", status);
+    let session = session.ok_or(ApiError::Unauthorized)?;
+    r.sessions.end(&session.id);
+    Ok(([(header::SET_COOKIE, r.session_cookie("", 0))], ()).into_response())
+}
+
+async fn list_sessions(State(r): State<Remote>) -> Json<Vec<Session>> {
+    Json(r.sessions.list())
+}
+
+async fn end_session(State(r): State<Remote>, Path(id): Path<String>) -> ApiResult<()> {
+    match r.sessions.end(&id) {
+        true => Ok(()),
+        false => Err(ApiError::NotFound(format!("No session with id {}", id))),
+    }
+}
+
+/// What a key may do and what it has left of its limits
+#[derive(Serialize)]
+struct KeyStatus {
+    label: String,
+    permissions: Vec<Permission>,
+    /// `None` for keys that aren't limited
+    limits: Option<LimitStatus>,
+}
+
+/// A small web client, the page invite links lead to. It logs in with the key in the fragment of
+/// the link itself.
+async fn client_page() -> Html<&'static str> {
+    Html(include_str!("client.html"))
+}
+
+/// Tells the key or session the request was sent with about itself, any key may ask
+async fn key_status(
+    State(r): State<Remote>,
+    connect: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> ApiResult<Json<KeyStatus>> {
+    let auth = r
+        .identify_from(&headers, &uri, connect.map(|c| c.0.ip()))
+        .map_err(ApiError::TooManyRequests)
+        .and_then(|a| a.ok_or(ApiError::Unauthorized));
+    let status = auth
+        .as_ref()
+        .map_or_else(|e| e.status(), |_| StatusCode::OK);
+    let label = auth.as_ref().ok().map(|a| a.label.as_str());
+    audit::record(label, &Method::GET, "/me", status);
+    let auth = auth?;
+    let limits = auth.is_limited().then(|| {
+        let queue = r.queued_ids();
+        let queue = queue.iter().map(String::as_str).collect_vec();
+        r.limits.status(&auth.label, &queue)
+    });
+    Ok(Json(KeyStatus {
+        label: auth.label,
+        permissions: auth.permissions,
+        limits,
+    }))
+}
+
+/// What a guest key made by `/invite.png` allows, the permissions are separated by commas
+#[derive(Deserialize)]
+struct InviteQuery {
+    label: Option<String>,
+    permissions: Option<String>,
+    minutes: Option<u64>,
+}
+
+/// Mints a guest key and answers with a QR code of the link that uses it. The link points to the
+/// host the request was sent to, so the phone reaches the remote the same way the admin did.
+async fn invite_png(
+    State(r): State<Remote>,
+    Query(q): Query<InviteQuery>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let permissions = match q.permissions.as_deref() {
+        None | Some("") => invite::default_permissions(),
+        Some(p) => p
+            .split(',')
+            .map(|p| serde_json::from_value(p.trim().into()))
+            .collect::<Result<Vec<Permission>, _>>()?,
+    };
+    let base = match headers.get(header::HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) if r.https => format!("https://{}", host),
+        Some(host) => format!("http://{}", host),
+        None => invite::local_base().ok_or(ApiError::Internal(
+            "The address of the remote is unknown".to_string(),
+        ))?,
+    };
+    let minutes = q.minutes.unwrap_or(invite::DEFAULT_MINUTES);
+    let invite = invite::create(&r.keys, &base, q.label.as_deref(), permissions, minutes)
+        .map_err(key_error)?;
+    let png = invite::qr_png(&invite.url).map_err(ApiError::Internal)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png".to_string()),
+            // The code holds a key, it shouldn't be kept around
+            (header::CACHE_CONTROL, "no-store".to_string()),
+            (HeaderName::from_static("x-invite-label"), invite.label),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+fn key_error(e: KeyError) -> ApiError {
+    match e {
+        KeyError::InvalidLabel | KeyError::InvalidExpiry => ApiError::Unprocessable(e.to_string()),
+        KeyError::LabelTaken => ApiError::Conflict(e.to_string()),
+        KeyError::NotFound => ApiError::NotFound(e.to_string()),
+        KeyError::Save(_) => ApiError::Internal(e.to_string()),
+    }
+}
+
+async fn not_found(uri: Uri) -> ApiError {
+    ApiError::NotFound(format!("No route for {}", uri.path()))
+}
+
+async fn info(State(r): State<Remote>) -> ApiResult<Json<PlayerState>> {
+    let s = r.state.lock().unwrap();
+    Ok(Json(s.clone()))
+}
+
+/// Runs work that reads the files of the library on a thread where blocking is fine, so it
+/// doesn't hold up the other requests
+async fn blocking<T, F>(work: F) -> ApiResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    task::spawn_blocking(work)
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+async fn list() -> ApiResult<Json<Vec<Song>>> {
+    Ok(Json(blocking(list_songs).await?))
+}
+
+async fn picture(body: String) -> ApiResult<Json<Vec<SongWithImage>>> {
+    let body = require_body(body)?;
+    let song_img_list = blocking(move || {
+        let list = list_songs();
+        let mut song_img_list: Vec<SongWithImage> = Vec::new();
+        for line in body.lines() {
+            if let Some(song) = Song::find_in(&list, line) {
+                if let Some(img) = song.get_image() {
+                    let engine = engine::general_purpose::STANDARD;
+                    let image = engine.encode(img);
+                    song_img_list.push(SongWithImage {
+                        song: song.clone(),
+                        image: Some(image),
                     });
-                    song_img_list.push(SongWithImage { song, image });
                 }
-                ResponceTypes::Success(Some(&serde_json::to_string(&song_img_list).unwrap()))
-                    .get_responce()
-            }
-            "POST /play" => {
-                check_permissions!(&[Permission::PlayPause], r);
-                send_until_succ!(ps, PlayerMessage::Play);
-                ResponceTypes::Success(None).get_responce()
-            }
-            "POST /pause" => {
-                check_permissions!(&[Permission::PlayPause], r);
-                send_until_succ!(ps, PlayerMessage::Pause);
-                ResponceTypes::Success(None).get_responce()
             }
-            "POST /skip" => {
-                check_permissions!(&[Permission::Seek], r);
-                let body = require_body!(r.body);
-                let mut l = vec![];
-                for line in body.lines() {
-                    if let Ok(n) = line.parse::<usize>() {
-                        l.push(n)
-                    }
-                }
-                send_until_succ!(ps, PlayerMessage::Skip(l.clone().into()));
-                ResponceTypes::Success(None).get_responce()
-            }
-            "POST /reorder" => {
-                check_permissions!(&[Permission::Seek], r);
-                let body = require_body!(r.body);
-                for line in body.lines() {
-                    if let Some((f, t)) = line.split_once(' ') {
-                        if let (Ok(f), Ok(t)) = (f.parse::<usize>(), t.parse::<usize>()) {
-                            send_until_succ!(ps, PlayerMessage::ReOrder(f, t))
-                        }
-                    }
-                }
-                ResponceTypes::Success(None).get_responce()
-            }
-            "POST /add" => {
-                check_permissions!(&[Permission::Add], r);
-                let body = require_body!(r.body);
-                for line in body.lines() {
-                    if let Some(song) = Song::from_string(line.to_owned()) {
-                        send_until_succ!(ps, PlayerMessage::Add(song.clone()));
-                    }
-                }
-                ResponceTypes::Success(None).get_responce()
-            }
-            "POST /download" => {
-                check_permissions!(&[Permission::Download], r);
-                let body = require_body!(r.body);
-                let mut handles = vec![];
-                for line in body.lines() {
-                    handles.push(downloader::download_dlp(line.to_owned()));
-                }
-                join_all(handles).await;
-                ResponceTypes::Success(None).get_responce()
-            }
-            "POST /download/add" => {
-                check_permissions!(&[Permission::Download], r);
-                let body = require_body!(r.body);
-                let mut handles = vec![];
-                for line in body.lines() {
-                    handles.push(Self::download_and_add(line.to_string(), ps.clone()));
-                }
-                join_all(handles).await;
-                ResponceTypes::Success(None).get_responce()
-            }
-            "POST /volume" => {
-                let body = require_body!(r.body);
-                match body.parse::<f32>() {
-                    Ok(target_volume) => {
-                        for p in r.permissions {
-                            match p {
-                                Permission::VolumeControl((min, max)) => {
-                                    if min <= target_volume && target_volume <= max {
-                                        send_until_succ!(ps, PlayerMessage::Volume(target_volume));
-                                        return ResponceTypes::Success(None).get_responce();
-                                    } else {
-                                        return ResponceTypes::Forbidden.get_responce();
-                                    }
-                                }
-                                _ => continue,
-                            }
-                        }
-                        ResponceTypes::Forbidden.get_responce()
-                    }
-                    Err(e) => ResponceTypes::BadRequest(Some(&e.to_string())).get_responce(),
-                }
-            }
-            "POST /speed" => {
-                check_permissions!(&[Permission::Seek], r);
-                let body = require_body!(r.body);
-                match body.parse::<f32>() {
-                    Ok(n) => {
-                        send_until_succ!(ps, PlayerMessage::Speed(n));
-                        ResponceTypes::Success(None).get_responce()
-                    }
-                    Err(e) => ResponceTypes::BadRequest(Some(&e.to_string())).get_responce(),
-                }
-            }
-            "POST /seek" => {
-                check_permissions!(&[Permission::Seek], r);
-                let body = require_body!(r.body);
-                match body.parse::<u64>(){
-                    Ok(n) => {
-                        send_until_succ!(ps, PlayerMessage::Seek(n));
-                        ResponceTypes::Success(None).get_responce()
-                    },
-                    Err(e) => ResponceTypes::BadRequest(Some(&e.to_string())).get_responce(),
-                }
-            }
-            "POST /proxy" => {
-                check_permissions!(&[Permission::Download],r);
-                let body = require_body!(r.body);
-                match reqwest::Client::new().get(body).send().await{
-                    Ok(r) => {
-                        let b = r.text().await.unwrap();
-                        ResponceTypes::Success(Some(&b)).get_responce()
-                    },
-                    Err(e) => ResponceTypes::BadRequest(Some(&e.to_string())).get_responce(),
-                }
+        }
+        song_img_list
+    })
+    .await?;
+    Ok(Json(song_img_list))
+}
+
+async fn picture_list() -> ApiResult<Json<Vec<SongWithImage>>> {
+    let song_img_list = blocking(|| {
+        let mut song_img_list: Vec<SongWithImage> = vec![];
+        for song in list_songs() {
+            let image = song.get_image().map(|i| {
+                let engine = engine::general_purpose::STANDARD;
+                engine.encode(i)
+            });
+            song_img_list.push(SongWithImage { song, image });
+        }
+        song_img_list
+    })
+    .await?;
+    Ok(Json(song_img_list))
+}
+
+async fn list_duplicates() -> ApiResult<Json<Vec<DuplicateGroup>>> {
+    Ok(Json(blocking(duplicates::list_duplicates).await?))
+}
+
+#[derive(Deserialize)]
+struct MergeQuery {
+    /// Removes downloaded copies that only have the same title instead of hiding them
+    #[serde(default)]
+    confirm: bool,
+}
+
+async fn merge_duplicates(Query(q): Query<MergeQuery>, body: String) -> ApiResult<()> {
+    let body = require_body(body)?;
+    blocking(move || {
+        for line in body.lines() {
+            duplicates::merge_into(line.trim(), q.confirm).map_err(ApiError::Conflict)?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+async fn hide_duplicates(body: String) -> ApiResult<()> {
+    let body = require_body(body)?;
+    let ids: Vec<String> = body.lines().map(|l| l.trim().to_string()).collect();
+    duplicates::hide(&ids).map_err(ApiError::Internal)
+}
+
+async fn play(State(r): State<Remote>) -> ApiResult<()> {
+    send_until_succ!(r.ps, PlayerMessage::Play);
+    Ok(())
+}
+
+async fn pause(State(r): State<Remote>) -> ApiResult<()> {
+    send_until_succ!(r.ps, PlayerMessage::Pause);
+    Ok(())
+}
+
+async fn skip(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let body = require_body(body)?;
+    let mut l = vec![];
+    for line in body.lines() {
+        if let Ok(n) = line.parse::<usize>() {
+            l.push(n)
+        }
+    }
+    send_until_succ!(r.ps, PlayerMessage::Skip(l.clone().into()));
+    Ok(())
+}
+
+async fn reorder(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let body = require_body(body)?;
+    for line in body.lines() {
+        if let Some((f, t)) = line.split_once(' ') {
+            if let (Ok(f), Ok(t)) = (f.parse::<usize>(), t.parse::<usize>()) {
+                send_until_succ!(r.ps, PlayerMessage::ReOrder(f, t))
             }
-            _ if r.method.as_str().starts_with("OPTIONS") => "HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Methods: POST, GET, OPTIONS\r\nAccess-Control-Allow-Headers: Key\r\nAccess-Control-Allow-Origin: *\r\n\r\n".to_string(),
-            _ => ResponceTypes::NotFound.get_responce(),
         }
     }
+    Ok(())
+}
+
+async fn add(State(r): State<Remote>, auth: Auth, body: String) -> ApiResult<()> {
+    let body = require_body(body)?;
+    // The library is listed once for every line of the body
+    let songs: Vec<Song> = blocking(move || {
+        let library = list_songs();
+        body.lines()
+            .filter_map(|line| Song::find_in(&library, line).cloned())
+            .collect()
+    })
+    .await?;
+    if auth.is_limited() {
+        let ids = songs.iter().map(|s| s.id.as_str()).collect_vec();
+        r.add_to_queue(&auth.label, &ids)?;
+    }
+    for song in songs {
+        send_until_succ!(r.ps, PlayerMessage::Add(song.clone()));
+    }
+    Ok(())
+}
+
+/// Counts `songs` downloads towards the daily quota of the key
+fn take_downloads(r: &Remote, auth: &Auth, songs: usize) -> ApiResult<()> {
+    if !auth.is_limited() {
+        return Ok(());
+    }
+    r.limits
+        .take_downloads(&auth.label, songs as u32)
+        .map_err(ApiError::TooManyRequests)
+}
+
+/// Queues a download of every url in the body, one a line, and returns the ids of the jobs
+fn enqueue_downloads(r: &Remote, auth: &Auth, body: String) -> ApiResult<Vec<JobId>> {
+    let body = require_body(body)?;
+    let urls = body.lines().map(str::trim).filter(|l| !l.is_empty());
+    if let Some(url) = urls.clone().find(|u| downloader::is_playlist_url(u)) {
+        return Err(ApiError::Unprocessable(format!(
+            "{} is a playlist, download it with /download/playlist",
+            url
+        )));
+    }
+    take_downloads(r, auth, urls.clone().count())?;
+    Ok(urls
+        .map(|url| downloads::enqueue(url.to_string(), Some(auth.label.clone())))
+        .collect())
+}
+
+async fn download(
+    State(r): State<Remote>,
+    auth: Auth,
+    body: String,
+) -> ApiResult<(StatusCode, Json<Vec<JobId>>)> {
+    let ids = enqueue_downloads(&r, &auth, body)?;
+    Ok((StatusCode::ACCEPTED, Json(ids)))
+}
+
+async fn download_add(
+    State(r): State<Remote>,
+    auth: Auth,
+    body: String,
+) -> ApiResult<(StatusCode, Json<Vec<JobId>>)> {
+    let ids = enqueue_downloads(&r, &auth, body)?;
+    for id in &ids {
+        tokio::spawn(add_when_downloaded(*id, r.clone(), auth.clone()));
+    }
+    Ok((StatusCode::ACCEPTED, Json(ids)))
+}
+
+/// Adds the song of the job to the queue once it has been downloaded, if the key still has room
+/// in the queue. A job that is retried after failing isn't waited for again.
+async fn add_when_downloaded(id: JobId, r: Remote, auth: Auth) {
+    let Some(song) = downloads::wait(id).await.and_then(|j| j.song) else {
+        return;
+    };
+    if auth.is_limited() && r.add_to_queue(&auth.label, &[&song.id]).is_err() {
+        println!(
+            "{} was downloaded but {} has no room in the queue",
+            song.name, auth.label
+        );
+        return;
+    }
+    send_until_succ!(r.ps, PlayerMessage::Add(song.clone()));
+}
+
+/// A playlist or a channel to download
+#[derive(Deserialize)]
+struct PlaylistDownload {
+    url: String,
+    /// Saves the songs as a playlist with the name once they have been downloaded
+    #[serde(default)]
+    save_as: Option<String>,
+    /// Adds the songs to the queue in the order of the playlist once they have been downloaded
+    #[serde(default)]
+    enqueue: bool,
+}
+
+/// Downloads the videos of a playlist that aren't in the library yet, only they count towards
+/// the quota
+async fn download_playlist(
+    State(r): State<Remote>,
+    auth: Auth,
+    body: String,
+) -> ApiResult<(StatusCode, Json<Playlist>)> {
+    let request: PlaylistDownload = serde_json::from_str(&require_body(body)?)?;
+    if let Some(name) = &request.save_as {
+        // Saving a playlist writes into the library
+        if !auth::has_permissions(&auth.permissions, &[Permission::Library]) {
+            return Err(ApiError::Forbidden);
+        }
+        playlists::check_name(name).map_err(ApiError::Unprocessable)?;
+    }
+    let mut playlist = Playlist::read(&request.url)
+        .await
+        .map_err(ApiError::Unprocessable)?;
+    take_downloads(&r, &auth, playlist.missing())?;
+    playlist.download(Some(auth.label.clone()));
+    if request.save_as.is_some() || request.enqueue {
+        tokio::spawn(finish_playlist(
+            playlist.clone(),
+            request,
+            r.clone(),
+            auth.clone(),
+        ));
+    }
+    Ok((StatusCode::ACCEPTED, Json(playlist)))
+}
+
+/// Saves and queues the songs of the playlist once its downloads are done
+async fn finish_playlist(playlist: Playlist, request: PlaylistDownload, r: Remote, auth: Auth) {
+    let songs = playlist.songs().await;
+    if let Some(name) = &request.save_as {
+        match playlists::save(name, &songs) {
+            Ok(path) => println!("Saved the playlist {}", path.display()),
+            Err(e) => println!("Failed to save the playlist {}: {}", name, e),
+        }
+    }
+    if !request.enqueue {
+        return;
+    }
+    let ids = songs.iter().map(|s| s.id.as_str()).collect_vec();
+    if auth.is_limited() && r.add_to_queue(&auth.label, &ids).is_err() {
+        println!(
+            "The playlist was downloaded but {} has no room in the queue",
+            auth.label
+        );
+        return;
+    }
+    for song in &songs {
+        send_until_succ!(r.ps, PlayerMessage::Add(song.clone()));
+    }
+}
+
+/// The job if the key may see it. Keys that aren't limited see every job, others only their own.
+fn visible_job(auth: &Auth, id: &str) -> ApiResult<Job> {
+    let not_found = || ApiError::NotFound(format!("No download with id {}", id));
+    let job = id
+        .parse()
+        .ok()
+        .and_then(downloads::get)
+        .ok_or_else(not_found)?;
+    match auth.sees_job(&job) {
+        true => Ok(job),
+        false => Err(not_found()),
+    }
+}
+
+fn job_error(e: JobError) -> ApiError {
+    match e {
+        JobError::NotFound => ApiError::NotFound(e.to_string()),
+        JobError::WrongState(_) => ApiError::Conflict(e.to_string()),
+    }
+}
+
+async fn list_downloads(auth: Auth) -> Json<Vec<Job>> {
+    Json(
+        downloads::list()
+            .into_iter()
+            .filter(|j| auth.sees_job(j))
+            .collect(),
+    )
+}
+
+async fn cancel_download(auth: Auth, Path(id): Path<String>) -> ApiResult<Json<Job>> {
+    let job = visible_job(&auth, &id)?;
+    downloads::cancel(job.id).map(Json).map_err(job_error)
+}
+
+/// Starts a failed or cancelled job again, which counts as another download
+async fn retry_download(
+    State(r): State<Remote>,
+    auth: Auth,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Job>> {
+    let job = visible_job(&auth, &id)?;
+    if !matches!(job.state, JobState::Failed | JobState::Cancelled) {
+        return Err(job_error(JobError::WrongState(job.state)));
+    }
+    take_downloads(&r, &auth, 1)?;
+    downloads::retry(job.id).map(Json).map_err(job_error)
+}
+
+async fn set_volume(State(r): State<Remote>, auth: Auth, body: String) -> ApiResult<()> {
+    let (min, max) = auth::volume_range(&auth.permissions).ok_or(ApiError::Forbidden)?;
+    let target_volume: f32 = parse_body(body)?;
+    if target_volume < min || max < target_volume {
+        return Err(ApiError::Forbidden);
+    }
+    send_until_succ!(r.ps, PlayerMessage::Volume(target_volume));
+    Ok(())
+}
 
-    async fn download_and_add(url: String, ps: Sender<PlayerMessage>) -> Result<(), String> {
-        let song = downloader::download_dlp(url).await?;
-        Ok(send_until_succ!(ps, PlayerMessage::Add(song.clone())))
+async fn speed(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let n: f32 = parse_body(body)?;
+    if !PlayerMessage::SPEED_RANGE.contains(&n) {
+        return Err(ApiError::Unprocessable(format!(
+            "The speed has to be between {} and {}",
+            PlayerMessage::SPEED_RANGE.start(),
+            PlayerMessage::SPEED_RANGE.end()
+        )));
     }
+    send_until_succ!(r.ps, PlayerMessage::Speed(n));
+    Ok(())
+}
 
-    async fn parse_request(mut buf: BufReader<&mut TcpStream>) -> Result<Request, String> {
-        let mut headers: HashMap<String, String> = HashMap::new();
-        let mut m = String::new();
-        buf.read_line(&mut m).await.map_err(|_| "Failed a read")?;
-        let (method, protocol) = Self::method_and_procol_from_line(m)?;
-        loop {
-            let mut st = String::new();
-            buf.read_line(&mut st).await.map_err(|_| "Failed a read")?;
-            if st == "\r\n" {
-                break;
-            }
-            if let Some((k, v)) = st.split_once(':') {
-                headers.insert(k.trim().to_lowercase(), v.trim().to_string());
-            }
-        }
-        let key = match headers.get("key") {
-            Some(k) => k.to_owned(),
-            None => "".to_owned(),
-        };
-        let permissions = Self::get_permissions(&key);
-
-        return match headers.get("content-length") {
-            Some(l) => {
-                let mut buffer = vec![
-                    0u8;
-                    l.parse::<usize>().map_err(|_| {
-                        "Unable to parse Content-Length".to_string()
-                    })?
-                ];
-                buf.read_exact(&mut buffer)
-                    .await
-                    .map_err(|_| "Unable to read promisec body".to_string())?;
-                let body = String::from_utf8(buffer)
-                    .map_err(|_| "Unable to parse body to a String".to_string())?;
-                Ok(Request {
-                    method,
-                    protocol,
-                    _headers: headers,
-                    body: Some(body),
-                    permissions,
-                })
-            }
-            None => Ok(Request {
-                method,
-                protocol,
-                _headers: headers,
-                body: None,
-                permissions,
-            }),
-        };
+async fn pitch(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let n: f32 = parse_body(body)?;
+    if !PlayerMessage::PITCH_RANGE.contains(&n) {
+        return Err(ApiError::Unprocessable(format!(
+            "The pitch shift has to be between {} and {} semitones",
+            PlayerMessage::PITCH_RANGE.start(),
+            PlayerMessage::PITCH_RANGE.end()
+        )));
+    }
+    send_until_succ!(r.ps, PlayerMessage::Pitch(n));
+    Ok(())
+}
+
+async fn replay_gain(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let mode = require_body(body)?
+        .parse::<ReplayGainMode>()
+        .map_err(ApiError::Unprocessable)?;
+    send_until_succ!(r.ps, PlayerMessage::ReplayGain(mode));
+    Ok(())
+}
+
+async fn devices() -> ApiResult<Json<Vec<OutputDevice>>> {
+    Ok(Json(output::list_devices()))
+}
+
+async fn effects(State(r): State<Remote>) -> ApiResult<Json<Effects>> {
+    let s = r.state.lock().unwrap();
+    Ok(Json(s.effects.clone()))
+}
+
+async fn set_effects(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let update: EffectsUpdate = serde_json::from_str(&require_body(body)?)?;
+    if let Some(preset) = update.preset {
+        send_until_succ!(r.ps, PlayerMessage::EqualizerPreset(preset));
+    }
+    if let Some(bands) = update.equalizer {
+        send_until_succ!(r.ps, PlayerMessage::Equalizer(bands.clone()));
+    }
+    if let Some(b) = update.bass_boost {
+        send_until_succ!(r.ps, PlayerMessage::BassBoost(b));
+    }
+    if let Some(m) = update.mono {
+        send_until_succ!(r.ps, PlayerMessage::Mono(m));
+    }
+    if let Some(l) = update.limiter {
+        send_until_succ!(r.ps, PlayerMessage::Limiter(l));
     }
+    Ok(())
+}
+
+async fn seek(State(r): State<Remote>, body: String) -> ApiResult<()> {
+    let n: u64 = parse_body(body)?;
+    send_until_succ!(r.ps, PlayerMessage::Seek(n));
+    Ok(())
+}
+
+/// Reads the title, description and thumbnail of a page on one of the allowed hosts
+async fn lookup(body: String) -> ApiResult<Json<Metadata>> {
+    let url = require_body(body)?;
+    let allowed = Configuration::get_conf().lookup_hosts;
+    lookup::lookup(&url, &allowed)
+        .await
+        .map(Json)
+        .map_err(|e| match e {
+            LookupError::InvalidUrl(_) => ApiError::BadRequest(e.to_string()),
+            LookupError::NotAllowed(_) => ApiError::Unprocessable(e.to_string()),
+            LookupError::Failed(_) => ApiError::BadGateway(e.to_string()),
+        })
+}
+
+async fn song_tags(Path(id): Path<String>) -> ApiResult<Json<TagEdit>> {
+    Ok(Json(tags::read_tags(&song_by_id(&id)?)))
+}
+
+async fn edit_song_tags(Path(id): Path<String>, body: String) -> ApiResult<Json<Song>> {
+    let edit: TagEdit = serde_json::from_str(&require_body(body)?)?;
+    let song = song_by_id(&id)?;
+    tags::edit_tags(&song, &edit)
+        .map(Json)
+        .map_err(ApiError::Unprocessable)
+}
+
+async fn song_file(Path(id): Path<String>, headers: HeaderMap) -> ApiResult<Response> {
+    let song = song_by_id(&id)?;
+    song_file::serve(&headers, &song).await.map_err(|e| {
+        println!("Failed to send {}: {}", song.name, e);
+        ApiError::Internal(e.to_string())
+    })
+}
+
+async fn stream_audio(State(r): State<Remote>, headers: HeaderMap) -> ApiResult<Response> {
+    let icy = headers
+        .get("icy-metadata")
+        .is_some_and(|v| v.as_bytes() == b"1");
+    Ok(stream::stream_audio(icy, r.state))
+}
+
+async fn event_stream(State(r): State<Remote>, auth: Auth) -> Response {
+    sse::stream_events(&r.state, move |event| auth.sees_event(event))
+}
 
-    fn method_and_procol_from_line(line: String) -> Result<(String, String), String> {
-        let (f, s, t) = line
-            .split(' ')
-            .collect_tuple()
-            .ok_or("Failed to process method")?;
-        Ok((format!("{} {}", f, s), t.to_string()))
+impl AddressListener {
+    async fn new(
+        address: ListenAddress,
+        stop_handle: Arc<Notify>,
+        ps: Sender<PlayerMessage>,
+        state: Arc<Mutex<PlayerState>>,
+        keys: Arc<KeyStore>,
+        sessions: Arc<SessionStore>,
+        limits: Arc<Limiter>,
+    ) -> Result<AddressListener, String> {
+        let adrl = AddressListener {
+            address,
+            stop_handle,
+            ps,
+            state,
+            keys,
+            sessions,
+            limits,
+        };
+        match adrl.start().await {
+            Ok(_) => Ok(adrl),
+            Err(e) => Err(format!("Unable to create listener for {e}")),
+        }
     }
 
-    fn get_permissions(key: &str) -> Vec<Permission> {
-        let conf = Configuration::get_conf();
-        for k in conf.keys {
-            if k.key == key {
-                return k.permissions;
+    async fn start(&self) -> Result<(), io::Error> {
+        let listener: StdTcpListener = TcpListener::bind(self.address.address())
+            .await?
+            .into_std()?;
+        let cors = Configuration::get_conf().cors.parse();
+        let app = router(
+            Remote {
+                ps: self.ps.clone(),
+                state: self.state.clone(),
+                keys: self.keys.clone(),
+                sessions: self.sessions.clone(),
+                limits: self.limits.clone(),
+                https: self.address.is_https(),
+                cross_site: false,
+            },
+            &cors,
+        )
+        .into_make_service_with_connect_info::<SocketAddr>();
+        let stop = self.stop_handle.clone();
+        match self.address.tls_files() {
+            None => {
+                let server = Server::from_tcp(listener)
+                    .map_err(io::Error::other)?
+                    .http1_keepalive(true)
+                    .http1_header_read_timeout(HEADER_TIMEOUT)
+                    .serve(app)
+                    // Requests that are being answered are finished after the listener is stopped
+                    .with_graceful_shutdown(async move { stop.notified().await });
+                tokio::spawn(async move {
+                    if let Err(e) = server.await {
+                        println!("The remote server failed: {}", e);
+                    }
+                });
+            }
+            Some((certificate, key)) => {
+                let config = tls::load_config(self.address.address(), &certificate, &key).await?;
+                let handle = axum_server::Handle::new();
+                let server = axum_server::from_tcp_rustls(listener, config)
+                    .handle(handle.clone())
+                    .http_config(
+                        HttpConfig::new()
+                            .http1_keep_alive(true)
+                            .http1_header_read_timeout(HEADER_TIMEOUT)
+                            .build(),
+                    )
+                    .serve(app);
+                tokio::spawn(async move {
+                    stop.notified().await;
+                    handle.graceful_shutdown(None);
+                });
+                tokio::spawn(async move {
+                    if let Err(e) = server.await {
+                        println!("The remote server failed: {}", e);
+                    }
+                });
             }
         }
-        vec![]
+        Ok(())
     }
 
     fn stop(&self) {
-        self.stop_handle.store(true, SeqCst);
+        self.stop_handle.notify_one();
     }
 }
 
@@ -465,12 +1311,16 @@ impl RemoteHandler {
     pub fn list_listeners(&self) -> Vec<&str> {
         self.address_listeners
             .iter()
-            .map(|a| a.address.as_str())
+            .map(|a| a.address.address())
             .collect()
     }
 
     pub fn stop_listener(&self, addrs: String) -> Result<(), String> {
-        match self.address_listeners.iter().find(|a| a.address == addrs) {
+        match self
+            .address_listeners
+            .iter()
+            .find(|a| a.address.address() == addrs)
+        {
             Some(listener) => {
                 listener.stop();
                 Ok(())
@@ -479,12 +1329,16 @@ impl RemoteHandler {
         }
     }
 
-    pub async fn new_listener(&mut self, addrs: String) -> Result<(), String> {
+    /// Starts listening on the address, plain strings are listened on over HTTP
+    pub async fn new_listener(&mut self, addrs: impl Into<ListenAddress>) -> Result<(), String> {
         let a = AddressListener::new(
-            addrs,
-            Arc::new(AtomicBool::new(false)),
+            addrs.into(),
+            Arc::new(Notify::new()),
             self.ps.clone(),
             self.state.clone(),
+            self.keys.clone(),
+            self.sessions.clone(),
+            self.limits.clone(),
         )
         .await?;
         self.address_listeners.push(a);
@@ -494,25 +1348,55 @@ impl RemoteHandler {
         RemoteHandler {
             ps,
             state,
+            keys: Arc::new(KeyStore::load()),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::load()),
             address_listeners: vec![],
         }
     }
+
+    /// The keys of the remote, changes apply to every listener right away. Anything else that
+    /// mints or removes keys has to share these, so neither overwrites the other's keys.
+    pub fn keys(&self) -> &Arc<KeyStore> {
+        &self.keys
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
         collections::VecDeque,
-        sync::{atomic::AtomicBool, Arc, Mutex},
+        net::SocketAddr,
+        sync::{Arc, Mutex},
         time::Duration,
     };
 
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{HeaderMap, Request, StatusCode, Uri},
+    };
     use serial_test::serial;
-    use tokio::sync::mpsc::channel;
+    use tokio::sync::{mpsc::channel, Notify};
+    use tower::ServiceExt;
 
-    use crate::player_state::PlayerState;
+    use crate::{
+        downloads::{self, Job, JobState},
+        events::Event,
+        loudness::ReplayGainMode,
+        player_state::PlayerState,
+    };
 
-    use super::AddressListener;
+    use super::{
+        auth::{Key, KeyStore, Permission},
+        cors::CorsPolicy,
+        credentials,
+        limits::{Bucket, Limiter, RateLimits},
+        query_param, router, routes,
+        session::SessionStore,
+        tls::ListenAddress,
+        AddressListener, Auth, Credentials, Remote,
+    };
 
     fn mock_status() -> Arc<Mutex<PlayerState>> {
         Arc::new(Mutex::new(PlayerState {
@@ -520,7 +1404,13 @@ mod tests {
             queue: VecDeque::new(),
             volume: 1.0,
             speed: 1.0,
+            pitch: 0.0,
             paused: true,
+            replay_gain: ReplayGainMode::Track,
+            effects: Default::default(),
+            output_device: None,
+            crossfade: 0.0,
+            error: None,
             total_duration: None,
             elapsed_duration: None,
         }))
@@ -529,10 +1419,13 @@ mod tests {
     async fn create_valid_listener() -> AddressListener {
         let (s, _) = channel(32);
         let a = AddressListener::new(
-            "127.0.0.1:8000".to_string(),
-            Arc::new(AtomicBool::new(false)),
+            "127.0.0.1:8000".to_string().into(),
+            Arc::new(Notify::new()),
             s,
             mock_status(),
+            Arc::new(KeyStore::new(vec![])),
+            Arc::new(SessionStore::default()),
+            Arc::new(Limiter::new(RateLimits::default())),
         )
         .await;
         assert!(a.is_ok());
@@ -541,7 +1434,7 @@ mod tests {
     #[tokio::test]
     #[serial]
     async fn test_listener_valid_ip() {
-        create_valid_listener().await;
+        create_valid_listener().await.stop();
     }
 
     #[tokio::test]
@@ -549,18 +1442,24 @@ mod tests {
     async fn test_listener_invalid_ip() {
         let (s, _) = channel(32);
         let adrl = AddressListener::new(
-            "slakhfjaskghak".to_string(),
-            Arc::new(AtomicBool::new(false)),
+            "slakhfjaskghak".to_string().into(),
+            Arc::new(Notify::new()),
             s.clone(),
             mock_status(),
+            Arc::new(KeyStore::new(vec![])),
+            Arc::new(SessionStore::default()),
+            Arc::new(Limiter::new(RateLimits::default())),
         )
         .await;
         assert!(adrl.is_err());
         let adrl = AddressListener::new(
-            "195.251.52.14:90".to_string(),
-            Arc::new(AtomicBool::new(false)),
+            "195.251.52.14:90".to_string().into(),
+            Arc::new(Notify::new()),
             s,
             mock_status(),
+            Arc::new(KeyStore::new(vec![])),
+            Arc::new(SessionStore::default()),
+            Arc::new(Limiter::new(RateLimits::default())),
         )
         .await;
         assert!(adrl.is_err())
@@ -570,10 +1469,69 @@ mod tests {
     #[serial]
     async fn test_response() {
         let adrl = create_valid_listener().await;
-        let ip = format!("http://{}", adrl.address);
+        let ip = format!("http://{}", adrl.address.address());
         let resp = reqwest::get(ip).await;
         assert!(resp.is_ok());
         resp.unwrap();
+        adrl.stop();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_https_listener() {
+        let dir = std::env::temp_dir().join(format!("ssmp-https-{}", std::process::id()));
+        let (s, _) = channel(32);
+        let adrl = AddressListener::new(
+            ListenAddress::Https {
+                address: "127.0.0.1:8443".to_string(),
+                certificate: Some(dir.join("cert.pem")),
+                key: Some(dir.join("key.pem")),
+            },
+            Arc::new(Notify::new()),
+            s,
+            mock_status(),
+            Arc::new(KeyStore::new(vec![])),
+            Arc::new(SessionStore::default()),
+            Arc::new(Limiter::new(RateLimits::default())),
+        )
+        .await
+        .unwrap();
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let resp = client
+            .get(format!("https://{}/list", adrl.address.address()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+        assert!(
+            reqwest::get(format!("http://{}/list", adrl.address.address()))
+                .await
+                .is_err()
+        );
+        adrl.stop();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_keep_alive() {
+        let adrl = create_valid_listener().await;
+        let client = reqwest::Client::new();
+        let ip = format!("http://{}/list", adrl.address.address());
+        for _ in 0..3 {
+            let resp = client.get(&ip).send().await.unwrap();
+            assert_eq!(resp.status(), 401);
+        }
+        let resp = client
+            .post(format!("http://{}/nothing", adrl.address.address()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+        adrl.stop();
     }
 
     #[tokio::test]
@@ -581,17 +1539,670 @@ mod tests {
     async fn test_stopping() {
         let a = create_valid_listener().await;
         a.stop();
-        let ip = format!("http://{}", a.address);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let ip = format!("http://{}", a.address.address());
         let resp = reqwest::get(ip).await;
         assert!(resp.is_err())
     }
+    fn all_but(required: &Permission) -> Vec<Permission> {
+        vec![
+            Permission::VolumeControl((0.0, 10.0)),
+            Permission::Seek,
+            Permission::Add,
+            Permission::Download,
+            Permission::PlayPause,
+            Permission::Info,
+            Permission::Library,
+            Permission::Effects,
+        ]
+        .into_iter()
+        .filter(|p| !p.grants(required))
+        .collect()
+    }
+
+    /// Sends an empty request with the key "test", which has the given permissions
+    async fn status(method: &str, uri: &str, permissions: Option<Vec<Permission>>) -> StatusCode {
+        let (ps, _messages) = channel(32);
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(
+                permissions
+                    .map(|p| vec![Key::new("test", "test", p)])
+                    .unwrap_or_default(),
+            )),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("key", "test")
+            .body(Body::empty())
+            .unwrap();
+        router(remote, &CorsPolicy::default().parse())
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_route_permissions() {
+        for route in routes() {
+            // Public routes check the credentials themselves
+            let Some(required) = route.required else {
+                continue;
+            };
+            let method = route.method.as_str();
+            let uri = route
+                .path
+                .replace(":id", "missing")
+                .replace(":label", "missing");
+            let uri = uri.as_str();
+            let denied = [
+                (None, StatusCode::UNAUTHORIZED),
+                (Some(vec![]), StatusCode::FORBIDDEN),
+                (Some(all_but(&required)), StatusCode::FORBIDDEN),
+            ];
+            for (held, expected) in denied {
+                assert_eq!(status(method, uri, held).await, expected, "{}", uri);
+            }
+            // The stream never ends, so it isn't opened
+            if uri == "/stream" {
+                continue;
+            }
+            for held in [vec![required], vec![Permission::All]] {
+                let status = status(method, uri, Some(held)).await;
+                assert!(
+                    status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN,
+                    "{} {}",
+                    uri,
+                    status
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_volume_range() {
+        let held = vec![Permission::VolumeControl((0.0, 0.5))];
+        let (ps, _messages) = channel(32);
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(vec![Key::new("test", "test", held)])),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        for (volume, expected) in [("0.4", StatusCode::OK), ("0.6", StatusCode::FORBIDDEN)] {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/volume")
+                .header("key", "test")
+                .body(Body::from(volume))
+                .unwrap();
+            let status = router(remote.clone(), &CorsPolicy::default().parse())
+                .oneshot(request)
+                .await
+                .unwrap()
+                .status();
+            assert_eq!(status, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_speed_range() {
+        let (ps, _messages) = channel(32);
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(vec![Key::new(
+                "test",
+                "test",
+                vec![Permission::Seek],
+            )])),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let cases = [
+            ("/speed", "1.5", StatusCode::OK),
+            ("/speed", "0", StatusCode::UNPROCESSABLE_ENTITY),
+            ("/speed", "-1", StatusCode::UNPROCESSABLE_ENTITY),
+            ("/speed", "NaN", StatusCode::UNPROCESSABLE_ENTITY),
+            ("/pitch", "-3", StatusCode::OK),
+            ("/pitch", "inf", StatusCode::UNPROCESSABLE_ENTITY),
+        ];
+        for (uri, body, expected) in cases {
+            let request = Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("key", "test")
+                .body(Body::from(body))
+                .unwrap();
+            let status = router(remote.clone(), &CorsPolicy::default().parse())
+                .oneshot(request)
+                .await
+                .unwrap()
+                .status();
+            assert_eq!(status, expected, "{} {}", uri, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_management() {
+        let (ps, _messages) = channel(32);
+        let admin = Key::new("admin", "admin", vec![Permission::All]);
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(vec![admin])),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let send = |method: &str, uri: &str, key: &str, body: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("key", key)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            router(remote.clone(), &CorsPolicy::default().parse()).oneshot(request)
+        };
+        let new = r#"{"label": "guest", "permissions": ["Info"], "expires_in": 3600}"#;
+        let responce = send("POST", "/keys", "admin", new).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(responce.into_body()).await.unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let guest = created["key"].as_str().unwrap();
+
+        let responce = send("GET", "/list", guest, "").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let responce = send("POST", "/keys", "admin", new).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::CONFLICT);
+        let responce = send("POST", "/keys", guest, new).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::FORBIDDEN);
+
+        let responce = send("DELETE", "/keys/guest", "admin", "").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let responce = send("GET", "/list", guest, "").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_invite() {
+        let (ps, _messages) = channel(32);
+        let admin = Key::new("admin", "admin", vec![Permission::All]);
+        let keys = Arc::new(KeyStore::new(vec![admin]));
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: keys.clone(),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let send = |uri: &str| {
+            let request = Request::builder()
+                .uri(uri)
+                .header("key", "admin")
+                .header("host", "192.168.1.5:8000")
+                .body(Body::empty())
+                .unwrap();
+            router(remote.clone(), &CorsPolicy::default().parse()).oneshot(request)
+        };
+        let responce = send("/invite.png?label=phone&permissions=Info,PlayPause&minutes=5")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        assert_eq!(responce.headers()["content-type"], "image/png");
+        assert_eq!(responce.headers()["x-invite-label"], "phone");
+        let phone = keys
+            .list()
+            .into_iter()
+            .find(|k| k.label == "phone")
+            .unwrap();
+        assert_eq!(
+            phone.permissions,
+            vec![Permission::Info, Permission::PlayPause]
+        );
+
+        let responce = send("/invite.png?permissions=Everything").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let responce = send("/invite.png?label=phone").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::CONFLICT);
+        let responce = send("/invite.png?minutes=18446744073709551615")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // The page the link leads to is served without a key
+        let request = Request::builder()
+            .uri("/client")
+            .body(Body::empty())
+            .unwrap();
+        let responce = router(remote.clone(), &CorsPolicy::default().parse())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        assert!(responce.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let (ps, _messages) = channel(32);
+        let guest = Key::new("guest", "guest", vec![Permission::Info, Permission::Add]);
+        let keys = Arc::new(KeyStore::new(vec![guest]));
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: keys.clone(),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let send = |method: &str, uri: &str, header: (&str, String), body: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header.0, header.1)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            router(remote.clone(), &CorsPolicy::default().parse()).oneshot(request)
+        };
+        let none = || ("x-none", "".to_string());
+
+        let wrong = r#"{"key": "wrong"}"#;
+        let responce = send("POST", "/login", none(), wrong).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+        let too_much = r#"{"key": "guest", "permissions": ["Seek"]}"#;
+        let responce = send("POST", "/login", none(), too_much).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::FORBIDDEN);
+
+        let login = r#"{"key": "guest", "permissions": ["Info"]}"#;
+        let responce = send("POST", "/login", none(), login).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let cookie = responce.headers()["set-cookie"].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let body = hyper::body::to_bytes(responce.into_body()).await.unwrap();
+        let logged_in: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let bearer = format!("Bearer {}", logged_in["token"].as_str().unwrap());
+
+        let responce = send("GET", "/list", ("authorization", bearer.clone()), "")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let responce = send("GET", "/list", ("cookie", cookie.clone()), "")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        // The session only has the permissions asked for
+        let responce = send("POST", "/add", ("authorization", bearer.clone()), "x")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::FORBIDDEN);
+
+        let responce = send("POST", "/logout", ("cookie", cookie.clone()), "")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let responce = send("GET", "/list", ("authorization", bearer), "")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+
+        // Sessions end with their key
+        let responce = send("POST", "/login", none(), login).await.unwrap();
+        let body = hyper::body::to_bytes(responce.into_body()).await.unwrap();
+        let logged_in: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let bearer = format!("Bearer {}", logged_in["token"].as_str().unwrap());
+        keys.remove("guest").unwrap();
+        let responce = send("GET", "/list", ("authorization", bearer), "")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let (ps, _messages) = channel(32);
+        let guest = Key::new("guest", "guest", vec![Permission::Add]);
+        let admin = Key::new("admin", "admin", vec![Permission::All]);
+        let limits = RateLimits {
+            add: Some(Bucket {
+                capacity: 1.0,
+                per_minute: 1.0,
+            }),
+            ..Default::default()
+        };
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(vec![guest, admin])),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(limits)),
+            https: false,
+            cross_site: false,
+        };
+        let send = |method: &str, uri: &str, key: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("key", key)
+                .body(Body::from("missing"))
+                .unwrap();
+            router(remote.clone(), &CorsPolicy::default().parse()).oneshot(request)
+        };
+        let responce = send("POST", "/add", "guest").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let responce = send("POST", "/add", "guest").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(responce.headers().contains_key("retry-after"));
+        // Keys with every permission aren't limited
+        for _ in 0..3 {
+            let responce = send("POST", "/add", "admin").await.unwrap();
+            assert_eq!(responce.status(), StatusCode::OK);
+        }
+
+        let responce = send("GET", "/me", "guest").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(responce.into_body()).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["label"], "guest");
+        assert_eq!(status["limits"]["buckets"][0]["remaining"], 0);
+        let responce = send("GET", "/me", "nobody").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+
+        // An address that keeps sending wrong keys can't try any key for a while
+        let from = |key: &str| {
+            let request = Request::builder()
+                .uri("/me")
+                .header("key", key)
+                .extension(ConnectInfo(SocketAddr::from(([192, 168, 1, 5], 4000))))
+                .body(Body::empty())
+                .unwrap();
+            router(remote.clone(), &CorsPolicy::default().parse()).oneshot(request)
+        };
+        for _ in 0..10 {
+            let responce = from("nobody").await.unwrap();
+            assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+        }
+        let responce = from("guest").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Requests without a key, like a browser with an old session, aren't counted
+        let without_key = |authorization: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/list")
+                .extension(ConnectInfo(SocketAddr::from(([192, 168, 1, 6], 4000))));
+            if let Some(a) = authorization {
+                request = request.header("authorization", a);
+            }
+            router(remote.clone(), &CorsPolicy::default().parse())
+                .oneshot(request.body(Body::empty()).unwrap())
+        };
+        for authorization in [None, Some("Bearer expired")].repeat(10) {
+            let responce = without_key(authorization).await.unwrap();
+            assert_eq!(responce.status(), StatusCode::UNAUTHORIZED);
+        }
+        let request = Request::builder()
+            .uri("/me")
+            .header("key", "guest")
+            .extension(ConnectInfo(SocketAddr::from(([192, 168, 1, 6], 4000))))
+            .body(Body::empty())
+            .unwrap();
+        let responce = router(remote.clone(), &CorsPolicy::default().parse())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_downloads() {
+        let (ps, _messages) = channel(32);
+        let keys = ["guest", "other"].map(|l| Key::new(l, l, vec![Permission::Download]));
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(keys.to_vec())),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let send = |method: &str, uri: &str, key: &str, body: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("key", key)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            router(remote.clone(), &CorsPolicy::default().parse()).oneshot(request)
+        };
+        let json = |responce: axum::response::Response| async {
+            let body = hyper::body::to_bytes(responce.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        // Nothing listens on the port, so the download fails right away
+        let responce = send("POST", "/download", "guest", "http://127.0.0.1:9/a\n\n")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::ACCEPTED);
+        let ids = json(responce).await;
+        let id = ids[0].as_u64().unwrap();
+        assert_eq!(ids.as_array().unwrap().len(), 1);
+        assert!(downloads::wait(id).await.is_some());
+
+        let listed = json(send("GET", "/downloads", "guest", "").await.unwrap()).await;
+        assert!(listed.as_array().unwrap().iter().any(|j| j["id"] == id));
+        // Other keys don't see the jobs of the guest
+        let listed = json(send("GET", "/downloads", "other", "").await.unwrap()).await;
+        assert!(listed.as_array().unwrap().iter().all(|j| j["id"] != id));
+        let uri = format!("/downloads/{}/retry", id);
+        let responce = send("POST", &uri, "other", "").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::NOT_FOUND);
+
+        let responce = send("POST", &uri, "guest", "").await.unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        let state = json(responce).await["state"].clone();
+        assert!(state == "queued" || state == "running", "{}", state);
+
+        // Playlists have a route of their own
+        let playlist = "https://www.youtube.com/playlist?list=PL1";
+        let responce = send("POST", "/download", "guest", playlist).await.unwrap();
+        assert_eq!(responce.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        // Saving one needs the library permission
+        let body = serde_json::json!({"url": playlist, "save_as": "a"}).to_string();
+        let responce = send("POST", "/download/playlist", "guest", &body)
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (ps, _messages) = channel(32);
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(vec![Key::new(
+                "test",
+                "test",
+                vec![Permission::Info],
+            )])),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let request = Request::builder()
+            .uri("/events")
+            .header("key", "test")
+            .body(Body::empty())
+            .unwrap();
+        let responce = router(remote, &CorsPolicy::default().parse())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::OK);
+        assert_eq!(responce.headers()["content-type"], "text/event-stream");
+        // The stream starts with the current state
+        let mut body = responce.into_body();
+        let chunk = hyper::body::HttpBody::data(&mut body)
+            .await
+            .unwrap()
+            .unwrap();
+        let chunk = String::from_utf8_lossy(&chunk);
+        assert!(chunk.starts_with("event:song_changed\n"), "{}", chunk);
+        assert!(chunk.contains(r#""type":"song_changed""#));
+    }
+
+    #[test]
+    fn test_download_events() {
+        let job = |owner: &str| {
+            Event::Download(Job {
+                id: 1,
+                url: "https://youtu.be/1".to_string(),
+                state: JobState::Running,
+                progress: Default::default(),
+                owner: Some(owner.to_string()),
+                attempts: 1,
+                song: None,
+                error: None,
+            })
+        };
+        let auth = |permissions| Auth {
+            label: "guest".to_string(),
+            permissions,
+        };
+        let downloader = auth(vec![Permission::Info, Permission::Download]);
+        assert!(downloader.sees_event(&job("guest")));
+        assert!(!downloader.sees_event(&job("other")));
+        assert!(!auth(vec![Permission::Info]).sees_event(&job("guest")));
+        assert!(auth(vec![Permission::All]).sees_event(&job("other")));
+        assert!(auth(vec![Permission::Info]).sees_event(&Event::Paused));
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let (ps, _messages) = channel(32);
+        let remote = Remote {
+            ps,
+            state: mock_status(),
+            keys: Arc::new(KeyStore::new(vec![Key::new("guest", "guest", vec![])])),
+            sessions: Arc::new(SessionStore::default()),
+            limits: Arc::new(Limiter::new(RateLimits::default())),
+            https: false,
+            cross_site: false,
+        };
+        let cors = CorsPolicy {
+            origins: vec!["https://player.example.com".to_string()],
+            credentials: true,
+            ..Default::default()
+        }
+        .parse();
+        let preflight = |uri: &str, origin: &str| {
+            let request = Request::builder()
+                .method("OPTIONS")
+                .uri(uri)
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .body(Body::empty())
+                .unwrap();
+            router(remote.clone(), &cors).oneshot(request)
+        };
+        let responce = preflight("/effects", "https://player.example.com")
+            .await
+            .unwrap();
+        let headers = responce.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://player.example.com"
+        );
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        // Only the methods of the route are allowed
+        let methods = headers["access-control-allow-methods"].to_str().unwrap();
+        assert!(methods.contains("GET") && methods.contains("POST"));
+        assert!(!methods.contains("DELETE"));
+
+        let responce = preflight("/effects", "https://evil.example.com")
+            .await
+            .unwrap();
+        assert!(!responce
+            .headers()
+            .contains_key("access-control-allow-origin"));
+        let responce = preflight("/nothing", "https://player.example.com")
+            .await
+            .unwrap();
+        assert_eq!(responce.status(), StatusCode::NOT_FOUND);
+        assert!(!responce
+            .headers()
+            .contains_key("access-control-allow-origin"));
+
+        // The listed origin is another site, so it only gets the cookie over HTTPS
+        let login = |remote: Remote| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/login")
+                .body(Body::from(r#"{"key": "guest"}"#))
+                .unwrap();
+            router(remote, &cors).oneshot(request)
+        };
+        let responce = login(remote.clone()).await.unwrap();
+        let cookie = responce.headers()["set-cookie"].to_str().unwrap();
+        assert!(cookie.contains("SameSite=Strict") && !cookie.contains("Secure"));
+        let https = Remote {
+            https: true,
+            ..remote.clone()
+        };
+        let responce = login(https).await.unwrap();
+        let cookie = responce.headers()["set-cookie"].to_str().unwrap();
+        assert!(cookie.contains("SameSite=None") && cookie.contains("Secure"));
+    }
+
     #[test]
-    fn test_method_line_correct() {
+    fn test_credentials() {
+        let uri: Uri = "/stream?key=abc".parse().unwrap();
+        assert_eq!(credentials(&HeaderMap::new(), &uri), Credentials::None);
+        let uri: Uri = "/stream?icy=1&token=a.1%2Eb".parse().unwrap();
+        assert_eq!(
+            credentials(&HeaderMap::new(), &uri),
+            Credentials::QueryToken("a.1.b".to_string())
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "theme=dark; ssmp_session=t1".parse().unwrap());
+        assert_eq!(
+            credentials(&headers, &uri),
+            Credentials::Token("t1".to_string())
+        );
+        headers.insert("authorization", "Bearer t2".parse().unwrap());
         assert_eq!(
-            AddressListener::method_and_procol_from_line("GET / HTTP/1.1".to_string()),
-            Ok(("GET /".to_string(), "HTTP/1.1".to_string()))
+            credentials(&headers, &uri),
+            Credentials::Token("t2".to_string())
         );
     }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("key=abc&icy=1", "key"), Some("abc".to_string()));
+        assert_eq!(query_param("token=a%2Eb", "token"), Some("a.b".to_string()));
+        assert_eq!(query_param("icy=1", "key"), None);
+    }
     #[tokio::test]
     #[ignore = "Manual test"]
     #[serial]
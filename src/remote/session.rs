@@ -0,0 +1,221 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Serialize;
+use sha2::Sha256;
+
+use super::auth::{self, constant_time_eq, Key, Permission};
+
+/// How long a session lasts if the login doesn't ask for less
+pub const SESSION_LENGTH: Duration = Duration::from_secs(12 * 60 * 60);
+/// The cookie the session token is kept in by browsers
+pub const COOKIE_NAME: &str = "ssmp_session";
+/// How long a session may last at most to be accepted from the query of a URL, as URLs end up
+/// in logs and histories
+pub const QUERY_SESSION_LENGTH: Duration = Duration::from_secs(15 * 60);
+
+/// A login made with a key. The session can have fewer permissions than its key and ends when
+/// the key is removed.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    /// The label of the key the session was started with
+    pub key: String,
+    pub permissions: Vec<Permission>,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    pub expires: u64,
+}
+
+impl Session {
+    /// Whether the session was started short enough to be sent in a URL
+    pub fn is_short_lived(&self) -> bool {
+        self.expires.saturating_sub(self.created) <= QUERY_SESSION_LENGTH.as_secs()
+    }
+}
+
+/// The sessions of the remote. Tokens are signed with a secret that is made when the store is,
+/// so sessions don't outlive the program.
+pub struct SessionStore {
+    secret: [u8; 32],
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    /// A permission the key doesn't have was asked for
+    NotGranted,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        SessionStore {
+            secret: rand::thread_rng().gen(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl SessionStore {
+    /// Starts a session for `key` and returns its token. Without `permissions` the session has
+    /// every permission of the key. The session never outlasts the key.
+    pub fn start(
+        &self,
+        key: &Key,
+        permissions: Option<Vec<Permission>>,
+        length: Option<Duration>,
+    ) -> Result<(String, Session), SessionError> {
+        let permissions = match permissions {
+            Some(p) => narrow(&key.permissions, p).ok_or(SessionError::NotGranted)?,
+            None => key.permissions.clone(),
+        };
+        let now = auth::now();
+        let length = length.unwrap_or(SESSION_LENGTH).min(SESSION_LENGTH);
+        let mut expires = now + length.as_secs();
+        if let Some(e) = key.expires {
+            expires = expires.min(e);
+        }
+        let session = Session {
+            id: auth::random_hex(16),
+            key: key.label.clone(),
+            permissions,
+            created: now,
+            expires,
+        };
+        let token = self.token(&session);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| now < s.expires);
+        sessions.insert(session.id.clone(), session.clone());
+        Ok((token, session))
+    }
+
+    /// The session of the token if its signature is right and it hasn't expired or been ended
+    pub fn verify(&self, token: &str) -> Option<Session> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        if !constant_time_eq(&self.sign(payload), signature) {
+            return None;
+        }
+        let (id, _) = payload.split_once('.')?;
+        let session = self.sessions.lock().unwrap().get(id).cloned()?;
+        (auth::now() < session.expires).then_some(session)
+    }
+
+    /// Ends a session, returns false if there was no such session
+    pub fn end(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(id).is_some()
+    }
+
+    /// Ends every session of the key with the label
+    pub fn end_key(&self, label: &str) {
+        self.sessions.lock().unwrap().retain(|_, s| s.key != label);
+    }
+
+    pub fn list(&self) -> Vec<Session> {
+        let now = auth::now();
+        let mut sessions: Vec<Session> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| now < s.expires)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.created);
+        sessions
+    }
+
+    fn token(&self, session: &Session) -> String {
+        let payload = format!("{}.{}", session.id, session.expires);
+        format!("{}.{}", payload, self.sign(&payload))
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(payload.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// The permissions asked for, if `held` grants all of them. Volume ranges are narrowed to the
+/// range of `held`, so a session can't get a wider range than its key.
+fn narrow(held: &[Permission], asked: Vec<Permission>) -> Option<Vec<Permission>> {
+    asked
+        .into_iter()
+        .map(|p| match p {
+            Permission::VolumeControl((min, max)) => {
+                let (held_min, held_max) = auth::volume_range(held)?;
+                let (min, max) = (min.max(held_min), max.min(held_max));
+                (min <= max).then_some(Permission::VolumeControl((min, max)))
+            }
+            p => auth::has_permissions(held, std::slice::from_ref(&p)).then_some(p),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let store = SessionStore::default();
+        let key = Key::new("guest", "guest", vec![Permission::Info, Permission::Add]);
+        let (token, session) = store.start(&key, None, None).unwrap();
+        assert_eq!(store.verify(&token), Some(session.clone()));
+        assert_eq!(session.permissions, key.permissions);
+
+        // Changing the expiration breaks the signature
+        let (id, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = format!("{}.{}.{}", id, session.expires + 1000, signature);
+        assert_eq!(store.verify(&forged), None);
+        // Tokens of another store aren't accepted
+        assert_eq!(SessionStore::default().verify(&token), None);
+
+        assert!(store.end(&session.id));
+        assert_eq!(store.verify(&token), None);
+        assert!(!store.end(&session.id));
+
+        // Only sessions that end soon may be sent in a URL
+        assert!(!session.is_short_lived());
+        let (_, session) = store
+            .start(&key, None, Some(Duration::from_secs(60)))
+            .unwrap();
+        assert!(session.is_short_lived());
+    }
+
+    #[test]
+    fn test_session_permissions() {
+        let store = SessionStore::default();
+        let mut key = Key::new(
+            "guest",
+            "guest",
+            vec![Permission::Info, Permission::VolumeControl((0.0, 1.0))],
+        );
+        key.expires = Some(auth::now() + 60);
+        let (_, session) = store
+            .start(
+                &key,
+                Some(vec![Permission::VolumeControl((0.5, 5.0))]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            session.permissions,
+            vec![Permission::VolumeControl((0.5, 1.0))]
+        );
+        assert_eq!(session.expires, key.expires.unwrap());
+        assert_eq!(
+            store.start(&key, Some(vec![Permission::Add]), None),
+            Err(SessionError::NotGranted)
+        );
+
+        store.end_key("guest");
+        assert!(store.list().is_empty());
+    }
+}
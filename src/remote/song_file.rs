@@ -0,0 +1,175 @@
+use std::{
+    io::{self, SeekFrom},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::StreamBody,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::song::Song;
+
+/// Sends the file of the song, answering `Range` requests with the part that was asked for and
+/// conditional requests with `304 Not Modified` if the file hasn't changed
+pub(super) async fn serve(headers: &HeaderMap, song: &Song) -> io::Result<Response> {
+    let mut file = File::open(&song.path).await?;
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag(len, modified);
+    let mut cache_headers = HeaderMap::new();
+    cache_headers.insert(header::ETAG, etag.parse().unwrap());
+    if let Some(m) = modified {
+        let date = httpdate::fmt_http_date(m);
+        cache_headers.insert(header::LAST_MODIFIED, date.parse().unwrap());
+    }
+
+    if is_not_modified(headers, &etag, modified) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let range = match headers
+        .get(header::RANGE)
+        .map(|r| parse_range(r.to_str().unwrap_or_default(), len))
+    {
+        Some(Err(())) => {
+            let content_range = format!("bytes */{}", len);
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, content_range)],
+            )
+                .into_response());
+        }
+        Some(Ok(range)) => range,
+        None => None,
+    };
+    let (status, start, length) = match range {
+        Some((start, end)) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, len);
+            cache_headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+        None => (StatusCode::OK, 0, len),
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = StreamBody::new(ReaderStream::new(file.take(length)));
+    Ok((
+        status,
+        cache_headers,
+        [
+            (header::CONTENT_TYPE, song.format.mime_type().to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let secs = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", len, secs)
+}
+
+/// `If-None-Match` is used over `If-Modified-Since` when both are sent
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(tags) = get(header::IF_NONE_MATCH) {
+        return tags
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+    match (get(header::IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(since) {
+            // The header only has whole seconds
+            Ok(since) => modified
+                .duration_since(since)
+                .map(|d| d.as_secs() == 0)
+                .unwrap_or(true),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Parses a `Range` header into the first and last byte that were asked for. Returns `Ok(None)`
+/// for ranges that are ignored, like multiple ranges, and `Err` if the range can't be satisfied.
+fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let range = match header.trim().strip_prefix("bytes=") {
+        Some(r) if !r.contains(',') => r,
+        _ => return Ok(None),
+    };
+    let (start, end) = range.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last bytes of the file
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, len.saturating_sub(1)),
+        (start, end) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (
+                start.parse().map_err(|_| ())?,
+                end.min(len.saturating_sub(1)),
+            )
+        }
+    };
+    if start >= len || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=20-10", 1000), Err(()));
+        assert_eq!(parse_range("bytes=a-b", 1000), Err(()));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let etag = etag(42, Some(modified));
+        let mut headers = HeaderMap::new();
+        assert!(!is_not_modified(&headers, &etag, Some(modified)));
+
+        let since = httpdate::fmt_http_date(modified);
+        headers.insert(header::IF_MODIFIED_SINCE, since.parse().unwrap());
+        assert!(is_not_modified(&headers, &etag, Some(modified)));
+        let later = modified + Duration::from_secs(10);
+        assert!(!is_not_modified(&headers, &etag, Some(later)));
+
+        headers.insert(header::IF_NONE_MATCH, "\"other\"".parse().unwrap());
+        assert!(!is_not_modified(&headers, &etag, Some(modified)));
+        headers.insert(header::IF_NONE_MATCH, etag.parse().unwrap());
+        assert!(is_not_modified(&headers, &etag, Some(later)));
+    }
+}
@@ -0,0 +1,46 @@
+use std::{convert::Infallible, sync::Mutex, time::Duration};
+
+use axum::response::{
+    sse::{self, KeepAlive, Sse},
+    IntoResponse, Response,
+};
+use futures::{future, stream, StreamExt};
+
+use crate::{
+    events::{self, Event, Tracker},
+    player_state::PlayerState,
+};
+
+/// How often a comment is sent when nothing happens, so proxies keep the connection open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Streams the events of the player as server-sent events until the listener disconnects. The
+/// stream starts with the events that describe the current state, so clients don't have to ask
+/// for it first. Only the events `visible` lets through are sent.
+pub(super) fn stream_events(
+    state: &Mutex<PlayerState>,
+    visible: impl Fn(&Event) -> bool + Send + 'static,
+) -> Response {
+    // Subscribing first means nothing that happens in between is missed
+    let receiver = events::subscribe();
+    let current = Tracker::default().update(&state.lock().unwrap());
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        Some((event, receiver))
+    });
+    let stream = stream::iter(current)
+        .chain(live)
+        .filter(move |event| future::ready(visible(event)))
+        .map(|event| Ok::<_, Infallible>(to_sse(&event)));
+    Sse::new(stream)
+        .keep_alive(KeepAlive::new().interval(KEEP_ALIVE))
+        .into_response()
+}
+
+/// The event named by its type with its JSON as the data
+fn to_sse(event: &Event) -> sse::Event {
+    sse::Event::default()
+        .event(event.name())
+        .json_data(event)
+        .unwrap_or_else(|e| sse::Event::default().comment(e.to_string()))
+}
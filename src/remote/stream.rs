@@ -0,0 +1,223 @@
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::StreamBody,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures::stream;
+use mp3lame_encoder::{
+    max_required_buffer_size, Bitrate, Builder, Encoder, InterleavedPcm, Quality,
+};
+use tokio::{sync::mpsc, task};
+
+use crate::{
+    broadcast,
+    output::{CHANNELS, SAMPLE_RATE},
+    player_state::PlayerState,
+};
+
+/// Bytes of audio between two ICY metadata blocks
+const ICY_METAINT: usize = 16000;
+/// Encoded chunks that can wait for the listener before the encoder waits too
+const ENCODED_BACKLOG: usize = 8;
+
+/// Streams everything that is played as MP3 until the listener disconnects. ICY metadata with
+/// the current song is put in the stream if the listener asked for it.
+pub(super) fn stream_audio(icy: bool, state: Arc<Mutex<PlayerState>>) -> Response {
+    let mut chunks = broadcast::subscribe();
+    let (sender, encoded) = mpsc::channel::<Vec<u8>>(ENCODED_BACKLOG);
+    // Encoding is blocking work, so it gets a thread of its own
+    task::spawn_blocking(move || {
+        let mut encoder = match create_encoder() {
+            Ok(e) => e,
+            Err(e) => {
+                println!("Failed to create the MP3 encoder: {}", e);
+                return;
+            }
+        };
+        while let Some(chunk) = chunks.blocking_recv() {
+            let mp3 = match encode(&mut encoder, &chunk) {
+                Ok(mp3) => mp3,
+                Err(e) => {
+                    println!("Failed to encode the stream: {}", e);
+                    return;
+                }
+            };
+            // The listener is gone when the receiver is dropped
+            if sender.blocking_send(mp3).is_err() {
+                return;
+            }
+        }
+    });
+
+    let writer = icy.then(|| IcyWriter::new(ICY_METAINT));
+    let body = stream::unfold((encoded, writer), move |(mut encoded, mut writer)| {
+        let state = state.clone();
+        async move {
+            let mp3 = encoded.recv().await?;
+            let bytes = match writer.as_mut() {
+                Some(w) => w.write(&mp3, &current_title(&state)),
+                None => mp3,
+            };
+            Some((Ok::<_, Infallible>(bytes), (encoded, writer)))
+        }
+    });
+
+    let mut headers = vec![
+        (header::CONTENT_TYPE, "audio/mpeg".to_string()),
+        (header::CACHE_CONTROL, "no-cache, no-store".to_string()),
+        (
+            header::HeaderName::from_static("icy-name"),
+            "ssmp".to_string(),
+        ),
+        (header::HeaderName::from_static("icy-br"), "128".to_string()),
+        (header::HeaderName::from_static("icy-pub"), "0".to_string()),
+    ];
+    if icy {
+        let metaint = header::HeaderName::from_static("icy-metaint");
+        headers.push((metaint, ICY_METAINT.to_string()));
+    }
+    let mut responce = StreamBody::new(body).into_response();
+    for (name, value) in headers {
+        responce.headers_mut().insert(name, value.parse().unwrap());
+    }
+    responce
+}
+
+fn create_encoder() -> Result<Encoder, String> {
+    let mut builder = Builder::new().ok_or("Failed to create a LAME builder")?;
+    builder
+        .set_num_channels(CHANNELS as u8)
+        .map_err(|e| format!("{:?}", e))?;
+    builder
+        .set_sample_rate(SAMPLE_RATE)
+        .map_err(|e| format!("{:?}", e))?;
+    builder
+        .set_brate(Bitrate::Kbps128)
+        .map_err(|e| format!("{:?}", e))?;
+    builder
+        .set_quality(Quality::Good)
+        .map_err(|e| format!("{:?}", e))?;
+    builder.build().map_err(|e| format!("{:?}", e))
+}
+
+/// Encodes interleaved samples, the encoder may keep some of them until the next call
+fn encode(encoder: &mut Encoder, samples: &[f32]) -> Result<Vec<u8>, String> {
+    let pcm: Vec<i16> = samples
+        .iter()
+        .map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect();
+    let mut mp3 = Vec::with_capacity(max_required_buffer_size(pcm.len()));
+    let written = encoder
+        .encode(InterleavedPcm(&pcm), mp3.spare_capacity_mut())
+        .map_err(|e| format!("{:?}", e))?;
+    // SAFETY: the encoder has initialized as many bytes as it says it wrote
+    unsafe { mp3.set_len(written) };
+    Ok(mp3)
+}
+
+fn current_title(state: &Mutex<PlayerState>) -> String {
+    let state = state.lock().unwrap();
+    match &state.now_playing {
+        Some(song) => match &song.artist {
+            Some(artist) => format!("{} - {}", artist, song.name),
+            None => song.name.clone(),
+        },
+        None => String::new(),
+    }
+}
+
+/// Puts ICY metadata blocks between the audio, as SHOUTcast and Icecast listeners expect when
+/// they send `Icy-MetaData: 1`
+struct IcyWriter {
+    metaint: usize,
+    /// Bytes of audio left before the next metadata block
+    until_metadata: usize,
+    /// The title that was sent last
+    title: Option<String>,
+}
+
+impl IcyWriter {
+    fn new(metaint: usize) -> Self {
+        IcyWriter {
+            metaint,
+            until_metadata: metaint,
+            title: None,
+        }
+    }
+
+    fn write(&mut self, mut audio: &[u8], title: &str) -> Vec<u8> {
+        let mut out = Vec::with_capacity(audio.len() + 1);
+        while !audio.is_empty() {
+            let n = self.until_metadata.min(audio.len());
+            out.extend_from_slice(&audio[..n]);
+            audio = &audio[n..];
+            self.until_metadata -= n;
+            if self.until_metadata == 0 {
+                out.extend(self.metadata(title));
+                self.until_metadata = self.metaint;
+            }
+        }
+        out
+    }
+
+    /// The title is only sent when it changes, otherwise the block is empty
+    fn metadata(&mut self, title: &str) -> Vec<u8> {
+        if self.title.as_deref() == Some(title) {
+            return vec![0];
+        }
+        self.title = Some(title.to_string());
+        icy_block(title)
+    }
+}
+
+/// A metadata block, a byte with the length in units of 16 bytes followed by the padded text
+fn icy_block(title: &str) -> Vec<u8> {
+    let mut text = format!("StreamTitle='{}';", title.replace('\'', "")).into_bytes();
+    text.truncate(255 * 16);
+    let blocks = text.len().div_ceil(16);
+    text.resize(blocks * 16, 0);
+    let mut block = vec![blocks as u8];
+    block.extend(text);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut encoder = create_encoder().unwrap();
+        let second = vec![0.5; SAMPLE_RATE as usize * CHANNELS as usize];
+        let mp3 = encode(&mut encoder, &second).unwrap();
+        // Every MP3 frame starts with the sync word
+        assert!(mp3.len() > 1000);
+        assert_eq!((mp3[0], mp3[1] & 0xe0), (0xff, 0xe0));
+    }
+
+    #[test]
+    fn test_icy_block() {
+        let block = icy_block("Artist - It's a song");
+        assert_eq!(block[0] as usize * 16 + 1, block.len());
+        assert!(block[1..].starts_with(b"StreamTitle='Artist - Its a song';"));
+        assert!(block[35..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_icy_writer() {
+        let mut writer = IcyWriter::new(4);
+        let out = writer.write(&[1, 2, 3, 4, 5, 6], "a");
+        let block = icy_block("a");
+        assert_eq!(out[..4], [1, 2, 3, 4]);
+        assert_eq!(out[4..4 + block.len()], block[..]);
+        assert_eq!(out[4 + block.len()..], [5, 6]);
+        // An unchanged title is sent as an empty block
+        let out = writer.write(&[7, 8, 9], "a");
+        assert_eq!(out, vec![7, 8, 0, 9]);
+    }
+}
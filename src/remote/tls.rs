@@ -0,0 +1,202 @@
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::invite;
+
+/// Where the generated certificate and key are kept for addresses that don't name their own
+const DEFAULT_CERTIFICATE: &str = "tls/cert.pem";
+const DEFAULT_KEY: &str = "tls/key.pem";
+
+/// An address the remote listens on. Plain addresses are written as just the address, HTTPS
+/// ones as an object.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ListenAddress {
+    Plain(String),
+    /// HTTPS with the certificate and private key in PEM files. A self-signed certificate is
+    /// made on the first start if neither file exists.
+    Https {
+        address: String,
+        #[serde(default)]
+        certificate: Option<PathBuf>,
+        #[serde(default)]
+        key: Option<PathBuf>,
+    },
+}
+
+impl ListenAddress {
+    pub fn address(&self) -> &str {
+        match self {
+            ListenAddress::Plain(a) => a,
+            ListenAddress::Https { address, .. } => address,
+        }
+    }
+
+    pub fn is_https(&self) -> bool {
+        matches!(self, ListenAddress::Https { .. })
+    }
+
+    /// The certificate and key files, `None` for plain addresses
+    pub fn tls_files(&self) -> Option<(PathBuf, PathBuf)> {
+        match self {
+            ListenAddress::Plain(_) => None,
+            ListenAddress::Https {
+                certificate, key, ..
+            } => Some((
+                certificate
+                    .clone()
+                    .unwrap_or(PathBuf::from(DEFAULT_CERTIFICATE)),
+                key.clone().unwrap_or(PathBuf::from(DEFAULT_KEY)),
+            )),
+        }
+    }
+}
+
+impl From<String> for ListenAddress {
+    fn from(address: String) -> Self {
+        ListenAddress::Plain(address)
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Plain(a) => write!(f, "http://{}", a),
+            ListenAddress::Https { address, .. } => write!(f, "https://{}", address),
+        }
+    }
+}
+
+/// Reads the certificate and key of an HTTPS address, making a self-signed certificate first if
+/// neither exists yet
+pub async fn load_config(
+    address: &str,
+    certificate: &Path,
+    key: &Path,
+) -> io::Result<RustlsConfig> {
+    if !certificate.exists() && !key.exists() {
+        generate(address, certificate, key)?;
+        println!(
+            "Made a self-signed certificate for {} with the fingerprint {}",
+            address,
+            fingerprint(certificate)?
+        );
+    }
+    RustlsConfig::from_pem_file(certificate, key).await
+}
+
+/// Makes a self-signed certificate for localhost and the addresses the remote can be reached at
+fn generate(address: &str, certificate: &Path, key: &Path) -> io::Result<()> {
+    let mut names = vec!["localhost".to_string()];
+    match address.parse::<SocketAddr>() {
+        Ok(a) if !a.ip().is_unspecified() => names.push(a.ip().to_string()),
+        _ => names.extend(invite::network_ip().map(|ip| ip.to_string())),
+    }
+    let generated = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    let pem = generated.serialize_pem().map_err(io::Error::other)?;
+    for path in [certificate, key] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(certificate, pem)?;
+    write_private(key, &generated.serialize_private_key_pem())
+}
+
+/// Writes a file only the owner may read. New files are created that way, so the contents are
+/// never readable by others, and a file that was already there is narrowed before it's written.
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes())
+}
+
+/// The SHA-256 fingerprint of the first certificate in the PEM file, written as colon separated
+/// hex like browsers show it
+pub fn fingerprint(certificate: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(fs::File::open(certificate)?);
+    let der = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .next()
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The file has no certificate",
+        ))?;
+    Ok(Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_address() {
+        let addresses: Vec<ListenAddress> = serde_json::from_str(
+            r#"["0.0.0.0:8000", {"address": "0.0.0.0:8443"}, {"address": "0.0.0.0:9443", "certificate": "a.pem", "key": "b.pem"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            addresses[0],
+            ListenAddress::Plain("0.0.0.0:8000".to_string())
+        );
+        assert_eq!(addresses[0].tls_files(), None);
+        assert!(addresses[1].is_https());
+        assert_eq!(addresses[1].address(), "0.0.0.0:8443");
+        assert_eq!(
+            addresses[1].tls_files(),
+            Some((DEFAULT_CERTIFICATE.into(), DEFAULT_KEY.into()))
+        );
+        assert_eq!(
+            addresses[2].tls_files(),
+            Some(("a.pem".into(), "b.pem".into()))
+        );
+        assert_eq!(addresses[2].to_string(), "https://0.0.0.0:9443");
+    }
+
+    #[tokio::test]
+    async fn test_self_signed() {
+        let dir = std::env::temp_dir().join(format!("ssmp-tls-{}", std::process::id()));
+        let (certificate, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        assert!(load_config("127.0.0.1:8443", &certificate, &key)
+            .await
+            .is_ok());
+        let first = fingerprint(&certificate).unwrap();
+        assert_eq!(first.split(':').count(), 32);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // The certificate is kept
+        assert!(load_config("127.0.0.1:8443", &certificate, &key)
+            .await
+            .is_ok());
+        assert_eq!(fingerprint(&certificate).unwrap(), first);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
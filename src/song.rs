@@ -1,18 +1,23 @@
 use std::{
     fs::File,
     io::{self, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use id3::{frame::PictureType, Tag, TagLike};
 use rodio::{decoder::DecoderError, Decoder};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+use crate::files::{list_all_songs, list_songs};
 use crate::format::Format;
-use crate::{files::list_songs, format::Formattable};
+use crate::format::Formattable;
+use crate::tags::mp4_url_ident;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Song {
+    /// Identifies the file of the song, derived from its path
+    pub id: String,
     pub name: String,
     pub artist: Option<String>,
     pub url: Option<String>,
@@ -53,18 +58,34 @@ impl Song {
     }
 
     pub fn from_file(path: PathBuf) -> Option<Song> {
-        let tag = Tag::read_from_path(&path).unwrap_or(Tag::new());
-        let url_frame = tag.get("WOAF");
         let filename = path
             .file_stem()
             .unwrap_or_default()
             .to_str()
             .unwrap_or_default();
+        if path.get_format() == Format::MP4 {
+            if let Ok(tag) = mp4ameta::Tag::read_from_path(&path) {
+                return Some(Song {
+                    id: Song::gen_id(&path),
+                    name: tag.title().unwrap_or(filename).to_string(),
+                    artist: tag.artist().map(|s| s.to_string()),
+                    url: tag
+                        .strings_of(&mp4_url_ident())
+                        .next()
+                        .map(|s| s.to_string()),
+                    path: path.clone(),
+                    format: Format::MP4,
+                });
+            }
+        }
+        let tag = Tag::read_from_path(&path).unwrap_or(Tag::new());
+        let url_frame = tag.get("WOAF");
         let url = match url_frame {
             Some(frame) => frame.content().link().map(|s| s.to_string()),
             None => None,
         };
         Some(Song {
+            id: Song::gen_id(&path),
             name: tag.title().unwrap_or(filename).to_string(),
             artist: tag.artist().map(|s| s.to_string()),
             url,
@@ -73,13 +94,36 @@ impl Song {
         })
     }
 
+    /// The first 8 bytes of the SHA-256 of the path as hex. Clients keep the ids around, so they
+    /// have to stay the same between builds.
+    pub fn gen_id(path: &Path) -> String {
+        Sha256::digest(path.to_string_lossy().as_bytes())[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn from_id(id: &str) -> Option<Song> {
+        list_all_songs().into_iter().find(|song| song.id == id)
+    }
+
     pub fn from_string(string: String) -> Option<Song> {
-        list_songs()
-            .into_iter()
-            .find(|song| song.name == string.clone() || song.url == Some(string.clone()))
+        Song::find_in(&list_songs(), &string).cloned()
+    }
+
+    /// Finds the song with the name or url among the songs, to look up many songs in one listing
+    /// of the library
+    pub fn find_in<'a>(songs: &'a [Song], string: &str) -> Option<&'a Song> {
+        songs
+            .iter()
+            .find(|song| song.name == string || song.url.as_deref() == Some(string))
     }
 
     pub fn get_image(&self) -> Option<Vec<u8>> {
+        if self.format == Format::MP4 {
+            let tag = mp4ameta::Tag::read_from_path(&self.path).ok()?;
+            return Some(tag.artwork()?.data.to_vec());
+        }
         let tag = Tag::read_from_path(&self.path).ok()?;
         let image_data = tag
             .pictures()
@@ -91,6 +135,7 @@ impl Song {
 impl Default for Song {
     fn default() -> Self {
         Song {
+            id: "".to_string(),
             name: "Unknown name".to_string(),
             artist: None,
             url: None,
@@ -99,3 +144,14 @@ impl Default for Song {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gen_id() {
+        // Ids are kept by clients, so this must never change
+        assert_eq!(Song::gen_id(Path::new("songs/a.mp3")), "f52de7e17ba035a7");
+    }
+}
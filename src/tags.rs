@@ -0,0 +1,168 @@
+use std::{io::Cursor, path::Path};
+
+use base64::{engine, Engine};
+use id3::{
+    frame::{Picture, PictureType},
+    Frame, Tag, TagLike, Timestamp,
+};
+use image::{DynamicImage, ImageOutputFormat};
+use mp4ameta::{Data, FreeformIdent, Img};
+use serde::{Deserialize, Serialize};
+
+use crate::{format::Format, song::Song};
+
+/// Changes to the tags of a song. Fields that are `None` are left untouched and empty strings
+/// remove the field.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TagEdit {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    /// Cover art encoded with base64, in any format the `image` crate can read
+    pub cover: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Reads the current tags of the song, leaving out the cover art
+pub fn read_tags(song: &Song) -> TagEdit {
+    let mut edit = TagEdit {
+        title: Some(song.name.clone()),
+        artist: song.artist.clone(),
+        url: song.url.clone(),
+        ..Default::default()
+    };
+    match song.format {
+        Format::MP3 => {
+            if let Ok(tag) = Tag::read_from_path(&song.path) {
+                edit.album = tag.album().map(|s| s.to_string());
+                edit.genre = tag.genre().map(|s| s.to_string());
+                edit.year = tag.date_recorded().map(|t| t.year).or(tag.year());
+            }
+        }
+        Format::MP4 => {
+            if let Ok(tag) = mp4ameta::Tag::read_from_path(&song.path) {
+                edit.album = tag.album().map(|s| s.to_string());
+                edit.genre = tag.genre().map(|s| s.to_string());
+                edit.year = tag.year().and_then(|y| y.get(..4)?.parse().ok());
+            }
+        }
+        Format::UNSUPPORTED => {}
+    }
+    edit
+}
+
+/// Writes the changes into the file of the song and returns the song as it is read back from
+/// the file.
+pub fn edit_tags(song: &Song, edit: &TagEdit) -> Result<Song, String> {
+    let cover = match &edit.cover {
+        Some(c) => {
+            let bytes = engine::general_purpose::STANDARD
+                .decode(c)
+                .map_err(|e| e.to_string())?;
+            let img = image::load_from_memory(&bytes).map_err(|e| e.to_string())?;
+            Some(encode_jpeg(&img).map_err(|e| e.to_string())?)
+        }
+        None => None,
+    };
+    match song.format {
+        Format::MP3 => write_id3(&song.path, edit, cover).map_err(|e| e.to_string())?,
+        Format::MP4 => write_mp4(&song.path, edit, cover).map_err(|e| e.to_string())?,
+        Format::UNSUPPORTED => return Err("Unsupported format".to_string()),
+    }
+    Song::from_file(song.path.clone()).ok_or("Failed to read the song back".to_string())
+}
+
+pub fn encode_jpeg(img: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut data: Vec<u8> = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(90))?;
+    Ok(data)
+}
+
+/// The freeform atom MP4 files store the source url in
+pub fn mp4_url_ident() -> FreeformIdent<'static> {
+    FreeformIdent::new("com.apple.iTunes", "URL")
+}
+
+fn write_id3(path: &Path, edit: &TagEdit, cover: Option<Vec<u8>>) -> Result<(), id3::Error> {
+    let mut tag = Tag::read_from_path(path).unwrap_or(Tag::new());
+    set_id3_text(&mut tag, "TIT2", &edit.title);
+    set_id3_text(&mut tag, "TPE1", &edit.artist);
+    set_id3_text(&mut tag, "TALB", &edit.album);
+    set_id3_text(&mut tag, "TCON", &edit.genre);
+    if let Some(year) = edit.year {
+        tag.remove_year();
+        tag.set_date_recorded(Timestamp {
+            year,
+            month: None,
+            day: None,
+            hour: None,
+            minute: None,
+            second: None,
+        });
+    }
+    if let Some(url) = &edit.url {
+        tag.remove("WOAF");
+        if !url.is_empty() {
+            tag.add_frame(Frame::link("WOAF", url.as_str()));
+        }
+    }
+    if let Some(data) = cover {
+        tag.remove_picture_by_type(PictureType::CoverFront);
+        tag.add_frame(Picture {
+            mime_type: "image/jpeg".to_string(),
+            picture_type: PictureType::CoverFront,
+            description: "Cover".to_string(),
+            data,
+        });
+    }
+    tag.write_to_path(path, id3::Version::Id3v24)
+}
+
+fn set_id3_text(tag: &mut Tag, id: &str, value: &Option<String>) {
+    match value.as_deref() {
+        Some("") => {
+            tag.remove(id);
+        }
+        Some(v) => tag.set_text(id, v),
+        None => {}
+    }
+}
+
+fn write_mp4(path: &Path, edit: &TagEdit, cover: Option<Vec<u8>>) -> mp4ameta::Result<()> {
+    let mut tag = mp4ameta::Tag::read_from_path(path)?;
+    match edit.title.as_deref() {
+        Some("") => tag.remove_title(),
+        Some(t) => tag.set_title(t),
+        None => {}
+    }
+    match edit.artist.as_deref() {
+        Some("") => tag.remove_artists(),
+        Some(a) => tag.set_artist(a),
+        None => {}
+    }
+    match edit.album.as_deref() {
+        Some("") => tag.remove_album(),
+        Some(a) => tag.set_album(a),
+        None => {}
+    }
+    match edit.genre.as_deref() {
+        Some("") => tag.remove_genres(),
+        Some(g) => tag.set_genre(g),
+        None => {}
+    }
+    if let Some(year) = edit.year {
+        tag.set_year(year.to_string());
+    }
+    if let Some(url) = &edit.url {
+        tag.remove_data_of(&mp4_url_ident());
+        if !url.is_empty() {
+            tag.set_data(mp4_url_ident(), Data::Utf8(url.clone()));
+        }
+    }
+    if let Some(data) = cover {
+        tag.set_artwork(Img::jpeg(data));
+    }
+    tag.write_to_path(path)
+}
@@ -0,0 +1,119 @@
+use gtk::prelude::*;
+use relm4::{gtk, prelude::FactoryComponent, FactorySender};
+
+use crate::{
+    downloads::{Job, JobState},
+    ui::youtube_browser::YtMessage,
+};
+
+/// A download job in the YouTube browser, with its progress and buttons to cancel or retry it
+#[derive(Debug)]
+pub struct JobRow {
+    pub job: Job,
+}
+
+#[derive(Debug)]
+pub enum JobRowMessage {
+    Cancel,
+    Retry,
+}
+
+#[relm4::factory(pub)]
+impl FactoryComponent for JobRow {
+    type Init = Job;
+    type Input = JobRowMessage;
+    type Output = YtMessage;
+    type CommandOutput = ();
+    type Widgets = JobRowWidgets;
+    type ParentInput = YtMessage;
+    type ParentWidget = gtk::Box;
+
+    view! {
+        root = gtk::Box{
+            set_spacing: 5,
+            gtk::Label{
+                set_hexpand: true,
+                set_xalign: 0.0,
+                #[watch]
+                set_label: self.title(),
+            },
+            gtk::ProgressBar{
+                set_show_text: true,
+                set_valign: gtk::Align::Center,
+                #[watch]
+                set_fraction: self.job.progress.percent.unwrap_or(0.0) as f64 / 100.0,
+                #[watch]
+                set_text: Some(&self.status()),
+            },
+            gtk::Button{
+                set_icon_name: "process-stop-symbolic",
+                set_tooltip_text: Some("Cancel"),
+                #[watch]
+                set_visible: !self.job.state.is_done(),
+                connect_clicked => JobRowMessage::Cancel
+            },
+            gtk::Button{
+                set_icon_name: "view-refresh-symbolic",
+                set_tooltip_text: Some("Retry"),
+                #[watch]
+                set_visible: matches!(self.job.state, JobState::Failed | JobState::Cancelled),
+                connect_clicked => JobRowMessage::Retry
+            }
+        }
+    }
+
+    fn init_model(init: Self::Init, _index: &Self::Index, _sender: FactorySender<Self>) -> Self {
+        JobRow { job: init }
+    }
+
+    fn forward_to_parent(output: Self::Output) -> Option<Self::Output> {
+        Some(output)
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: FactorySender<Self>) {
+        match msg {
+            JobRowMessage::Cancel => sender.output(YtMessage::CancelDownload(self.job.id)),
+            JobRowMessage::Retry => sender.output(YtMessage::RetryDownload(self.job.id)),
+        }
+    }
+}
+
+impl JobRow {
+    /// The name of the song once it is known, the url until then
+    fn title(&self) -> &str {
+        match &self.job.song {
+            Some(song) => &song.name,
+            None => &self.job.url,
+        }
+    }
+
+    fn status(&self) -> String {
+        let progress = &self.job.progress;
+        match self.job.state {
+            JobState::Queued => "Waiting".to_string(),
+            JobState::Running => {
+                let mut status = match progress.percent {
+                    Some(p) => format!("{:.0}%", p),
+                    None => "Starting".to_string(),
+                };
+                if let Some(speed) = progress.speed {
+                    status += &format!(" at {}", format_speed(speed));
+                }
+                if let Some(eta) = progress.eta {
+                    status += &format!(", {}:{:02} left", eta / 60, eta % 60);
+                }
+                status
+            }
+            JobState::Finished => "Done".to_string(),
+            JobState::Failed => self.job.error.clone().unwrap_or("Failed".to_string()),
+            JobState::Cancelled => "Cancelled".to_string(),
+        }
+    }
+}
+
+fn format_speed(bytes_per_second: f64) -> String {
+    match bytes_per_second >= 1024.0 * 1024.0 {
+        true => format!("{:.1} MiB/s", bytes_per_second / 1024.0 / 1024.0),
+        false => format!("{:.0} KiB/s", bytes_per_second / 1024.0),
+    }
+}
@@ -0,0 +1,102 @@
+use itertools::Itertools;
+use relm4::{
+    gtk::{
+        self,
+        traits::{BoxExt, ButtonExt},
+    },
+    prelude::FactoryComponent,
+    FactorySender,
+};
+
+use crate::{
+    conf::Configuration,
+    files::duplicates::{self, DuplicateGroup},
+    MainMessage,
+};
+
+#[derive(Debug)]
+pub(crate) struct DuplicateGroupView {
+    group: DuplicateGroup,
+}
+
+#[derive(Debug)]
+pub enum DuplicateMessage {
+    /// Keeps the preferred copy and merges the rest into it, copies that only share the title
+    /// are hidden rather than removed
+    Merge,
+    /// Hides every copy except the preferred one
+    Hide,
+}
+
+#[relm4::factory(pub)]
+impl FactoryComponent for DuplicateGroupView {
+    type Init = DuplicateGroup;
+    type Input = DuplicateMessage;
+    type Output = MainMessage;
+    type CommandOutput = ();
+    type Widgets = DuplicateGroupWidgets;
+    type ParentInput = MainMessage;
+    type ParentWidget = gtk::Box;
+
+    view! {
+        root = gtk::Box{
+            set_spacing: 3,
+            gtk::Label{
+                #[watch]
+                set_label: &self.describe(),
+            },
+            gtk::Button{
+                set_label: "Merge",
+                connect_clicked => DuplicateMessage::Merge
+            },
+            gtk::Button{
+                set_label: "Hide",
+                connect_clicked => DuplicateMessage::Hide
+            }
+        }
+    }
+
+    fn init_model(
+        init: Self::Init,
+        _index: &Self::Index,
+        _sender: relm4::FactorySender<Self>,
+    ) -> Self {
+        Self { group: init }
+    }
+
+    fn forward_to_parent(output: Self::Output) -> Option<Self::Output> {
+        Some(output)
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: FactorySender<Self>) {
+        let conf = Configuration::get_conf();
+        let keep = self.group.preferred(&conf).id.clone();
+        let result = match msg {
+            DuplicateMessage::Merge => duplicates::merge_into(&keep, false).map(|_| ()),
+            DuplicateMessage::Hide => {
+                let ids: Vec<String> = self
+                    .group
+                    .songs
+                    .iter()
+                    .filter(|s| s.id != keep)
+                    .map(|s| s.id.clone())
+                    .collect();
+                duplicates::hide(&ids)
+            }
+        };
+        if let Err(e) = result {
+            println!("Failed to resolve duplicates: {}", e)
+        }
+        sender.output(MainMessage::FilesChanged);
+    }
+}
+
+impl DuplicateGroupView {
+    fn describe(&self) -> String {
+        self.group
+            .songs
+            .iter()
+            .map(|s| s.path.to_string_lossy())
+            .join(", ")
+    }
+}
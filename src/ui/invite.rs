@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use gtk::prelude::*;
+use image::DynamicImage;
+use relm4::{
+    gtk::{self, gdk, glib},
+    ComponentParts, ComponentSender, SimpleComponent,
+};
+
+use crate::remote::{
+    auth::{KeyStore, Permission},
+    invite::{self, Invite},
+};
+
+/// The permissions that can be given to a guest, volume ranges and `All` are left to the
+/// configuration
+const GUEST_PERMISSIONS: [Permission; 7] = [
+    Permission::Info,
+    Permission::Add,
+    Permission::PlayPause,
+    Permission::Seek,
+    Permission::Download,
+    Permission::Effects,
+    Permission::Library,
+];
+
+/// Mints guest keys and shows them as a QR code a phone can scan
+pub struct InviteView {
+    /// The keys of the running remote, so it accepts the invites right away
+    keys: Arc<KeyStore>,
+    label: gtk::EntryBuffer,
+    minutes: gtk::Adjustment,
+    permissions: Vec<Permission>,
+    invite: Option<Invite>,
+    code: Option<gdk::MemoryTexture>,
+    error: Option<String>,
+    /// The fingerprint of the HTTPS certificate, so guests can check it before accepting it
+    fingerprint: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum InviteMessage {
+    Toggle(Permission, bool),
+    Create,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for InviteView {
+    type Init = Arc<KeyStore>;
+    type Input = InviteMessage;
+    type Output = ();
+
+    view! {
+        gtk::Box{
+            set_orientation: gtk::Orientation::Vertical,
+            set_spacing: 3,
+            gtk::Entry{
+                set_buffer: &model.label,
+                set_placeholder_text: Some("Label, guest if empty"),
+            },
+            gtk::Box{
+                set_orientation: gtk::Orientation::Horizontal,
+                set_spacing: 5,
+                gtk::Label{
+                    set_label: "Minutes",
+                },
+                gtk::SpinButton{
+                    set_adjustment: &model.minutes,
+                },
+            },
+            #[local_ref]
+            permission_box -> gtk::Box{
+                set_orientation: gtk::Orientation::Horizontal,
+                set_spacing: 5,
+            },
+            gtk::Button{
+                set_label: "Create invite",
+                connect_clicked => InviteMessage::Create
+            },
+            gtk::Picture{
+                set_can_shrink: false,
+                #[watch]
+                set_visible: model.code.is_some(),
+                #[watch]
+                set_paintable: model.code.as_ref(),
+            },
+            gtk::Label{
+                set_selectable: true,
+                set_wrap: true,
+                #[watch]
+                set_label: &match (&model.invite, &model.error) {
+                    (_, Some(e)) => e.clone(),
+                    (Some(i), None) => format!("{}: {}", i.label, i.url),
+                    (None, None) => "".to_string(),
+                },
+            },
+            gtk::Label{
+                set_selectable: true,
+                set_wrap: true,
+                #[watch]
+                set_visible: model.fingerprint.is_some(),
+                #[watch]
+                set_label: &format!(
+                    "Certificate fingerprint: {}",
+                    model.fingerprint.as_deref().unwrap_or_default()
+                ),
+            },
+        }
+    }
+
+    fn init(
+        keys: Self::Init,
+        root: &Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = InviteView {
+            keys,
+            label: gtk::EntryBuffer::default(),
+            minutes: gtk::Adjustment::new(
+                invite::DEFAULT_MINUTES as f64,
+                1.0,
+                invite::MAX_MINUTES as f64,
+                1.0,
+                60.0,
+                0.0,
+            ),
+            permissions: invite::default_permissions(),
+            invite: None,
+            code: None,
+            error: None,
+            fingerprint: invite::fingerprint(),
+        };
+        let permission_box = gtk::Box::default();
+        for permission in GUEST_PERMISSIONS {
+            let check = gtk::CheckButton::with_label(&format!("{:?}", permission));
+            check.set_active(model.permissions.contains(&permission));
+            let sender = sender.clone();
+            check.connect_toggled(move |c| {
+                sender.input(InviteMessage::Toggle(permission.clone(), c.is_active()))
+            });
+            permission_box.append(&check);
+        }
+        let widgets = view_output!();
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+        match msg {
+            InviteMessage::Toggle(permission, true) => {
+                if !self.permissions.contains(&permission) {
+                    self.permissions.push(permission)
+                }
+            }
+            InviteMessage::Toggle(permission, false) => {
+                self.permissions.retain(|p| *p != permission)
+            }
+            InviteMessage::Create => match self.create() {
+                Ok((invite, code)) => {
+                    self.invite = Some(invite);
+                    self.code = Some(code);
+                    self.error = None;
+                    // The certificate may have been made since the view was
+                    self.fingerprint = invite::fingerprint();
+                }
+                Err(e) => {
+                    println!("Failed to create the invite: {}", e);
+                    self.error = Some(e);
+                }
+            },
+        }
+    }
+}
+
+impl InviteView {
+    fn create(&self) -> Result<(Invite, gdk::MemoryTexture), String> {
+        let base = invite::local_base()
+            .ok_or("The remote has no address that other devices can reach".to_string())?;
+        let label = self.label.text();
+        let label = Some(label.trim()).filter(|l| !l.is_empty());
+        let invite = invite::create(
+            &self.keys,
+            &base,
+            label,
+            self.permissions.clone(),
+            self.minutes.value() as u64,
+        )
+        .map_err(|e| e.to_string())?;
+        let image = DynamicImage::ImageLuma8(invite::qr_image(&invite.url)?).into_rgb8();
+        let (width, height) = image.dimensions();
+        let code = gdk::MemoryTexture::new(
+            width as i32,
+            height as i32,
+            gdk::MemoryFormat::R8g8b8,
+            &glib::Bytes::from_owned(image.into_raw()),
+            width as usize * 3,
+        );
+        Ok((invite, code))
+    }
+}
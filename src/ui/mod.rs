@@ -1,2 +1,6 @@
+pub mod downloads;
+pub mod duplicates;
+pub mod invite;
 pub mod song_selecter;
+pub mod tag_editor;
 pub mod youtube_browser;
@@ -7,7 +7,7 @@ use relm4::{
     FactorySender,
 };
 
-use crate::{commands::PlayerMessage, song::Song};
+use crate::{commands::PlayerMessage, song::Song, MainMessage};
 
 #[derive(Debug)]
 pub(crate) struct SongFile {
@@ -20,16 +20,17 @@ pub enum SelectorMessage {
     Queue,
     QueueFront,
     Play,
+    EditTags,
 }
 
 #[relm4::factory(pub)]
 impl FactoryComponent for SongFile {
     type Init = Song;
     type Input = SelectorMessage;
-    type Output = PlayerMessage;
+    type Output = MainMessage;
     type CommandOutput = ();
     type Widgets = SongFileWidgets;
-    type ParentInput = PlayerMessage;
+    type ParentInput = MainMessage;
     type ParentWidget = gtk::Box;
 
     view! {
@@ -50,6 +51,12 @@ impl FactoryComponent for SongFile {
                     set_from_icon_name: Some("view-continuous"),
                 },
                 connect_clicked => SelectorMessage::Queue
+            },
+            gtk::Button{
+                gtk::Image{
+                    set_from_icon_name: Some("document-edit"),
+                },
+                connect_clicked => SelectorMessage::EditTags
             }
         }
     }
@@ -68,11 +75,14 @@ impl FactoryComponent for SongFile {
 
     fn update(&mut self, msg: Self::Input, sender: FactorySender<Self>) {
         match msg {
-            SelectorMessage::Queue => sender.output(PlayerMessage::Add(self.song.clone())),
+            SelectorMessage::Queue => {
+                sender.output(MainMessage::Player(PlayerMessage::Add(self.song.clone())))
+            }
             SelectorMessage::Play => {
-                sender.output(PlayerMessage::Stop);
-                sender.output(PlayerMessage::Add(self.song.clone()));
+                sender.output(MainMessage::Player(PlayerMessage::Stop));
+                sender.output(MainMessage::Player(PlayerMessage::Add(self.song.clone())));
             }
+            SelectorMessage::EditTags => sender.output(MainMessage::EditTags(self.song.clone())),
             _ => {}
         }
     }
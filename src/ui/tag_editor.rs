@@ -0,0 +1,164 @@
+use std::fs;
+
+use base64::{engine, Engine};
+use gtk::prelude::*;
+use relm4::{gtk, ComponentParts, ComponentSender, SimpleComponent};
+
+use crate::{
+    song::Song,
+    tags::{self, TagEdit},
+    MainMessage,
+};
+
+pub struct TagEditor {
+    song: Option<Song>,
+    title: gtk::EntryBuffer,
+    artist: gtk::EntryBuffer,
+    album: gtk::EntryBuffer,
+    genre: gtk::EntryBuffer,
+    year: gtk::EntryBuffer,
+    url: gtk::EntryBuffer,
+    /// Path to an image file that replaces the cover art
+    cover: gtk::EntryBuffer,
+}
+
+#[derive(Debug)]
+pub enum TagEditorMessage {
+    Open(Song),
+    Save,
+    Close,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for TagEditor {
+    type Init = ();
+    type Input = TagEditorMessage;
+    type Output = MainMessage;
+
+    view! {
+        gtk::Box{
+            set_orientation: gtk::Orientation::Vertical,
+            set_spacing: 3,
+            #[watch]
+            set_visible: model.song.is_some(),
+            gtk::Label{
+                #[watch]
+                set_label: &model.song.as_ref().map(|s| s.path.to_string_lossy().to_string()).unwrap_or_default(),
+            },
+            gtk::Entry{
+                set_buffer: &model.title,
+                set_placeholder_text: Some("Title"),
+            },
+            gtk::Entry{
+                set_buffer: &model.artist,
+                set_placeholder_text: Some("Artist"),
+            },
+            gtk::Entry{
+                set_buffer: &model.album,
+                set_placeholder_text: Some("Album"),
+            },
+            gtk::Entry{
+                set_buffer: &model.genre,
+                set_placeholder_text: Some("Genre"),
+            },
+            gtk::Entry{
+                set_buffer: &model.year,
+                set_placeholder_text: Some("Year"),
+            },
+            gtk::Entry{
+                set_buffer: &model.url,
+                set_placeholder_text: Some("Source url"),
+            },
+            gtk::Entry{
+                set_buffer: &model.cover,
+                set_placeholder_text: Some("Path to new cover art"),
+            },
+            gtk::Box{
+                set_orientation: gtk::Orientation::Horizontal,
+                set_spacing: 5,
+                set_halign: gtk::Align::End,
+                gtk::Button{
+                    set_label: "Cancel",
+                    connect_clicked => TagEditorMessage::Close
+                },
+                gtk::Button{
+                    set_label: "Save",
+                    connect_clicked => TagEditorMessage::Save
+                },
+            }
+        }
+    }
+
+    fn init(
+        _: Self::Init,
+        root: &Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = TagEditor {
+            song: None,
+            title: gtk::EntryBuffer::default(),
+            artist: gtk::EntryBuffer::default(),
+            album: gtk::EntryBuffer::default(),
+            genre: gtk::EntryBuffer::default(),
+            year: gtk::EntryBuffer::default(),
+            url: gtk::EntryBuffer::default(),
+            cover: gtk::EntryBuffer::default(),
+        };
+        let widgets = view_output!();
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+        match msg {
+            TagEditorMessage::Open(song) => {
+                let current = tags::read_tags(&song);
+                self.title.set_text(&current.title.unwrap_or_default());
+                self.artist.set_text(&current.artist.unwrap_or_default());
+                self.album.set_text(&current.album.unwrap_or_default());
+                self.genre.set_text(&current.genre.unwrap_or_default());
+                self.year
+                    .set_text(&current.year.map(|y| y.to_string()).unwrap_or_default());
+                self.url.set_text(&current.url.unwrap_or_default());
+                self.cover.set_text("");
+                self.song = Some(song);
+            }
+            TagEditorMessage::Save => {
+                if let Some(song) = &self.song {
+                    match self.edit().and_then(|e| tags::edit_tags(song, &e)) {
+                        Ok(_) => {
+                            self.song = None;
+                            sender.output(MainMessage::FilesChanged).unwrap();
+                        }
+                        Err(e) => println!("Failed to edit tags: {}", e),
+                    }
+                }
+            }
+            TagEditorMessage::Close => self.song = None,
+        }
+    }
+}
+
+impl TagEditor {
+    fn edit(&self) -> Result<TagEdit, String> {
+        let year = match self.year.text().trim() {
+            "" => None,
+            y => Some(y.parse::<i32>().map_err(|e| e.to_string())?),
+        };
+        let cover = match self.cover.text().trim() {
+            "" => None,
+            path => {
+                let data = fs::read(path).map_err(|e| e.to_string())?;
+                Some(engine::general_purpose::STANDARD.encode(data))
+            }
+        };
+        Ok(TagEdit {
+            title: Some(self.title.text().to_string()),
+            artist: Some(self.artist.text().to_string()),
+            album: Some(self.album.text().to_string()),
+            genre: Some(self.genre.text().to_string()),
+            year,
+            cover,
+            url: Some(self.url.text().to_string()),
+        })
+    }
+}
@@ -10,9 +10,10 @@ use relm4::gtk;
 use reqwest::Client;
 
 use crate::{
-    downloader::download_dlp,
+    downloads::{self, Job, JobId, JobState},
+    events::{self, Event},
     insert_into_factory,
-    song::Song,
+    ui::downloads::JobRow,
     youtube::{scrape_youtube, video::Video},
     MainMessage,
 };
@@ -20,6 +21,8 @@ use crate::{
 #[derive(Debug)]
 pub struct YoutubeBrowser {
     youtube_factory: FactoryVecDeque<Video>,
+    /// The download jobs, kept up to date from the events of the download queue
+    jobs: FactoryVecDeque<JobRow>,
     // PERF: Investigate if there is a performance diffirence when using other sizes
     order: u128,
     view_order: u128,
@@ -28,6 +31,8 @@ pub struct YoutubeBrowser {
 #[derive(Debug)]
 pub enum YtMessage {
     Download(String),
+    CancelDownload(JobId),
+    RetryDownload(JobId),
     QueryChanges(String),
 }
 
@@ -35,8 +40,11 @@ pub enum YtMessage {
 pub enum CommandMessage {
     QueryUpdated(Vec<Video>, u128),
     QueryFailed(String),
-    DownloadSuccesful(Song),
-    DownloadFailed(String),
+    JobChanged(Job),
+    /// Cancelling or retrying a job failed
+    JobFailed(String),
+    /// Nothing to do, the change comes in through the events
+    Done,
 }
 
 #[relm4::component(async, pub)]
@@ -47,6 +55,13 @@ impl AsyncComponent for YoutubeBrowser {
     type CommandOutput = CommandMessage;
     view! {
         gtk::Box{
+            set_orientation: gtk::Orientation::Vertical,
+            set_spacing: 5,
+            #[local_ref]
+            jobs_box -> gtk::Box{
+                set_orientation: gtk::Orientation::Vertical,
+                set_spacing: 3,
+            },
             #[local_ref]
             yt_box -> gtk::Box{
                 set_orientation: gtk::Orientation::Vertical,
@@ -61,8 +76,25 @@ impl AsyncComponent for YoutubeBrowser {
     ) -> AsyncComponentParts<Self> {
         let youtube_factory =
             FactoryVecDeque::<Video>::new(gtk::Box::default(), sender.input_sender());
-        let model = YoutubeBrowser { youtube_factory, order: 0, view_order: 0};
+        let mut jobs = FactoryVecDeque::<JobRow>::new(gtk::Box::default(), sender.input_sender());
+        insert_into_factory(downloads::list().into_iter(), &mut jobs.guard());
+        sender.command(|out, shutdown| {
+            shutdown
+                .register(async move {
+                    let mut events = events::subscribe();
+                    while let Some(event) = events.recv().await {
+                        if let Event::Download(job) = event {
+                            if out.send(CommandMessage::JobChanged(job)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                })
+                .drop_on_shutdown()
+        });
+        let model = YoutubeBrowser { youtube_factory, jobs, order: 0, view_order: 0};
         let yt_box = model.youtube_factory.widget();
+        let jobs_box = model.jobs.widget();
         let widgets = view_output!();
         AsyncComponentParts { model, widgets }
     }
@@ -83,10 +115,21 @@ impl AsyncComponent for YoutubeBrowser {
         _root: &Self::Root,
     ) {
         match msg {
+            // The download queue needs the runtime of the commands to start the jobs
             YtMessage::Download(id) => sender.oneshot_command(async move {
-                match download_dlp(format!("https://www.youtube.com/watch?v={}", id)).await {
-                    Ok(s) => CommandMessage::DownloadSuccesful(s),
-                    Err(e) => CommandMessage::DownloadFailed(e),
+                downloads::enqueue(format!("https://www.youtube.com/watch?v={}", id), None);
+                CommandMessage::Done
+            }),
+            YtMessage::CancelDownload(id) => sender.oneshot_command(async move {
+                match downloads::cancel(id) {
+                    Ok(_) => CommandMessage::Done,
+                    Err(e) => CommandMessage::JobFailed(e.to_string()),
+                }
+            }),
+            YtMessage::RetryDownload(id) => sender.oneshot_command(async move {
+                match downloads::retry(id) {
+                    Ok(_) => CommandMessage::Done,
+                    Err(e) => CommandMessage::JobFailed(e.to_string()),
                 }
             }),
             YtMessage::QueryChanges(s) => {
@@ -119,11 +162,22 @@ impl AsyncComponent for YoutubeBrowser {
                 }
             }
             CommandMessage::QueryFailed(s) => println!("Failed to query yt: {}", s),
-            CommandMessage::DownloadSuccesful(_) => {
-                // PERF: This should be done without scanning the whole file system
-                sender.output(MainMessage::FilesChanged).unwrap();
+            CommandMessage::JobChanged(job) => {
+                if job.state == JobState::Finished {
+                    // PERF: This should be done without scanning the whole file system
+                    sender.output(MainMessage::FilesChanged).unwrap();
+                }
+                let mut g = self.jobs.guard();
+                let index = g.iter().position(|row| row.job.id == job.id);
+                match index {
+                    Some(index) => g.get_mut(index).unwrap().job = job,
+                    None => {
+                        g.push_back(job);
+                    }
+                }
             }
-            CommandMessage::DownloadFailed(s) => println!("Failed download: {}", s),
+            CommandMessage::JobFailed(s) => println!("Failed to change the download: {}", s),
+            CommandMessage::Done => {}
         }
     }
 }